@@ -0,0 +1,83 @@
+//! Wall time of compressing and decompressing small files, against the C version.
+//!
+//! Run with `cargo bench --bench small_inputs`. The bzip2 binary of this crate is run on text inputs of 1k to 99k
+//! bytes (built from tests/corpus/text.txt), and so is the C bzip2 found on the PATH, if any. The median of RUNS runs
+//! is printed for each. Both outputs are checked against the input before the times are printed.
+//!
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const SIZES: [usize; 4] = [1_000, 10_000, 50_000, 99_000];
+const RUNS: usize = 31;
+
+/// Run the command RUNS times, returning the median wall time.
+fn median_time(program: &Path, args: &[&str], file: &Path) -> Duration {
+    let mut times = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let status = Command::new(program)
+                .args(args)
+                .arg(file)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .expect("Unable to run bzip2");
+            assert!(status.success(), "{} {:?} {} failed", program.display(), args, file.display());
+            start.elapsed()
+        })
+        .collect::<Vec<_>>();
+    times.sort();
+    times[RUNS / 2]
+}
+
+/// Return the path of the C bzip2, if it is on the PATH.
+fn c_bzip2() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).map(|dir| dir.join("bzip2")).find(|exe| exe.is_file())
+}
+
+fn ms(time: Duration) -> String {
+    format!("{:6.2} ms", time.as_secs_f64() * 1000.0)
+}
+
+fn main() {
+    let rust = PathBuf::from(env!("CARGO_BIN_EXE_bzip2"));
+    let c = c_bzip2();
+    let dir = std::env::temp_dir().join(format!("bzip2-small-inputs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let text = include_bytes!("../tests/corpus/text.txt");
+
+    println!("  size    compress rust / C          decompress rust / C");
+    for size in SIZES {
+        let data = text.iter().copied().cycle().take(size).collect::<Vec<u8>>();
+        let rust_file = dir.join(format!("rust{}", size));
+        let c_file = dir.join(format!("c{}", size));
+        std::fs::write(&rust_file, &data).unwrap();
+        std::fs::write(&c_file, &data).unwrap();
+        let rust_bz2 = rust_file.with_extension("bz2");
+        let c_bz2 = c_file.with_extension("bz2");
+
+        // This crate writes <name>.bz2 when compressing and <name>.txt when decompressing <name>.bz2
+        let rust_compress = median_time(&rust, &["-z"], &rust_file);
+        let rust_decompress = median_time(&rust, &["-d"], &rust_bz2);
+        assert!(std::fs::read(rust_file.with_extension("txt")).unwrap() == data);
+
+        let c_times = c.as_ref().map(|c| {
+            let compress = median_time(c, &["-zkf"], &c_file);
+            let decompress = median_time(c, &["-dkf"], &c_bz2);
+            assert!(std::fs::read(&c_file).unwrap() == data);
+            (ms(compress), ms(decompress))
+        });
+        let (c_compress, c_decompress) = c_times.unwrap_or_else(|| ("-".into(), "-".into()));
+        println!(
+            "{:>6}    {} / {}      {} / {}",
+            size,
+            ms(rust_compress),
+            c_compress,
+            ms(rust_decompress),
+            c_decompress
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,75 @@
+//! Time of each BWT backend on blocks of ordinary text.
+//!
+//! Run with `cargo bench --bench sort`. The text is the source code of this crate, which has the long shared prefixes
+//! (indentation, repeated identifiers and doc comments) of real text without being one phrase repeated. Blocks of
+//! 10k, 100k and 300k bytes are each sorted RUNS times by every backend, and the median time is printed. The port of
+//! the C version's sort (julian) is the yardstick.
+//!
+//! The bench fails if the default (auto) backend takes more than SLOWEST_AUTO times as long as the fastest backend, so
+//! a change that makes the choice between the native sort and SA-IS slow on ordinary data shows up here.
+//!
+use bzip2::bwt_algorithms::backend::{Auto, BwtBackend, Julian, Native, Sais};
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const SIZES: [usize; 3] = [10_000, 100_000, 300_000];
+const RUNS: usize = 11;
+const WORK_FACTOR: usize = 30;
+/// Largest time of the auto backend, as a multiple of the fastest backend, before the bench fails.
+const SLOWEST_AUTO: f64 = 1.5;
+
+/// Return the median time of RUNS runs of f.
+fn median_time(mut f: impl FnMut()) -> Duration {
+    let mut times = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect::<Vec<_>>();
+    times.sort();
+    times[RUNS / 2]
+}
+
+/// Append the contents of every .rs file under dir to text, in name order so the text is the same on every run.
+fn read_sources(dir: &Path, text: &mut Vec<u8>) {
+    let mut entries = std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            read_sources(&path, text);
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            text.extend(std::fs::read(&path).unwrap());
+        }
+    }
+}
+
+fn main() {
+    let mut text = vec![];
+    read_sources(&Path::new(env!("CARGO_MANIFEST_DIR")).join("src"), &mut text);
+    let backends: [&dyn BwtBackend; 4] = [&Auto, &Native, &Sais, &Julian];
+
+    println!("  size    {}", backends.map(|b| format!("{:>13}", b.name())).join(""));
+    let mut slow = vec![];
+    for size in SIZES {
+        let block = text.iter().copied().cycle().take(size).collect::<Vec<u8>>();
+        let expected = Julian.encode(&block, WORK_FACTOR);
+        let times = backends.map(|backend| {
+            assert!(backend.encode(&block, WORK_FACTOR) == expected, "{} differs", backend.name());
+            median_time(|| {
+                black_box(backend.encode(black_box(&block), WORK_FACTOR));
+            })
+        });
+        println!(
+            "{:>6}    {}",
+            size,
+            times.map(|time| format!("{:10.2} ms", time.as_secs_f64() * 1000.0)).join("")
+        );
+        let fastest = times.iter().min().unwrap();
+        if times[0].as_secs_f64() > fastest.as_secs_f64() * SLOWEST_AUTO {
+            slow.push(size);
+        }
+    }
+    assert!(slow.is_empty(), "auto is over {} times slower than the fastest backend at sizes {:?}", SLOWEST_AUTO, slow);
+}
@@ -0,0 +1,71 @@
+//! Throughput of BitWriter::add_block, against writing the block a byte at a time.
+//!
+//! Run with `cargo bench --bench splice`. A 900k block is added ROUNDS times with the stream at each bit offset, and
+//! the best time is printed as MB/s. The byte path is the way add_block wrote blocks before it spliced them a 64-bit
+//! word at a time: every byte goes through a 64-bit queue, and full bytes are moved out when the queue is nearly full.
+//!
+use bzip2::bitstream::bitwriter::BitWriter;
+use std::hint::black_box;
+use std::io::sink;
+use std::time::{Duration, Instant};
+
+const BLOCK_SIZE: usize = 900_000;
+const ROUNDS: usize = 20;
+
+/// Return the best time of ROUNDS runs of f.
+fn best_time(mut f: impl FnMut()) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn mb_per_s(time: Duration) -> String {
+    format!("{:8.1} MB/s", BLOCK_SIZE as f64 / time.as_secs_f64() / 1e6)
+}
+
+/// Write data to output through a bit queue holding q_bits bits, a byte at a time.
+fn byte_path(data: &[u8], output: &mut Vec<u8>, queue: &mut u64, q_bits: &mut u8) {
+    for &byte in data {
+        if *q_bits > 56 {
+            while *q_bits > 7 {
+                output.push((*queue >> (*q_bits - 8)) as u8);
+                *q_bits -= 8;
+            }
+        }
+        *queue = (*queue << 8) | byte as u64;
+        *q_bits += 8;
+    }
+}
+
+fn main() {
+    let mut seed = 1_u32;
+    let block = (0..BLOCK_SIZE)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect::<Vec<u8>>();
+
+    println!("offset    add_block         byte path");
+    for offset in 0..8_u8 {
+        // The 32 bit header and a first block with (8 - offset) % 8 bits of padding leave the stream offset bits
+        // past a byte boundary. Blocks without padding keep it there.
+        let mut writer = BitWriter::new(sink(), 9);
+        writer.add_block(&[0; 10], (8 - offset) % 8).unwrap();
+        let splice = best_time(|| writer.add_block(black_box(&block), 0).unwrap());
+
+        let mut output = Vec::with_capacity(BLOCK_SIZE + 8);
+        let (mut queue, mut q_bits) = (0_u64, offset);
+        let bytes = best_time(|| {
+            output.clear();
+            byte_path(black_box(&block), &mut output, &mut queue, &mut q_bits);
+            black_box(&output);
+        });
+        println!("{:>6}    {}    {}", offset, mb_per_s(splice), mb_per_s(bytes));
+    }
+}
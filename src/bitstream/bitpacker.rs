@@ -14,14 +14,16 @@
 /// Creates a huffman-encoded, packed bitstream of one block of data. The final byte of the block
 /// is padded with zeros to reach a full byte. The padding is always a number between 0 and 7
 /// inclusive.
+#[derive(Default)]
 pub struct BitPacker {
     /// The output buffer which can be read externally.
     pub output: Vec<u8>,
     /// The number of zero bits padded to the last byte of the output buffer.
     pub padding: u8,
-    /// The queue holds bits temporarily until we can put full bytes on the output buffer
+    /// The queue holds bits until there are 64 of them to put on the output buffer. Only the low q_bits bits are
+    /// valid; anything above them is shifted out before the queue is written.
     queue: u64,
-    /// q_bits is the number of valid bits in the queue
+    /// q_bits is the number of valid bits in the queue (0-63)
     q_bits: u8,
 }
 
@@ -39,12 +41,38 @@ impl BitPacker {
         }
     }
 
-    /// Internal bitstream write function common to all out.XX functions. Keeps the queue short.
-    fn write_stream(&mut self) {
-        while self.q_bits > 7 {
-            let byte = (self.queue >> (self.q_bits - 8)) as u8;
-            self.output.push(byte); //push the packed byte out
-            self.q_bits -= 8; //adjust the count of bits left in the queue
+    /// Empty the BitPacker so it can be used for another block, keeping the output buffer's memory.
+    pub fn clear(&mut self) {
+        self.output.clear();
+        self.padding = 0;
+        self.queue = 0;
+        self.q_bits = 0;
+    }
+
+    /// Put the n least significant bits of value (0-64 bits) on the stream, most significant bit first. Bits of
+    /// value above those n are ignored.
+    #[inline]
+    pub fn put_bits(&mut self, value: u64, n: u8) {
+        debug_assert!(n <= 64, "Cannot put more than 64 bits at once");
+        if n == 0 {
+            return;
+        }
+        let value = value & (u64::MAX >> (64 - n));
+        let free = 64 - self.q_bits;
+        if n < free {
+            self.queue = (self.queue << n) | value;
+            self.q_bits += n;
+        } else {
+            // Fill the queue with the top bits of value and write it as a whole word. The rest stay in the queue.
+            let rest = n - free;
+            let word = if free == 64 {
+                value
+            } else {
+                (self.queue << free) | (value >> rest)
+            };
+            self.output.extend_from_slice(&word.to_be_bytes());
+            self.queue = value;
+            self.q_bits = rest;
         }
     }
 
@@ -60,48 +88,29 @@ impl BitPacker {
     /// Writes 0-24 bits encoded with the number of bits to write in the most
     /// significant byte of a 32 bit word.
     pub fn out24(&mut self, data: u32) {
-        let depth = (data >> 24) as u8; //get bit length by shifting out the 24 data bits
-        self.queue <<= depth; //shift queue by bit length
-        self.queue |= (data & (0xffffffff >> (32 - depth))) as u64; //add data portion to queue
-        self.q_bits += depth; //update depth of queue bits
-        self.write_stream();
+        self.put_bits(data as u64, (data >> 24) as u8);
     }
 
     /// Puts a 32 bit word of pre-packed binary encoded data on the stream.
     pub fn out32(&mut self, data: u32) {
-        self.queue <<= 32; //shift queue by bit length
-        self.queue |= data as u64; //add data portion to queue
-        self.q_bits += 32; //update depth of queue bits
-        self.write_stream();
+        self.put_bits(data as u64, 32);
     }
 
     /// Puts a 16 bit word  of pre-packed binary encoded data on the stream.
     pub fn out16(&mut self, data: u16) {
-        self.queue <<= 16; //shift queue by bit length
-        self.queue |= data as u64; //add data portion to queue
-        self.q_bits += 16; //update depth of queue bits
-        self.write_stream();
+        self.put_bits(data as u64, 16);
     }
 
-    // out_8 is not currently used, but is here for completeness.
-    // /// Puts an 8 bit word  of pre-packed binary encoded data on the stream.
-    // pub fn out8(&mut self, data: u8) {
-    //     self.queue <<= 8; //shift queue by bit length
-    //     self.queue |= data as u64; //add data portion to queue
-    //     self.q_bits += 8; //update depth of queue bits
-    //     self.write_stream();
-    // }
-
-    /// Flushes the remaining bits (1-7) from the buffer, padding with 0s in the least
+    /// Flushes the remaining bits from the queue, padding the last byte with 0s in the least
     /// signficant bits. Flush MUST be called before reading the output or data may be
     /// left in the private queue.
     pub fn flush(&mut self) {
         if self.q_bits > 0 {
-            self.padding = 8 - self.q_bits % 8;
-
-            self.queue <<= self.padding; //pad the queue with zeros
-            self.q_bits += self.padding;
-            self.write_stream(); // write out all that is left
+            let bytes = (self.q_bits as usize).div_ceil(8);
+            self.padding = (bytes * 8) as u8 - self.q_bits;
+            let word = self.queue << (64 - self.q_bits);
+            self.output.extend_from_slice(&word.to_be_bytes()[..bytes]);
+            self.q_bits = 0;
         }
     }
 
@@ -164,4 +173,22 @@ mod test {
         let out = bp.output;
         assert_eq!(out, [33, 32, 33, 32]);
     }
+
+    #[test]
+    fn put_bits_test() {
+        // Every width at every starting offset, checked against a bit at a time reference
+        let mut bp = BitPacker::new(100);
+        let mut bits = vec![];
+        let mut seed = 5_u64;
+        for n in (0..=64).chain((0..=64).rev()) {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            bp.put_bits(seed, n);
+            bits.extend((0..n).rev().map(|i| (seed >> i) as u8 & 1));
+        }
+        bp.flush();
+        bits.resize(bits.len().div_ceil(8) * 8, 0);
+        let expected = bits.chunks(8).map(|b| b.iter().fold(0, |acc, &bit| acc << 1 | bit)).collect::<Vec<u8>>();
+        assert_eq!(bp.output, expected);
+        assert_eq!(bp.padding as usize, expected.len() * 8 - 2 * (64 * 65 / 2));
+    }
 }
@@ -1,68 +1,194 @@
 //! BitReader reads a packed bitstream for the block-oriented deconstruction of BZIP2 compressed files.
 //! 
-//! NOTE: This module can read from any I/O source that supports the read() call.
+//! Bits are moved from the source data into a 64-bit bit container, most significant bit first. All reads are
+//! served from that container, which is refilled (up to eight bytes at a time) whenever it runs low. This lets the huffman
+//! decoder peek at the next several bits and then consume only as many as the code it found actually used.
+//! 
+//! The bit container API is:
+//! - refill(): top up the container so it holds at least 57 bits (unless the data runs out).
+//! - peek(n): look at the next 1-32 bits without consuming them.
+//! - consume(n): drop n bits after a peek.
+//! - align_to_byte(): skip to the next byte boundary of the stream.
+//! - bit_position(): number of bits read from the start of the stream.
+//! - remaining_in_buffer(): number of bits that can be read before the source must be read again.
+//! - take_error(): the error that stopped a read of the source, if there was one.
+//! 
+//! NOTE: This module can read from any ByteSource: a memory mapped file or slice is read in place, and any I/O source
+//! that supports the read() call is read through a ReadSource buffer. If reading the source fails, the BitReader
+//! treats it as the end of the data and keeps the error. The decoder checks take_error() before reporting what it
+//! made of the missing data, so a failing pipe or device gives the I/O error rather than a truncated stream.
 //!
-
-const BUFFER_SIZE: usize = 1024 * 1024;
-const BIT_MASK: u8 = 0xff;
+use super::byte_source::{ByteSource, MemorySource, ReadSource};
+use std::io;
 
 /// Reads a binary Bzip2 file.
 #[derive(Debug)]
-pub struct BitReader<R> {
-    buffer: Vec<u8>,
+pub struct BitReader<S> {
+    source: S,
+    /// Position of the next byte to load in source.data().
     cursor: usize,
-    bit_index: usize,
-    source: R,
+    /// Bit container. Valid bits are left aligned (the next bit to read is bit 63).
+    bits: u64,
+    /// Count of valid bits in the bit container.
+    bit_count: u32,
+    /// Count of bytes moved into the bit container since the start of the stream.
+    bytes_loaded: u64,
+    /// The error that stopped a read of the source, until take_error() is called.
+    error: Option<io::Error>,
+    /// True once a read of the source has failed. No more reads are tried.
+    failed: bool,
 }
 
-impl<R: std::io::Read> BitReader<R> {
+impl<R: std::io::Read> BitReader<ReadSource<R>> {
     /// Creates a new bitReader (with a 1Mbyte buffer).
     pub fn new(source: R) -> Self {
+        Self::from_source(ReadSource::new(source))
+    }
+
+    /// Creates a new bitReader with a buffer of size bytes (at most 1Mbyte). Use this when the source is known to be
+    /// small, so a tiny input doesn't pay for a large buffer.
+    pub fn with_capacity(source: R, size: usize) -> Self {
+        Self::from_source(ReadSource::with_capacity(source, size))
+    }
+}
+
+impl<'a> BitReader<MemorySource<&'a [u8]>> {
+    /// Creates a new bitReader that reads data in place.
+    pub fn from_slice(data: &'a [u8]) -> Self {
+        Self::from_source(MemorySource::new(data))
+    }
+}
+
+impl<S: ByteSource> BitReader<S> {
+    /// Creates a new bitReader over a ByteSource.
+    pub fn from_source(source: S) -> Self {
         Self {
-            buffer: vec![0; BUFFER_SIZE],
-            cursor: BUFFER_SIZE,
-            bit_index: 0,
             source,
+            cursor: 0,
+            bits: 0,
+            bit_count: 0,
+            bytes_loaded: 0,
+            error: None,
+            failed: false,
         }
     }
 
     /// Check (and refill) buffer. Returns true if we have data, false if there is no more
     fn have_data(&mut self) -> bool {
-        // Only try to read more data when the buffer length is equal to the buffer cursor location
-        if self.cursor == self.buffer.len() {
-            let size = self
-                .source
-                .read(&mut self.buffer)
-                .expect("Unable to read source data");
+        // Only ask for more data when we have used everything the source had available
+        if self.cursor == self.source.data().len() {
+            if self.failed {
+                return false;
+            }
+            self.source.consume(self.cursor);
+            self.cursor = 0;
+            // A read error ends the data. Keep it for take_error().
+            if let Err(e) = self.source.fill(1) {
+                self.error = Some(e);
+                self.failed = true;
+                return false;
+            }
             // If nothing came back from our read attempt, then we have no more data.
-            if size == 0 {
+            if self.source.data().is_empty() {
                 return false;
-            } else {
-                // Adjust the buffer if we read less than the buffer size
-                self.buffer.truncate(size);
-                // Reset the cursor and bit index
-                self.cursor = 0;
-                self.bit_index = 0;
             }
         }
         true
     }
 
+    /// Return the error that stopped a read of the source, if there was one. The data ends where the error happened.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Top up the bit container with whole bytes from the buffer until it holds more than 56 bits,
+    /// or until we run out of data. Reads are refilled automatically, so this only needs to be called
+    /// to make sure remaining_in_buffer() reflects everything that is available.
+    pub fn refill(&mut self) {
+        // When there are at least 8 bytes in the buffer, load them in one go.
+        let data = self.source.data();
+        if self.bit_count <= 56 && self.cursor + 8 <= data.len() {
+            let word = u64::from_be_bytes(
+                data[self.cursor..self.cursor + 8]
+                    .try_into()
+                    .unwrap(),
+            );
+            let take = (64 - self.bit_count) / 8;
+            // Keep only the whole bytes we are taking (take is 8 only when the container is empty)
+            let mask = if take == 8 { u64::MAX } else { !(u64::MAX >> (take * 8)) };
+            self.bits |= (word & mask) >> self.bit_count;
+            self.cursor += take as usize;
+            self.bit_count += take * 8;
+            self.bytes_loaded += take as u64;
+        }
+        while self.bit_count <= 56 {
+            if !self.have_data() {
+                return;
+            }
+            self.bits |= (self.source.data()[self.cursor] as u64) << (56 - self.bit_count);
+            self.cursor += 1;
+            self.bit_count += 8;
+            self.bytes_loaded += 1;
+        }
+    }
+
+    /// Return the next n bits (0-32) without consuming them. Bits beyond the end of the data
+    /// are returned as zeros, so check the return value of consume() to detect the end of the data.
+    #[inline(always)]
+    pub fn peek(&mut self, n: u32) -> u32 {
+        debug_assert!(n <= 32, "peek can only return up to 32 bits at a time");
+        if self.bit_count < n {
+            self.refill();
+        }
+        // Shifting a u64 by 64 overflows, so peeking 0 bits is handled separately.
+        self.bits.checked_shr(64 - n).unwrap_or(0) as u32
+    }
+
+    /// Consume n bits (usually after a peek). Returns false if fewer than n bits remained.
+    #[inline(always)]
+    pub fn consume(&mut self, n: u32) -> bool {
+        if self.bit_count < n {
+            self.refill();
+            if self.bit_count < n {
+                return false;
+            }
+        }
+        // Shifting a u64 by 64 overflows, so consuming a full container is handled separately.
+        self.bits = self.bits.checked_shl(n).unwrap_or(0);
+        self.bit_count -= n;
+        true
+    }
+
+    /// Skip any bits left in the current byte so the next read starts on a byte boundary.
+    pub fn align_to_byte(&mut self) {
+        // Whole bytes are loaded into the container, so the bits past the boundary are bit_count % 8.
+        let partial = self.bit_count % 8;
+        self.bits <<= partial;
+        self.bit_count -= partial;
+    }
+
+    /// Return the number of bits read (consumed) since the start of the stream.
+    pub fn bit_position(&self) -> u64 {
+        self.bytes_loaded * 8 - self.bit_count as u64
+    }
+
+    /// Return the number of bits that can be read before the source must be read again.
+    pub fn remaining_in_buffer(&self) -> u64 {
+        self.bit_count as u64 + (self.source.data().len() - self.cursor) as u64 * 8
+    }
+
     /// Return bit as Option<usize> (1 or 0), or None if there is no more data to read
     pub fn bit(&mut self) -> Option<usize> {
-        // If bit_index is == 0, check if we have a byte to read. Return None if we have no data
-        if self.bit_index == 0 && !self.have_data() {
-            return None;
-        }
-        // Otherwise return the bit as an Some(usize)
-        let bit =
-            (self.buffer[self.cursor] & BIT_MASK >> self.bit_index) >> (7 - self.bit_index);
-        self.bit_index += 1;
-        self.bit_index %= 8;
-        if self.bit_index == 0 {
-            self.cursor += 1;
+        if self.bit_count == 0 {
+            self.refill();
+            if self.bit_count == 0 {
+                return None;
+            }
         }
-        Some(bit as usize)
+        let bit = (self.bits >> 63) as usize;
+        self.bits <<= 1;
+        self.bit_count -= 1;
+        Some(bit)
     }
 
     /// Return Option<Bool> *true* if the next bit is 1, *false* if 0, consuming the bit, 
@@ -71,70 +197,26 @@ impl<R: std::io::Read> BitReader<R> {
         self.bit().map(|bit| bit == 1)
     }
 
-    /// Return Option<usize> of the next n bits, or None if there is no more data to read. 
-    pub fn bint(&mut self, mut n: usize) -> Option<usize> {
+    /// Return Option<usize> of the next n bits (0-32), or None if there is no more data to read. 
+    pub fn bint(&mut self, n: usize) -> Option<usize> {
         /*
         This is used primarilyl to return signatures and crc values. For example, if a crc
         value is stored on the stream as a u32, then bint(32) will return the crc value in
         Some(usize).
 
-        This is optimized to read as many bits as possible for each read.
-        First, look to see if we have less than 8 bits in the current byte. If so, get
-        those. Then get full bytes as needed to fulfill the request. Lastly, get a
-        partial byte to complete the request.
+        The bit container always holds at least 57 bits after a refill (unless we are at the
+        end of the data), so any request of 32 bits or less can be served in one step.
         */
-        // Prepare the usize for returning
-        let mut result = 0_usize;
-
-        // Test if we have a partial byte of data. If we do, read from it.
-        if self.bit_index > 0 {
-            // Set up to read the minimum of the partial byte and what we need to read
-            let needed = n.min(8 - self.bit_index);
-
-            // Get what we need/can from this partial byte
-            result = ((self.buffer[self.cursor] & BIT_MASK >> self.bit_index)
-                >> (8 - self.bit_index - needed)) as usize;
-            self.bit_index += needed;
-            if self.bit_index / 8 > 0 {
-                self.cursor += 1;
-            }
-            self.bit_index %= 8;
-
-            // See if we got all we needed.
-            if n == needed {
-                // Return if so.
-                return Some(result);
-            } else {
-                // Else adjust what we still need and try to read more data.
-                n -= needed;
-            }
+        debug_assert!(n <= 32, "bint can only read up to 32 bits at a time");
+        if n == 0 {
+            return Some(0);
         }
-        // If we are here, we need more data. Get as many full bytes as we need.
-        while n >= 8 {
-            // Checking always for data
-            if !self.have_data() {
-                return None;
-            }
-            result = result << 8 | (self.buffer[self.cursor]) as usize;
-            self.cursor += 1;
-            n -= 8;
+        let result = self.peek(n as u32) as usize;
+        if self.consume(n as u32) {
+            Some(result)
+        } else {
+            None
         }
-        // If we still need a partial byte, get whatever bits we still need.
-        if n > 0 {
-            // Checking always for data
-            if !self.have_data() {
-                return None;
-            }
-            // Get the remaining bits
-            result = result << n | (self.buffer[self.cursor] >> (8 - n)) as usize;
-            // Adjust indecies
-            self.bit_index += n;
-            if self.bit_index / 8 > 1 {
-                self.cursor += 1;
-            }
-            self.bit_index %= 8;
-        }
-        Some(result)
     }
 
     /// Returns a byte as an Option<u8>, or None if there is no more data to read. This is
@@ -149,23 +231,23 @@ impl<R: std::io::Read> BitReader<R> {
         let mut result: Vec<u8> = Vec::with_capacity(n);
 
         while n > 0 {
-            if let Some(byte) = self.byte() {
-                result.push(byte);
-                n -= 1;
-            }
+            result.push(self.byte()?);
+            n -= 1;
         }
         Some(result)
     }
 
-    /// Debugging function. Report current position in the buffer.
+    /// Debugging function. Report current position in the stream as bytes.bits.
     pub fn loc(&self) -> String {
-        format!("[{}.{}]", self.cursor, self.bit_index)
+        let position = self.bit_position();
+        format!("[{}.{}]", position / 8, position % 8)
     }
 }
 
 /*
 Note: I tried several refactorings to use an iterator to read bits for the above functions,
-but this code above proved faster than any iterator I could devise.
+but this code above proved faster than any iterator I could devise. The 64-bit container is
+faster still, since most reads no longer touch the buffer at all.
  */
 
 #[cfg(test)]
@@ -200,10 +282,10 @@ mod test {
     fn byte_test() {
         let x = "Hello, world!".as_bytes();
         let mut br = BitReader::new(x);
-        assert_eq!(br.byte(), Some('H' as u8));
-        assert_eq!(br.byte(), Some('e' as u8));
-        assert_eq!(br.byte(), Some('l' as u8));
-        assert_eq!(br.byte(), Some('l' as u8));
+        assert_eq!(br.byte(), Some(b'H'));
+        assert_eq!(br.byte(), Some(b'e'));
+        assert_eq!(br.byte(), Some(b'l'));
+        assert_eq!(br.byte(), Some(b'l'));
     }
 
     #[test]
@@ -235,4 +317,108 @@ mod test {
         assert_eq!(br.bool_bit(), Some(false));
         assert_eq!(br.bool_bit(), Some(false));
     }
+
+    #[test]
+    fn peek_consume_test() {
+        let x = [0b1011_0011, 0b1000_0000].as_slice();
+        let mut br = BitReader::new(x);
+        assert_eq!(br.peek(4), 0b1011);
+        assert_eq!(br.peek(4), 0b1011);
+        assert_eq!(br.peek(0), 0);
+        assert!(br.consume(0));
+        assert!(br.consume(3));
+        assert_eq!(br.peek(6), 0b10_0111);
+        assert!(br.consume(6));
+        // Peeking past the end of the data pads with zeros, but consuming fails
+        assert_eq!(br.peek(10), 0);
+        assert!(br.consume(7));
+        assert!(!br.consume(1));
+        assert_eq!(br.bit(), None);
+    }
+
+    #[test]
+    fn align_and_position_test() {
+        let x = [0xff, 0x0f, 0xf0, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde].as_slice();
+        let mut br = BitReader::new(x);
+        assert_eq!(br.bit_position(), 0);
+        assert_eq!(br.bint(3), Some(0b111));
+        assert_eq!(br.bit_position(), 3);
+        br.align_to_byte();
+        assert_eq!(br.bit_position(), 8);
+        // Aligning when already aligned does nothing
+        br.align_to_byte();
+        assert_eq!(br.byte(), Some(0x0f));
+        assert_eq!(br.bit_position(), 16);
+        assert_eq!(br.remaining_in_buffer(), 64);
+        assert_eq!(br.loc(), "[2.0]");
+        assert_eq!(br.bint(32), Some(0xf0123456));
+        assert_eq!(br.bint(32), Some(0x789abcde));
+        assert_eq!(br.remaining_in_buffer(), 0);
+        assert_eq!(br.bit_position(), 80);
+    }
+
+    /// A reader that returns one byte per read call, to force the buffer to refill often.
+    struct Trickle<'a>(&'a [u8]);
+    impl std::io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    /// A reader that returns its data, then fails.
+    struct Broken<'a>(&'a [u8]);
+    impl std::io::Read for Broken<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(std::io::Error::other("device error"));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn read_error_test() {
+        let mut br = BitReader::new(Broken(&[0xab]));
+        assert_eq!(br.byte(), Some(0xab));
+        assert_eq!(br.bit(), None);
+        assert_eq!(br.take_error().unwrap().to_string(), "device error");
+        // The error is only returned once, and the source is not read again
+        assert_eq!(br.bit(), None);
+        assert!(br.take_error().is_none());
+    }
+
+    #[test]
+    fn from_slice_test() {
+        // Reading in place gives the same bits as reading through a buffer
+        let data = (0..1000_u32).map(|i| (i * 37 % 256) as u8).collect::<Vec<u8>>();
+        let mut buffered = BitReader::new(data.as_slice());
+        let mut in_place = BitReader::from_slice(&data);
+        for n in (1..=32).cycle().take(300) {
+            assert_eq!(in_place.bint(n), buffered.bint(n));
+            assert_eq!(in_place.remaining_in_buffer(), buffered.remaining_in_buffer());
+        }
+        assert_eq!(in_place.bit_position(), buffered.bit_position());
+    }
+
+    #[test]
+    fn trickle_refill_test() {
+        let data = (0..=255_u8).collect::<Vec<u8>>();
+        let mut br = BitReader::new(Trickle(&data));
+        br.bint(4);
+        for i in 0..255_u32 {
+            // The byte after the end of the data reads as zero
+            let next = if i < 254 { (i + 2) >> 4 } else { 0 };
+            assert_eq!(br.peek(16), (i & 0xf) << 12 | (i + 1) << 4 | next);
+            let i = i as u8;
+            assert_eq!(br.byte(), Some((i << 4) | ((i + 1) >> 4)));
+        }
+        assert_eq!(br.bit_position(), 255 * 8 + 4);
+        assert_eq!(br.bint(4), Some(0xf));
+        assert_eq!(br.bit(), None);
+    }
 }
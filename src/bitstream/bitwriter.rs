@@ -14,13 +14,27 @@
 //! This module is called from a thread that is spawned before any block compression threads are spawned in order to ensure that it can catch
 //! the earliest blocks that are compressed. 
 //!
-//! NOTE: Error handling when encountering bad IO is **not** yet well implemented.
+//! The writer moves through three states: the stream header has not been written, blocks are being written, and
+//! the stream is finished. The header is written with the first block, or by finish() if there are no blocks, so
+//! the output is always a valid stream. Any write error is returned to the caller.
 //!
 use crate::tools::crc::do_stream_crc;
+use std::io::{Error, ErrorKind, Write};
+
+/// Where the BitWriter is in the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamState {
+    /// Nothing has been written yet. The stream header is next.
+    Header,
+    /// The header has been written and blocks are being added.
+    Blocks,
+    /// The stream footer has been written. Nothing more can be added.
+    Finished,
+}
 
 /// Writes a bitstream for output. Takes the blocks packed by BitPacker and assembles them with
 /// the stream header and footer, calculating the stream CRC as it processes the blocks.
-pub struct BitWriter {
+pub struct BitWriter<W: Write> {
     /// Output buffer used to write the bitstream.
     output: Vec<u8>,
     /// Private queue to hold bits that are waiting to be put as bytes into the output buffer.
@@ -28,38 +42,35 @@ pub struct BitWriter {
     /// Count of valid bits in the queue.
     q_bits: u8,
 
-    /// Handle to the output stream
-    writer: Box<dyn std::io::Write + std::marker::Sync + std::marker::Send>,
+    /// The output stream
+    writer: W,
     /// Block size, needed to create the header.
     block_size: u8,
     /// Stream CRC, calculated from each block crc and added to the stream footer.
     stream_crc: u32,
+    /// Progress through the stream.
+    state: StreamState,
 }
 
-impl BitWriter {
-    /// Create a new Bitwriter with an output buffer of size specified. We need the block size.
-    /// to create the header. Use add_block() to add each block to the stream.
-    pub fn new(filepath: &str, mut block_size: u8) -> Self {
-        // Abort if the filepath is empty
-        if filepath.is_empty() {
-            panic!("Filepath cannot be empty when compressing a file");
-        }
-        // Ensure that the block size is valid
-        let result = std::fs::File::create(filepath);
-        if block_size > 9 {
-            block_size = 9;
-        }
-        // Open the output device for writing and initialize the struct
+impl<W: Write> BitWriter<W> {
+    /// Create a new Bitwriter that writes to writer. We need the block size to create the header (it is limited
+    /// to 1-9). Use add_block() to add each block to the stream, then finish() to end it.
+    pub fn new(writer: W, block_size: u8) -> Self {
+        Self::with_buffer(writer, block_size, Vec::with_capacity(block_size.clamp(1, 9) as usize * 100000))
+    }
+
+    /// Same as new(), but uses buffer as the output buffer so the buffer of a previous BitWriter can be reused. See
+    /// into_parts().
+    pub fn with_buffer(writer: W, block_size: u8, mut buffer: Vec<u8>) -> Self {
+        buffer.clear();
         Self {
-            writer: match result {
-                Ok(file) => Box::new(file),
-                Err(_) => Box::new(std::io::stdout()),
-            },
-            output: Vec::with_capacity(block_size as usize * 100000),
+            writer,
+            output: buffer,
             queue: 0,
             q_bits: 0,
-            block_size,
+            block_size: block_size.clamp(1, 9),
             stream_crc: 0,
+            state: StreamState::Header,
         }
     }
 
@@ -69,21 +80,22 @@ impl BitWriter {
         let magic = "BZh".as_bytes();
         magic.iter().for_each(|&x| self.out8(x));
         self.out8(self.block_size + 0x30);
+        self.state = StreamState::Blocks;
     }
 
-    /// Add a block of data to the output. The block is assumed to be packed by BitPacker. "last"
-    /// indicates if the block is the last block in the file. "padding" indicates how many
-    /// trailing zeros were added to the last byte of the block to make it a multiple of 8 bits.
-    pub fn add_block(
-        &mut self,
-        last: bool,
-        data: &[u8],
-        padding: u8,
-    ) -> Result<usize, std::io::Error> {
-        // If this is the first block, write the header
-        if self.stream_crc == 0 {
-            self.push_header()
-        };
+    /// Add a block of data to the output. The block is assumed to be packed by BitPacker. "padding" indicates how
+    /// many trailing zeros were added to the last byte of the block to make it a multiple of 8 bits.
+    pub fn add_block(&mut self, data: &[u8], padding: u8) -> Result<(), Error> {
+        if self.state == StreamState::Finished {
+            return Err(Error::other("Cannot add a block to a finished stream"));
+        }
+        if data.len() < 10 || padding > 7 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Block is too short or has invalid padding"));
+        }
+        // Only change state once the block is known to be good
+        if self.state == StreamState::Header {
+            self.push_header();
+        }
 
         // Get the CRC from this block
         let block_crc = u32::from_be_bytes(data[6..10].try_into().unwrap());
@@ -91,44 +103,82 @@ impl BitWriter {
         self.stream_crc = do_stream_crc(self.stream_crc, block_crc);
 
         // Write all the block data
-        data.iter().for_each(|&x| self.out8(x));
+        self.splice(data, padding);
 
-        // Back up the queue to remove any padding on the last byte
-        if padding > 0 {
-            self.queue >>= padding as u64;
-            self.q_bits -= padding
+        // Write out the data in the bitstream buffer. The queue will carry over to the next block.
+        self.writer.write_all(&self.output)?;
+        self.output.clear();
+        Ok(())
+    }
+
+    /// Write the stream footer and flush the output. The header is written first if there were no blocks, giving
+    /// an empty stream. Calling finish() again does nothing.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match self.state {
+            StreamState::Header => self.push_header(),
+            StreamState::Blocks => {}
+            StreamState::Finished => return Ok(()),
         }
 
-        // If this is the last block, add the footer to the queue.
-        if last {
-            // First the stream footer magic, then the block_crc
-            let magic = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
-            magic.iter().for_each(|&x| self.out8(x));
-            // Write the stream crc
-            self.out8((self.stream_crc >> 24) as u8);
-            self.out8((self.stream_crc >> 16) as u8);
-            self.out8((self.stream_crc >> 8) as u8);
-            self.out8((self.stream_crc) as u8);
-
-            // Now flush the queue
-            self.flush();
-
-            // And write out the remaining (flushed) data in the bitstream buffer.
-            let result = self.writer.write(&self.output);
-
-            // Drain what we wrote from the buffer
-            if let Ok(written) = result {
-                self.output.drain(..written);
-            }
-            result
+        // First the stream footer magic, then the stream crc
+        let magic = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+        magic.iter().for_each(|&x| self.out8(x));
+        self.stream_crc.to_be_bytes().iter().for_each(|&x| self.out8(x));
+
+        // Now flush the queue and write out the remaining data in the bitstream buffer.
+        self.flush();
+        self.writer.write_all(&self.output)?;
+        self.output.clear();
+        self.state = StreamState::Finished;
+        self.writer.flush()
+    }
+
+    /// Return the output stream.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Return the output stream and the output buffer, so the buffer can be reused.
+    pub fn into_parts(self) -> (W, Vec<u8>) {
+        (self.writer, self.output)
+    }
+
+    /// Append a packed block to the stream, dropping the padding bits at the end of its last byte.
+    ///
+    /// The stream is usually not on a byte boundary when a block arrives, so every byte of the block must be shifted
+    /// to line up with the bits already in the queue. This is done 64 bits at a time. If the stream is on a byte
+    /// boundary, the block is copied to the output untouched.
+    fn splice(&mut self, data: &[u8], padding: u8) {
+        let Some((&last_byte, body)) = data.split_last() else {
+            return;
+        };
+        // Move the full bytes out of the queue, leaving 0-7 bits
+        while self.q_bits > 7 {
+            self.output.push((self.queue >> (self.q_bits - 8)) as u8);
+            self.q_bits -= 8;
+        }
+        self.queue &= (1 << self.q_bits) - 1;
+
+        if self.q_bits == 0 {
+            self.output.extend_from_slice(body);
         } else {
-            // Write out the data in the bitstream buffer. The queue will carry over to the next block.
-            let result = self.writer.write(&self.output);
-            if result.is_ok() {
-                self.output.drain(..result.as_ref().unwrap());
+            // The queue bits go in front of each word, and the low bits of the word become the new queue
+            let offset = self.q_bits as u32;
+            let mut words = body.chunks_exact(8);
+            self.output.reserve(body.len());
+            for word in &mut words {
+                let word = u64::from_be_bytes(word.try_into().unwrap());
+                let out = (self.queue << (64 - offset)) | (word >> offset);
+                self.output.extend_from_slice(&out.to_be_bytes());
+                self.queue = word & ((1 << offset) - 1);
             }
-            result
+            words.remainder().iter().for_each(|&x| self.out8(x));
         }
+        self.out8(last_byte);
+
+        // Back up the queue to remove any padding on the last byte
+        self.queue >>= padding as u64;
+        self.q_bits -= padding;
     }
 
     /// Internal bitstream write function common to all out.XX functions.
@@ -175,11 +225,12 @@ impl BitWriter {
 #[cfg(test)]
 mod test {
     use super::BitWriter;
+    use std::io::{Error, ErrorKind, Write};
 
     #[test]
     fn out8_test() {
-        let mut bw = BitWriter::new("", 1);
-        let data = 'x' as u8;
+        let mut bw = BitWriter::new(vec![], 1);
+        let data = b'x';
         bw.out8(data);
         bw.flush();
         let out = bw.output;
@@ -188,7 +239,7 @@ mod test {
 
     #[test]
     fn last_bits_test_1() {
-        let mut bw = BitWriter::new("", 1);
+        let mut bw = BitWriter::new(vec![], 1);
         bw.out8(255);
         bw.out8(1);
         bw.out8(128);
@@ -201,11 +252,109 @@ mod test {
 
     #[test]
     fn out24_short_test() {
-        let mut bw = BitWriter::new("", 100);
+        let mut bw = BitWriter::new(vec![], 100);
         bw.out8(255);
         bw.out8(6 << 5);
         bw.flush();
         let out2 = &bw.output;
         assert_eq!(out2, &[0b1111_1111, 0b1100_0000]); // Note: '33' is data from previous call
     }
+
+    /// A BitWriter that only builds its output buffer.
+    fn buffer_writer() -> BitWriter<Vec<u8>> {
+        BitWriter::new(vec![], 9)
+    }
+
+    #[test]
+    fn splice_test() {
+        let data = (0..100_u32).map(|i| (i * 37 + 11) as u8).collect::<Vec<u8>>();
+        for lead_bits in 0..20 {
+            for len in [1, 2, 7, 8, 9, 16, 17, 100] {
+                for padding in [0, 3, 7] {
+                    // Lead bits alternate 1 and 0, so the block starts at every bit offset
+                    let lead = (0..lead_bits).fold(0_u64, |acc, i| acc << 1 | (i & 1));
+                    let block = &data[..len];
+
+                    // Byte at a time, as add_block did before
+                    let mut expected = buffer_writer();
+                    expected.queue = lead;
+                    expected.q_bits = lead_bits as u8;
+                    block.iter().for_each(|&x| expected.out8(x));
+                    expected.queue >>= padding;
+                    expected.q_bits -= padding as u8;
+                    expected.out8(0xA5);
+                    expected.flush();
+
+                    let mut bw = buffer_writer();
+                    bw.queue = lead;
+                    bw.q_bits = lead_bits as u8;
+                    bw.splice(block, padding as u8);
+                    bw.out8(0xA5);
+                    bw.flush();
+                    assert_eq!(bw.output, expected.output, "lead {} len {} padding {}", lead_bits, len, padding);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_stream_test() {
+        let mut bw = buffer_writer();
+        bw.finish().unwrap();
+        bw.finish().unwrap();
+        assert_eq!(
+            bw.into_inner(),
+            [b'B', b'Z', b'h', b'9', 0x17, 0x72, 0x45, 0x38, 0x50, 0x90, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn zero_stream_crc_test() {
+        // A block CRC of 0 leaves the stream CRC at 0. The header must still be written only once.
+        let block = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0, 0, 0, 0, 0x80];
+        let mut bw = buffer_writer();
+        bw.add_block(&block, 7).unwrap();
+        bw.add_block(&block, 7).unwrap();
+        bw.finish().unwrap();
+        let out = bw.into_inner();
+        assert_eq!(out.windows(3).filter(|w| w == b"BZh").count(), 1);
+    }
+
+    /// A writer that fails on every write.
+    struct FailingWriter;
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(Error::new(ErrorKind::BrokenPipe, "closed"))
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_error_test() {
+        let block = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 1, 2, 3, 4, 0x80];
+        let result = BitWriter::new(FailingWriter, 9).add_block(&block, 7);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::BrokenPipe);
+        let result = BitWriter::new(FailingWriter, 9).finish();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+        // Nothing can be added once the stream is finished
+        let mut bw = buffer_writer();
+        bw.finish().unwrap();
+        assert_eq!(bw.add_block(&block, 7).unwrap_err().kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn rejected_block_test() {
+        // A bad block must leave the writer as it was, so finish() still gives an empty stream
+        let mut bw = buffer_writer();
+        assert_eq!(bw.add_block(&[0; 4], 0).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(bw.add_block(&[0; 11], 8).unwrap_err().kind(), ErrorKind::InvalidInput);
+        bw.finish().unwrap();
+        assert_eq!(
+            bw.into_inner(),
+            [b'B', b'Z', b'h', b'9', 0x17, 0x72, 0x45, 0x38, 0x50, 0x90, 0, 0, 0, 0]
+        );
+    }
 }
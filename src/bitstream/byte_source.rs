@@ -0,0 +1,272 @@
+//! ByteSource is the input side of the I/O subsystem. It hands the input out as slices, so the RLE1 splitter and
+//! the BitReader can work on the data where it is instead of each copying it into a buffer of their own.
+//!
+//! There are two sources:
+//! - ReadSource: reads any Read source (pipes, special files, small files) into a buffer that it reuses.
+//! - MemorySource: data that is already in memory, such as a slice or a memory mapped file. Nothing is copied.
+//!
+//! open_input() maps large regular files into memory and reads everything else, so callers handle both with one
+//! generic function.
+//!
+//! The API is:
+//! - fill(want): make at least want bytes available, unless the source runs out first.
+//! - data(): the bytes available and not yet consumed.
+//! - consume(n): drop the first n bytes of data().
+//! - is_done(): true once data() holds everything left in the source.
+//!
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Default read size of a ReadSource.
+const READ_SIZE: usize = 1024 * 1024;
+/// Files smaller than this are read. Mapping them costs more than copying them.
+const MMAP_MIN_SIZE: usize = 256 * 1024;
+
+/// Input that is handed out as slices.
+pub trait ByteSource {
+    /// Make at least want bytes available in data(), unless the source runs out first.
+    fn fill(&mut self, want: usize) -> io::Result<()>;
+    /// The bytes available and not yet consumed.
+    fn data(&self) -> &[u8];
+    /// Drop the first n bytes of data().
+    fn consume(&mut self, n: usize);
+    /// True once data() holds everything left in the source.
+    fn is_done(&self) -> bool;
+}
+
+/// Reads a Read source into a buffer. The buffer is only moved or grown when more than its size is wanted.
+#[derive(Debug)]
+pub struct ReadSource<R> {
+    source: R,
+    buffer: Vec<u8>,
+    /// Start and end of the unconsumed data in the buffer.
+    start: usize,
+    end: usize,
+    /// Minimum number of bytes asked for in each read.
+    read_size: usize,
+    done: bool,
+}
+
+impl<R: Read> ReadSource<R> {
+    /// Create a source that reads up to 1Mbyte at a time.
+    pub fn new(source: R) -> Self {
+        Self::with_buffer(source, vec![], READ_SIZE)
+    }
+
+    /// Create a source that reads up to size bytes (1 byte to 1Mbyte) at a time. Use this when the source is known
+    /// to be small, so a tiny input doesn't pay for a large buffer.
+    pub fn with_capacity(source: R, size: usize) -> Self {
+        Self::with_buffer(source, vec![], size.clamp(1, READ_SIZE))
+    }
+
+    /// Create a source that reads read_size bytes at a time into buffer, so the buffer of a previous source can be
+    /// reused. See into_buffer().
+    pub fn with_buffer(source: R, mut buffer: Vec<u8>, read_size: usize) -> Self {
+        buffer.clear();
+        Self { source, buffer, start: 0, end: 0, read_size: read_size.max(1), done: false }
+    }
+
+    /// Return the buffer so it can be reused by another source.
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+impl<R: Read> ByteSource for ReadSource<R> {
+    fn fill(&mut self, want: usize) -> io::Result<()> {
+        if self.end - self.start >= want || self.done {
+            return Ok(());
+        }
+        // Move what is left to the front, then make room for the rest (the buffer is only zeroed when it grows).
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        let size = want.max(self.end + self.read_size);
+        if self.buffer.len() < size {
+            self.buffer.resize(size, 0);
+        }
+        while self.end < want {
+            match self.source.read(&mut self.buffer[self.end..]) {
+                // A short read does not mean the source is empty (pipes return what they have), only a zero read does.
+                Ok(0) => {
+                    self.done = true;
+                    break;
+                }
+                Ok(received) => self.end += received,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn data(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    fn consume(&mut self, n: usize) {
+        debug_assert!(n <= self.end - self.start, "Consumed more than was available");
+        self.start += n;
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+/// Data that is already in memory, such as a slice, a vec or a memory mapped file.
+#[derive(Debug)]
+pub struct MemorySource<T> {
+    data: T,
+    cursor: usize,
+}
+
+impl<T: AsRef<[u8]>> MemorySource<T> {
+    pub fn new(data: T) -> Self {
+        Self { data, cursor: 0 }
+    }
+}
+
+impl<T: AsRef<[u8]>> ByteSource for MemorySource<T> {
+    fn fill(&mut self, _want: usize) -> io::Result<()> {
+        // Everything is available already
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn data(&self) -> &[u8] {
+        &self.data.as_ref()[self.cursor..]
+    }
+
+    fn consume(&mut self, n: usize) {
+        debug_assert!(n <= self.data().len(), "Consumed more than was available");
+        self.cursor += n;
+    }
+
+    fn is_done(&self) -> bool {
+        true
+    }
+}
+
+/// An input file, mapped into memory or read.
+pub enum FileSource {
+    Mapped(MemorySource<Mmap>),
+    Read(ReadSource<File>),
+}
+
+/// Open the file at path and return it with its size. The size is only known for regular files: pipes, devices and
+/// /proc files report a size of 0, so None is returned for them. A regular file of at least 256Kbytes is mapped into
+/// memory when mmap is true. Anything else, or a file that can't be mapped, is read.
+pub fn open_input(path: &str, mmap: bool) -> io::Result<(FileSource, Option<usize>)> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let size = metadata.is_file().then_some(metadata.len() as usize);
+    if mmap && size.is_some_and(|size| size >= MMAP_MIN_SIZE) {
+        // SAFETY: The map is only read. If another process truncates the file while it is mapped, reading the
+        // missing pages fails with SIGBUS, just as C programs that map their input do. Use --no-mmap to avoid this.
+        if let Ok(map) = unsafe { Mmap::map(&file) } {
+            return Ok((FileSource::Mapped(MemorySource::new(map)), size));
+        }
+    }
+    let source = match size {
+        Some(size) if size > 0 => ReadSource::with_capacity(file, size),
+        _ => ReadSource::new(file),
+    };
+    Ok((FileSource::Read(source), size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open_input, ByteSource, FileSource, MemorySource, ReadSource, MMAP_MIN_SIZE, READ_SIZE};
+    use std::io::Read;
+
+    /// Returns at most 3 bytes per read, as a pipe might.
+    struct ShortReads<'a>(&'a [u8]);
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = buf.len().min(self.0.len()).min(3);
+            buf[..size].copy_from_slice(&self.0[..size]);
+            self.0 = &self.0[size..];
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn read_source_test() {
+        let data = (0..100_u8).collect::<Vec<u8>>();
+        let mut source = ReadSource::with_capacity(ShortReads(&data), 8);
+        source.fill(10).unwrap();
+        assert!(source.data().len() >= 10 && source.data() == &data[..source.data().len()]);
+        assert!(!source.is_done());
+        source.consume(7);
+        // Asking for no more than is available does not read
+        let available = source.data().len();
+        source.fill(available).unwrap();
+        assert_eq!(source.data().len(), available);
+        // The unconsumed data is kept in front of the new data
+        source.fill(50).unwrap();
+        assert!(source.data().len() >= 50 && source.data() == &data[7..7 + source.data().len()]);
+        source.consume(source.data().len());
+        source.fill(1000).unwrap();
+        assert!(source.is_done());
+        assert_eq!(source.data(), &data[57..]);
+    }
+
+    #[test]
+    fn memory_source_test() {
+        let mut source = MemorySource::new(b"hello world");
+        source.fill(1000).unwrap();
+        assert!(source.is_done());
+        source.consume(6);
+        assert_eq!(source.data(), b"world");
+    }
+
+    #[test]
+    fn open_input_test() {
+        let path = std::env::temp_dir().join(format!("bzip2_test_{}_open_input", std::process::id()));
+        let path = path.to_str().unwrap();
+        for (size, mmap, mapped) in [(MMAP_MIN_SIZE, true, true), (MMAP_MIN_SIZE, false, false), (10, true, false)] {
+            let data = (0..size).map(|i| i as u8).collect::<Vec<u8>>();
+            std::fs::write(path, &data).unwrap();
+            let (input, input_size) = open_input(path, mmap).unwrap();
+            assert_eq!(input_size, Some(size));
+            let read = match input {
+                FileSource::Mapped(source) => {
+                    assert!(mapped);
+                    source.data().to_vec()
+                }
+                FileSource::Read(mut source) => {
+                    assert!(!mapped);
+                    source.fill(size + 1).unwrap();
+                    source.data().to_vec()
+                }
+            };
+            assert!(read == data);
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_pipe_test() {
+        // A pipe reports a size of 0, which must not shrink the reads to one byte
+        let path = std::env::temp_dir().join(format!("bzip2_test_{}_open_pipe", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+        let data = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let writer = {
+            let (path, data) = (path.clone(), data.clone());
+            std::thread::spawn(move || std::fs::write(path, data).unwrap())
+        };
+        let (input, input_size) = open_input(path.to_str().unwrap(), true).unwrap();
+        assert_eq!(input_size, None);
+        let FileSource::Read(mut source) = input else { panic!("a pipe can't be mapped") };
+        assert_eq!(source.read_size, READ_SIZE);
+        source.fill(data.len() + 1).unwrap();
+        assert!(source.is_done() && source.data() == &data[..]);
+        writer.join().unwrap();
+        std::fs::remove_file(&path).ok();
+    }
+}
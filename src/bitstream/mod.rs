@@ -9,6 +9,10 @@
 //! This I/O subsystem is designed to efficiently interface with the other modules within BZIP2. It is not intended for
 //! more general use. (It has not been generalized to handle a wider variety of calles that might be necessary in other applications.)
 //! 
+//! The input reaches the RLE1 splitter and the BitReader through a ByteSource (see byte_source.rs), which maps large
+//! files into memory instead of copying them through read buffers.
+//! 
 pub mod bitwriter;
 pub mod bitpacker;
 pub mod bitreader;
+pub mod byte_source;
@@ -0,0 +1,130 @@
+//! The BwtBackend trait lets the caller choose which sorting algorithm performs the Burrows-Wheeler-Transform.
+//!
+//! Different algorithms suit different kinds of data, so the choice is exposed as the --bwt=<name> command line option
+//! and as BzOpts.bwt for library use. The backends are:
+//! - auto: whichever of the native sort and SA-IS a cost model expects to be faster on the block (the default).
+//! - native: a bucket sort followed by introsort, switching to SA-IS if it runs over its work budget.
+//! - sais: the SA-IS suffix array algorithm.
+//! - sais-parallel: SA-IS, running the steps that allow it on multiple threads.
+//! - julian: a port of Julian Seward's mainSort/fallbackSort from the C version. Reference mode always uses this.
+//!
+use super::bwt_sort::{bwt_encode, bwt_encode_into, native_encode, native_encode_into};
+use super::julian_sort::julian_sort;
+use super::sais_fallback::{sais_bwt_into, sais_entry, sais_entry_parallel};
+use crate::tools::cli::{BwtAlgorithm, BzOpts};
+
+/// A Burrows-Wheeler-Transform implementation.
+pub trait BwtBackend: Sync {
+    /// Name used to select this backend on the command line.
+    fn name(&self) -> &'static str;
+    /// Encode the data, returning the u32 key and a u8 vec of the BWT data. All backends return the same result.
+    fn encode(&self, rle1_data: &[u8], work_factor: usize) -> (u32, Vec<u8>);
+    /// Encode the data into bwt, using index as workspace, and return the key. Both vecs are resized as needed, so
+    /// they can be kept from block to block. Backends that can't use them allocate as encode does.
+    fn encode_into(&self, rle1_data: &[u8], work_factor: usize, _index: &mut Vec<u32>, bwt: &mut Vec<u8>) -> u32 {
+        let (key, data) = self.encode(rle1_data, work_factor);
+        *bwt = data;
+        key
+    }
+}
+
+/// Native sort or SA-IS, whichever is expected to be faster.
+pub struct Auto;
+/// Bucket sort followed by introsort.
+pub struct Native;
+/// SA-IS suffix array algorithm.
+pub struct Sais;
+/// SA-IS suffix array algorithm, multi-threaded where possible.
+pub struct SaisParallel;
+/// Port of the C version's sorting algorithm.
+pub struct Julian;
+
+impl BwtBackend for Auto {
+    fn name(&self) -> &'static str {
+        "auto"
+    }
+    fn encode(&self, rle1_data: &[u8], work_factor: usize) -> (u32, Vec<u8>) {
+        bwt_encode(rle1_data, work_factor)
+    }
+    fn encode_into(&self, rle1_data: &[u8], work_factor: usize, index: &mut Vec<u32>, bwt: &mut Vec<u8>) -> u32 {
+        bwt_encode_into(rle1_data, work_factor, index, bwt)
+    }
+}
+
+impl BwtBackend for Native {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+    fn encode(&self, rle1_data: &[u8], work_factor: usize) -> (u32, Vec<u8>) {
+        native_encode(rle1_data, work_factor)
+    }
+    fn encode_into(&self, rle1_data: &[u8], work_factor: usize, index: &mut Vec<u32>, bwt: &mut Vec<u8>) -> u32 {
+        native_encode_into(rle1_data, work_factor, index, bwt)
+    }
+}
+
+impl BwtBackend for Sais {
+    fn name(&self) -> &'static str {
+        "sais"
+    }
+    fn encode(&self, rle1_data: &[u8], _work_factor: usize) -> (u32, Vec<u8>) {
+        sais_entry(rle1_data)
+    }
+    fn encode_into(&self, rle1_data: &[u8], _work_factor: usize, index: &mut Vec<u32>, bwt: &mut Vec<u8>) -> u32 {
+        sais_bwt_into(rle1_data, false, index, bwt)
+    }
+}
+
+impl BwtBackend for SaisParallel {
+    fn name(&self) -> &'static str {
+        "sais-parallel"
+    }
+    fn encode(&self, rle1_data: &[u8], _work_factor: usize) -> (u32, Vec<u8>) {
+        sais_entry_parallel(rle1_data)
+    }
+    fn encode_into(&self, rle1_data: &[u8], _work_factor: usize, index: &mut Vec<u32>, bwt: &mut Vec<u8>) -> u32 {
+        sais_bwt_into(rle1_data, true, index, bwt)
+    }
+}
+
+impl BwtBackend for Julian {
+    fn name(&self) -> &'static str {
+        "julian"
+    }
+    fn encode(&self, rle1_data: &[u8], work_factor: usize) -> (u32, Vec<u8>) {
+        julian_sort(rle1_data, work_factor)
+    }
+}
+
+/// Get the backend selected in the options. Reference mode always uses the port of the C version.
+pub fn bwt_backend(opts: &BzOpts) -> &'static dyn BwtBackend {
+    if opts.reference {
+        return &Julian;
+    }
+    match opts.bwt {
+        BwtAlgorithm::Auto => &Auto,
+        BwtAlgorithm::Native => &Native,
+        BwtAlgorithm::Sais => &Sais,
+        BwtAlgorithm::SaisParallel => &SaisParallel,
+        BwtAlgorithm::Julian => &Julian,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Auto, BwtBackend, Julian, Native, Sais, SaisParallel};
+
+    #[test]
+    fn backends_agree_test() {
+        let mut data = b"Peter Piper picked a peck of pickled peppers. ".repeat(300);
+        data.extend_from_slice(b"How many pickled peppers did Peter Piper pick?");
+        let expected = Julian.encode(&data, 30);
+        let (mut index, mut bwt) = (vec![], vec![]);
+        for backend in [&Auto as &dyn BwtBackend, &Native, &Sais, &SaisParallel, &Julian] {
+            assert!(backend.encode(&data, 30) == expected, "{} differs", backend.name());
+            // Reusing the buffers from the previous backend
+            let key = backend.encode_into(&data, 30, &mut index, &mut bwt);
+            assert!((key, &bwt) == (expected.0, &expected.1), "{} differs", backend.name());
+        }
+    }
+}
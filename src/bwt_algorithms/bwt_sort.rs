@@ -1,49 +1,186 @@
 //! This is the entry point for the main bwt_sort algorithm and contains the main sorting algorithm.
 //!
-//! The main sorting algorithm is currently based on the standard Rust sort_unstable algorithm. When data is
-//! larger than 5k bytes, we use a multi-threaded approach based on Rayon's par_sort_unstable algorithm.
+//! The main sorting algorithm puts the rotations in buckets by their first two bytes (one byte on small blocks), then sorts each bucket with an
+//! introsort: a quicksort with a median-of-three pivot, finished with insertion sort on small partitions and with
+//! heapsort if the partitions get too deep. Blocks larger than 40k bytes are sorted on several threads, sharing out
+//! the buckets and the two sides of large partitions with Rayon. A sort of our own is used, rather than sort_unstable,
+//! so it can stop as soon as its work budget runs out.
 //!
-//! Since different sorting algorithms are better suited for different kinds of data, this module contains a test
-//! to determine whether the data would be better suited to the main algorithm or the fallback algorithm.
+//! Since different sorting algorithms are better suited for different kinds of data, this module contains a cost model
+//! to determine whether the data would be better suited to the main algorithm or the fallback algorithm (SA-IS). SA-IS
+//! costs about the same for every byte. The main algorithm makes about n log2(n) comparisons, which can be shared by
+//! several threads, and each comparison costs more the longer the rotations match. The model samples rotations from
+//! across the whole block to measure how long they match.
+//!
+//! On one thread SA-IS is measured to be about 1.3-2 times faster than the main algorithm on blocks of 10k to 300k
+//! bytes of text (see benches/sort.rs), so the main algorithm is only chosen for tiny blocks, or when a large block can
+//! be sorted on enough threads.
 //! 
 //! NOTE: 
-//! * During the earlier development and testing phases, I ported Julian Seward's sort algorithm into Rust. However 
-//! the built-in sort_unstable algorithm performed equally well as my port. Using the built-in algorithm avoids a lot of complexity. 
-//! (I do welcome suggestions for improved sorting algorithms.)
-//! * I have tried multiple different algorithms to test data entropy in order to choose when to select the fallback algorithm. The final
-//! method I finally implemented is based on the "left most smaller" (LMS) concept in suffix array algorithms.
+//! * Julian Seward's sort algorithm is ported in julian_sort.rs. It is used for --reference output and can be chosen
+//!   with --bwt=julian. It is faster than the main algorithm on one thread, but much more complex.
+//!   (I do welcome suggestions for improved sorting algorithms.)
+//! * The native sort can go quadratic on highly repetitive data. It is given a work budget based on the work factor, and
+//!   when that runs out the block is sorted with SA-IS instead.
+//! * I have tried multiple different algorithms to test data entropy in order to choose when to select the fallback algorithm. An earlier
+//!   method was based on the "left most smaller" (LMS) concept in suffix array algorithms, applied to the first 5k bytes of the block.
+//!   Measuring the comparison depth of a sample from across the block directly predicts the cost that matters to the main algorithm.
 //!
-use super::sais_fallback::sais_entry;
-use crate::bwt_algorithms::sais_fallback::lms_complexity;
+use super::sais_fallback::sais_bwt_into;
+use crate::tools::crc::crc_byte;
 use log::info;
 use rayon::prelude::*;
+use std::cmp::Ordering::{Equal, Less};
+use std::sync::atomic::{AtomicIsize, Ordering};
 /*
 I tried a varient that used a double length block to avoid the nested equality checks
 in block_compare, but it was barely faster.
 */
 
-/// Encode data using the Burrows-Wheeler-Transform. Requires a u8 slice of data to be sorted.
+/// Encode data using the Burrows-Wheeler-Transform. Requires a u8 slice of data to be sorted and the work factor.
 /// This returns a u32 key and a u8 vec of the BWT data.
-pub fn bwt_encode(rle1_data: &[u8]) -> (u32, Vec<u8>) {
-    // Test data longer than 5k bytes to help select the best algorithm
-    if rle1_data.len() > 5_000 && lms_complexity(&rle1_data[0..5_000.min(rle1_data.len())]) < 0.3 {
-        info!("Using SA-IS algorithm.");
-        return sais_entry(rle1_data);
+pub fn bwt_encode(rle1_data: &[u8], work_factor: usize) -> (u32, Vec<u8>) {
+    let mut bwt = vec![];
+    let key = bwt_encode_into(rle1_data, work_factor, &mut vec![], &mut bwt);
+    (key, bwt)
+}
+
+/// Same as bwt_encode, but the BWT data is written to bwt, and index is used as the workspace for the sort. Both
+/// vecs are resized as needed, so they can be reused from block to block. Returns the key.
+pub fn bwt_encode_into(rle1_data: &[u8], work_factor: usize, index: &mut Vec<u32>, bwt: &mut Vec<u8>) -> u32 {
+    let end = rle1_data.len();
+    let threads = if end > PARALLEL_MIN_LEN { rayon::current_num_threads() } else { 1 };
+    let log2_n = (usize::BITS - end.leading_zeros()) as usize;
+
+    // Only sample the block if the native sort could beat SA-IS when no rotations match past their first chunk
+    if log2_n <= SAIS_COST * threads {
+        let estimate = estimate_native_work(rle1_data);
+        let budget = end * work_factor.clamp(1, 100);
+        let native_cost = (end * log2_n + estimate) / threads;
+        // The estimate is rough, so also switch well before the native sort would run out of budget
+        let use_sais = native_cost > end * SAIS_COST || estimate > budget / 2;
+        info!(
+            "Block of {} bytes on {} threads: estimated native sort cost {} against SA-IS cost {}, work {} of budget {}. Using {} algorithm.",
+            end,
+            threads,
+            native_cost,
+            end * SAIS_COST,
+            estimate,
+            budget,
+            if use_sais { "SA-IS" } else { "native" }
+        );
+        if !use_sais {
+            return native_encode_into(rle1_data, work_factor, index, bwt);
+        }
+    } else {
+        info!("Block of {} bytes on {} threads. Using SA-IS algorithm.", end, threads);
+    }
+    sais_encode_into(rle1_data, index, bwt)
+}
+
+/// Cost of sorting a block with SA-IS, per byte, counted in comparisons of the native sort. On one thread SA-IS was
+/// measured at 70-150ns per byte and a native comparison that ends in its first chunk at about 15ns.
+const SAIS_COST: usize = 8;
+
+/// Maximum number of strata sampled by estimate_native_work. One rotation is sampled from each.
+const SAMPLE_STRATA: usize = 4096;
+/// Smaller blocks get one stratum per this many bytes, so the sample stays cheap next to the sort itself.
+const SAMPLE_SPACING: usize = 16;
+/// Sampled comparisons stop after this many chunks.
+const SAMPLE_MAX_CHUNKS: usize = 32;
+
+/// Estimate the budget the native sort would use on this block, in the same units as the budget (chunks compared
+/// after the first chunk of each comparison).
+///
+/// The block is split into equal strata and one rotation is taken from a pseudo-random position in each, so repetitive
+/// data anywhere in the block is seen. Repeats with a long period show up as sampled rotations that match each other.
+/// The sample is sorted and the average number of extra chunks per comparison is measured. The native sort makes
+/// about n log2(n) comparisons, so the estimate is n log2(n) times that average.
+fn estimate_native_work(block: &[u8]) -> usize {
+    let end = block.len();
+    let strata = (end / SAMPLE_SPACING).clamp(1, SAMPLE_STRATA);
+    // (An empty block still gets one stratum of one byte, which is never compared.)
+    let stratum_size = (end / strata).max(1);
+    let mut seed = 0x2545_f491_u32;
+    let mut sample = (0..strata)
+        .map(|stratum| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (stratum * stratum_size + (seed >> 8) as usize % stratum_size) as u32
+        })
+        .collect::<Vec<u32>>();
+
+    let mut comparisons = 0_usize;
+    let mut extra_chunks = 0_usize;
+    sample.sort_unstable_by(|&a, &b| {
+        comparisons += 1;
+        sample_compare(a as usize, b as usize, block, &mut extra_chunks)
+    });
+
+    let log2_n = usize::BITS - end.leading_zeros();
+    (end as f64 * log2_n as f64 * extra_chunks as f64 / comparisons.max(1) as f64) as usize
+}
+
+/// compare two rotations for estimate_native_work, counting the chunks compared after the first. Comparisons stop
+/// after SAMPLE_MAX_CHUNKS chunks, and the positions break the tie so the order is still consistent.
+fn sample_compare(a: usize, b: usize, block: &[u8], extra_chunks: &mut usize) -> std::cmp::Ordering {
+    let end = block.len();
+    let (mut i, mut j) = (a, b);
+    let mut remaining = end;
+    for chunk in 0..SAMPLE_MAX_CHUNKS {
+        if remaining == 0 {
+            break;
+        }
+        if chunk > 0 {
+            *extra_chunks += 1;
+        }
+        let len = COMPARE_CHUNK.min(remaining).min(end - i).min(end - j);
+        let result = block[i..i + len].cmp(&block[j..j + len]);
+        if result != std::cmp::Ordering::Equal {
+            return result;
+        }
+        remaining -= len;
+        i = if i + len == end { 0 } else { i + len };
+        j = if j + len == end { 0 } else { j + len };
     }
+    // A match this long is most likely a repeat, which on average continues for half of the rest of the block.
+    *extra_chunks += remaining / COMPARE_CHUNK / 2;
+    a.cmp(&b)
+}
 
-    info!("Using native algorithm.");
+/// Encode data using the Burrows-Wheeler-Transform with the native sort. The sort is given a work budget based on
+/// the work factor (1-100, default 30). If the data is so repetitive that the budget runs out, the sort is abandoned and
+/// the block is sorted with SA-IS instead, like the fallback sort of the C version.
+/// This returns a u32 key and a u8 vec of the BWT data.
+pub fn native_encode(rle1_data: &[u8], work_factor: usize) -> (u32, Vec<u8>) {
+    let mut bwt = vec![];
+    let key = native_encode_into(rle1_data, work_factor, &mut vec![], &mut bwt);
+    (key, bwt)
+}
+
+/// Same as native_encode, but the BWT data is written to bwt, and index holds the sorted rotations. Returns the key.
+pub fn native_encode_into(rle1_data: &[u8], work_factor: usize, index: &mut Vec<u32>, bwt: &mut Vec<u8>) -> u32 {
     // Create index into block. Index is u32, which should be more than enough
-    let mut index = (0_u32..rle1_data.len() as u32).collect::<Vec<u32>>();
+    index.clear();
+    index.extend(0_u32..rle1_data.len() as u32);
+
+    // The budget counts the chunks compared after the first chunk of each comparison.
+    let budget = rle1_data.len() * work_factor.clamp(1, 100);
 
     // Sort index
-    if rle1_data.len() > 40000 {
-        index[..].par_sort_unstable_by(|a, b| block_compare(*a as usize, *b as usize, rle1_data));
+    let sorted = if rle1_data.len() > PARALLEL_MIN_LEN {
+        par_sort(index, rle1_data, budget)
     } else {
-        index[..].sort_unstable_by(|a, b| block_compare(*a as usize, *b as usize, rle1_data));
+        sort(index, rle1_data, budget)
+    };
+    if !sorted {
+        info!("Native sort ran over budget. Using SA-IS algorithm.");
+        return sais_encode_into(rle1_data, index, bwt);
     }
+
     // Get key and BWT output
     let mut key = 0_u32;
-    let mut bwt = vec![0; rle1_data.len()];
+    bwt.clear();
+    bwt.resize(rle1_data.len(), 0);
     for i in 0..bwt.len() {
         if index[i] == 0 {
             key = i as u32;
@@ -54,78 +191,539 @@ pub fn bwt_encode(rle1_data: &[u8]) -> (u32, Vec<u8>) {
             bwt[i] = rle1_data[(index[i] as usize) - 1];
         }
     }
-    (key, bwt)
+    key
 }
 
-/// compare the next two chunks of the original data to decide which sorts first
-fn block_compare(a: usize, b: usize, block: &[u8]) -> std::cmp::Ordering {
-    let min = std::cmp::min(block[a..].len(), block[b..].len());
+/// Sort with SA-IS, using the parallel variant for large blocks when rayon has more than one thread to spare.
+fn sais_encode_into(rle1_data: &[u8], index: &mut Vec<u32>, bwt: &mut Vec<u8>) -> u32 {
+    let parallel = rle1_data.len() > PARALLEL_MIN_LEN && rayon::current_num_threads() > 1;
+    sais_bwt_into(rle1_data, parallel, index, bwt)
+}
 
-    // Lexicographical comparison
-    let mut result = block[a..a + min].cmp(&block[b..b + min]);
+/// Blocks up to this size are sorted on the calling thread. Starting the rayon pool costs more than it saves on
+/// them, so a small input never starts it.
+const PARALLEL_MIN_LEN: usize = 40_000;
 
-    // Implement wraparound if needed
-    if result == std::cmp::Ordering::Equal {
-        if a < b {
-            let to_end = block.len() - a - min;
-            result = block[(a + min)..].cmp(&block[..to_end]);
-            if result == std::cmp::Ordering::Equal {
-                let rest_of_block = block.len() - to_end - min;
-                return block[..rest_of_block].cmp(&block[to_end..(to_end + rest_of_block)]);
-            }
+/// Size of the chunks the budget is counted in. Only chunks after the first of a comparison are charged to it.
+const COMPARE_CHUNK: usize = 32;
+
+/// Partitions up to this size are finished with insertion sort.
+const INSERTION_MAX: usize = 16;
+
+/// In a parallel sort, both sides of partitions larger than this are sorted at the same time.
+const PARALLEL_SPLIT_MIN: usize = 4_096;
+
+/// Chunks a task counts on its own before taking them from the shared budget, so the threads of a parallel sort
+/// don't contend on it.
+const BUDGET_BATCH: usize = 1024;
+
+/// Returned through the sort when the budget runs out, so the native sort can be abandoned.
+#[derive(Debug)]
+struct BudgetExhausted;
+
+/// The budget of one task of a sort. Chunks are counted locally and taken from the budget shared by the tasks of the
+/// same sort BUDGET_BATCH at a time.
+struct Budget<'a> {
+    shared: &'a AtomicIsize,
+    uncharged: usize,
+}
+
+impl<'a> Budget<'a> {
+    fn new(shared: &'a AtomicIsize) -> Self {
+        Budget { shared, uncharged: 0 }
+    }
+
+    /// Count chunks compared. Fails when the shared budget runs out.
+    #[inline]
+    fn charge(&mut self, chunks: usize) -> Result<(), BudgetExhausted> {
+        self.uncharged += chunks;
+        if self.uncharged >= BUDGET_BATCH {
+            return self.flush();
+        }
+        Ok(())
+    }
+
+    /// Take the chunks counted so far from the shared budget. Fails if it has run out.
+    fn flush(&mut self) -> Result<(), BudgetExhausted> {
+        let chunks = std::mem::take(&mut self.uncharged) as isize;
+        match self.shared.fetch_sub(chunks, Ordering::Relaxed) - chunks < 0 {
+            true => Err(BudgetExhausted),
+            false => Ok(()),
+        }
+    }
+
+    /// Check that no other task of the sort has used up the shared budget.
+    fn exhausted(&self) -> bool {
+        self.shared.load(Ordering::Relaxed) < 0
+    }
+
+    /// Compare two rotations, charging the chunks compared after the first.
+    #[inline]
+    fn less(&mut self, a: u32, b: u32, block: &[u8]) -> Result<bool, BudgetExhausted> {
+        let (result, chunks) = block_compare(a as usize, b as usize, block);
+        if chunks > 0 {
+            self.charge(chunks)?;
+        }
+        Ok(result == Less)
+    }
+}
+
+/// Sort the rotations in index on the calling thread. Returns false if the budget ran out, leaving index in no
+/// particular order.
+fn sort(index: &mut [u32], block: &[u8], budget: usize) -> bool {
+    run_introsort(index, block, budget, false)
+}
+
+/// Sort the rotations in index on the rayon threads. Returns false if the budget ran out, leaving index in no
+/// particular order.
+fn par_sort(index: &mut [u32], block: &[u8], budget: usize) -> bool {
+    run_introsort(index, block, budget, true)
+}
+
+/// Sort with a budget that belongs to this sort alone. The rotations are first put in buckets by their first two bytes
+/// with a counting sort, then each bucket is sorted with introsort. Every task flushes what it has counted when it
+/// ends, so nothing is left over for a later sort.
+fn run_introsort(index: &mut [u32], block: &[u8], budget: usize, parallel: bool) -> bool {
+    let shared = AtomicIsize::new(budget.min(isize::MAX as usize) as isize);
+    let buckets = bucket_sort(index, block);
+    let sort_bucket = |bucket: &mut [u32]| {
+        let depth_limit = 2 * (usize::BITS - bucket.len().leading_zeros());
+        sort_task(bucket, block, &shared, depth_limit, parallel)
+    };
+    match parallel {
+        true => buckets.into_par_iter().try_for_each(sort_bucket).is_ok(),
+        false => buckets.into_iter().try_for_each(sort_bucket).is_ok(),
+    }
+}
+
+/// Sort the rotations in index as a task of its own, counting its chunks in its own Budget and flushing them at the end.
+fn sort_task(
+    index: &mut [u32],
+    block: &[u8],
+    shared: &AtomicIsize,
+    depth_limit: u32,
+    parallel: bool,
+) -> Result<(), BudgetExhausted> {
+    let mut budget = Budget::new(shared);
+    introsort(index, block, &mut budget, depth_limit, parallel)?;
+    budget.flush()
+}
+
+/// Blocks smaller than this are put in buckets by their first byte only, so the counts stay small next to the block.
+const TWO_BYTE_BUCKETS_MIN_LEN: usize = 1 << 16;
+
+/// Put the rotations in index in order of their first two bytes (first byte for small blocks) with a counting sort.
+/// Returns the buckets of more than one rotation, which still need sorting.
+fn bucket_sort<'a>(index: &'a mut [u32], block: &[u8]) -> Vec<&'a mut [u32]> {
+    let end = block.len();
+    let two_bytes = end >= TWO_BYTE_BUCKETS_MIN_LEN;
+    let key = |i: usize| match two_bytes {
+        true => (block[i] as usize) << 8 | block[if i + 1 == end { 0 } else { i + 1 }] as usize,
+        false => block[i] as usize,
+    };
+    let mut starts = vec![0_u32; if two_bytes { 65_537 } else { 257 }];
+    for i in 0..end {
+        starts[key(i) + 1] += 1;
+    }
+    for k in 1..starts.len() {
+        starts[k] += starts[k - 1];
+    }
+    let mut next = starts.clone();
+    for i in 0..end {
+        let k = key(i);
+        index[next[k] as usize] = i as u32;
+        next[k] += 1;
+    }
+
+    let mut buckets = vec![];
+    let mut rest = index;
+    for k in 0..starts.len() - 1 {
+        let (bucket, tail) = std::mem::take(&mut rest).split_at_mut((starts[k + 1] - starts[k]) as usize);
+        if bucket.len() > 1 {
+            buckets.push(bucket);
+        }
+        rest = tail;
+    }
+    buckets
+}
+
+/// Sort the rotations in index. Quicksort is used until depth_limit levels of partitions, then heapsort. Stops with
+/// BudgetExhausted as soon as the budget runs out, leaving index in no particular order.
+fn introsort(
+    mut index: &mut [u32],
+    block: &[u8],
+    budget: &mut Budget,
+    mut depth_limit: u32,
+    parallel: bool,
+) -> Result<(), BudgetExhausted> {
+    loop {
+        if index.len() <= INSERTION_MAX {
+            return insertion_sort(index, block, budget);
+        }
+        // Another task may have used up the budget
+        if budget.exhausted() {
+            return Err(BudgetExhausted);
+        }
+        if depth_limit == 0 {
+            return heapsort(index, block, budget);
+        }
+        depth_limit -= 1;
+
+        let pivot = partition(index, block, budget)?;
+        let (left, right) = std::mem::take(&mut index).split_at_mut(pivot);
+        let right = &mut right[1..];
+        if parallel && left.len().min(right.len()) > PARALLEL_SPLIT_MIN {
+            let shared = budget.shared;
+            let (left, right) = rayon::join(
+                || sort_task(left, block, shared, depth_limit, parallel),
+                || sort_task(right, block, shared, depth_limit, parallel),
+            );
+            return left.and(right);
+        }
+        // Recurse into the smaller side and loop on the larger, so the stack stays shallow
+        if left.len() < right.len() {
+            introsort(left, block, budget, depth_limit, parallel)?;
+            index = right;
         } else {
-            let to_end = block.len() - b - min;
-            result = block[..to_end].cmp(&block[(b + min)..]);
-            if result == std::cmp::Ordering::Equal {
-                let rest_of_block = block.len() - to_end - min;
-                return block[to_end..(to_end + rest_of_block)].cmp(&block[..rest_of_block]);
-            }
+            introsort(right, block, budget, depth_limit, parallel)?;
+            index = left;
         }
     }
-    result
 }
 
+/// Partition index around the median of its first, middle and last rotations. Returns the final position of the
+/// pivot: rotations before it sort first, rotations after it sort after it.
+fn partition(index: &mut [u32], block: &[u8], budget: &mut Budget) -> Result<usize, BudgetExhausted> {
+    let (first, middle, last) = (0, index.len() / 2, index.len() - 1);
+    let first_last = budget.less(index[first], index[last], block)?;
+    let mut pivot = middle;
+    if budget.less(index[first], index[middle], block)? != first_last {
+        pivot = first;
+    } else if budget.less(index[middle], index[last], block)? != first_last {
+        pivot = last;
+    }
 
-/// Decode a Burrows-Wheeler-Transform. Requires a key, a u8 slice containing the BWT data, and an array of the u8 frequencies
-/// found in the data. Returns the decoded data as a u8 vec.
-pub fn bwt_decode(mut key: u32, bwt_in: &[u8], freq_in: &[u32]) -> Vec<u8> {
-    /* LOGIC:
-    Encode the current byte and the index to the next byte in each element of the transformation vector.
-    The leftmost byte of each element is the current byte value. The rightmost three bytes are the index to the next byte.
-    */
+    index.swap(0, pivot);
+    let pivot = index[0];
+    let mut store = 1;
+    for i in 1..index.len() {
+        if budget.less(index[i], pivot, block)? {
+            index.swap(i, store);
+            store += 1;
+        }
+    }
+    index.swap(0, store - 1);
+    Ok(store - 1)
+}
+
+/// Sort a short run of rotations with insertion sort.
+fn insertion_sort(index: &mut [u32], block: &[u8], budget: &mut Budget) -> Result<(), BudgetExhausted> {
+    for i in 1..index.len() {
+        let mut j = i;
+        while j > 0 && budget.less(index[j], index[j - 1], block)? {
+            index.swap(j, j - 1);
+            j -= 1;
+        }
+    }
+    Ok(())
+}
+
+/// Sort rotations with heapsort, which never needs more than n log2(n) comparisons.
+fn heapsort(index: &mut [u32], block: &[u8], budget: &mut Budget) -> Result<(), BudgetExhausted> {
+    for node in (0..index.len() / 2).rev() {
+        sift_down(index, node, index.len(), block, budget)?;
+    }
+    for end in (1..index.len()).rev() {
+        index.swap(0, end);
+        sift_down(index, 0, end, block, budget)?;
+    }
+    Ok(())
+}
+
+/// Move the rotation at node down the heap in index[..end] until neither child sorts after it.
+fn sift_down(
+    index: &mut [u32],
+    mut node: usize,
+    end: usize,
+    block: &[u8],
+    budget: &mut Budget,
+) -> Result<(), BudgetExhausted> {
+    loop {
+        let mut child = 2 * node + 1;
+        if child >= end {
+            return Ok(());
+        }
+        if child + 1 < end && budget.less(index[child], index[child + 1], block)? {
+            child += 1;
+        }
+        if !budget.less(index[node], index[child], block)? {
+            return Ok(());
+        }
+        index.swap(node, child);
+        node = child;
+    }
+}
+
+/// compare the rotations of the original data starting at a and b to decide which sorts first. Also returns the
+/// number of chunks compared after the first, which are charged to the budget.
+#[inline]
+fn block_compare(a: usize, b: usize, block: &[u8]) -> (std::cmp::Ordering, usize) {
+    let end = block.len();
+    let (mut a, mut b) = (a, b);
+    let mut matched = 0;
+
+    while matched < end {
+        // Compare up to the end of the block, implementing wraparound as needed
+        let len = (end - matched).min(end - a).min(end - b);
+        let same = common_prefix(&block[a..a + len], &block[b..b + len]);
+        if same < len {
+            return (block[a + same].cmp(&block[b + same]), (matched + same) / COMPARE_CHUNK);
+        }
+        matched += len;
+        a = if a + len == end { 0 } else { a + len };
+        b = if b + len == end { 0 } else { b + len };
+    }
+    (Equal, (end - 1) / COMPARE_CHUNK)
+}
 
-    // Calculate end once.
+/// Return the number of leading bytes that x and y (of the same length) have in common, comparing eight at a time.
+#[inline]
+fn common_prefix(x: &[u8], y: &[u8]) -> usize {
+    let mut same = 0;
+    for (x8, y8) in x.chunks_exact(8).zip(y.chunks_exact(8)) {
+        let diff = u64::from_le_bytes(x8.try_into().unwrap()) ^ u64::from_le_bytes(y8.try_into().unwrap());
+        if diff != 0 {
+            // The first byte that differs is the lowest byte of diff that isn't zero
+            return same + diff.trailing_zeros() as usize / 8;
+        }
+        same += 8;
+    }
+    same + x[same..].iter().zip(&y[same..]).take_while(|(x, y)| x == y).count()
+}
+
+/// Undo the Burrows-Wheeler-Transform and the RLE1 encoding of a block in one pass, computing the block CRC as
+/// the data is produced. Requires a key, a u8 slice containing the BWT data, and an array of the u8 frequencies
+/// found in the data. The decoded data is appended to out and the CRC of the block is returned. If out would grow
+/// past max_out bytes, None is returned and out holds part of the block.
+///
+/// This avoids the two full size vecs that a separate inverse BWT followed by rle1_decode would need. The transformation vec is
+/// kept in t_vec so it can be reused for the next block.
+pub fn bwt_rle1_decode(
+    key: u32,
+    bwt_in: &[u8],
+    freq_in: &[u32],
+    t_vec: &mut Vec<u32>,
+    out: &mut Vec<u8>,
+    max_out: usize,
+) -> Option<u32> {
     let end = bwt_in.len();
+    if end == 0 {
+        return Some(0);
+    }
 
     // Convert frequency count to a cumulative sum of frequencies
     let mut freq = [0_u32; 256];
-
-    {
-        for i in 0..255 {
-            freq[i + 1] = freq[i] + freq_in[i];
-        }
+    for i in 0..255 {
+        freq[i + 1] = freq[i] + freq_in[i];
     }
 
-    //Build the transformation vector to find the next character in the original data
-    let mut t_vec = vec![0_u32; end];
+    // Build the transformation vector. Each element holds the current byte in its leftmost byte
+    // and the index to the next byte in the rightmost three bytes, so each step is a single memory access.
+    t_vec.clear();
+    t_vec.resize(end, 0);
     for (i, &s) in bwt_in.iter().enumerate() {
         t_vec[i] |= (s as u32) << 24;
         t_vec[freq[s as usize] as usize] |= i as u32;
         freq[s as usize] += 1
     }
 
-    // Transform the data
-    // First put they last element into the end, thus avoiding a test on each iteration
-    let mut rle1_data = vec![0_u8; end];
-    let mut el = t_vec[key as usize];
-    key = el & 0xFFFFFF;
-    rle1_data[end - 1] = (el >> 24) as u8;
+    /*
+    The element at the key holds the last byte of the block. The chain is a cycle, so starting from the element it
+    points to gives the bytes in order, ending with the last byte.
+
+    RLE1: after four identical bytes, the next byte is a count of how many more copies follow. The count byte itself
+    is not data, and the byte after it starts a new sequence.
+    */
+    out.reserve((end + end / 4).min(max_out));
+    let mut crc = !0_u32;
+    let mut prev = u32::MAX;
+    let mut run = 0;
+    let mut idx = t_vec[key as usize] & 0xFFFFFF;
+    for _ in 0..end {
+        let el = t_vec[idx as usize];
+        idx = el & 0xFFFFFF;
+        let byte = (el >> 24) as u8;
 
-    for i in 1..t_vec.len() {
-        el = t_vec[key as usize];
-        key = el & 0xFFFFFF;
-        rle1_data[i - 1] = (el >> 24) as u8;
+        if run == 4 {
+            // This is a count byte. Runs are where a block grows, so the output is only checked here and at the end.
+            if out.len() + byte as usize > max_out {
+                return None;
+            }
+            for _ in 0..byte {
+                crc = crc_byte(crc, prev as u8);
+            }
+            out.resize(out.len() + byte as usize, prev as u8);
+            prev = u32::MAX;
+            run = 0;
+            continue;
+        }
+        if byte as u32 == prev {
+            run += 1;
+        } else {
+            prev = byte as u32;
+            run = 1;
+        }
+        crc = crc_byte(crc, byte);
+        out.push(byte);
     }
-    rle1_data
+    (out.len() <= max_out).then_some(!crc)
 }
 
+#[cfg(test)]
+mod test {
+    use super::{bwt_rle1_decode, estimate_native_work, native_encode, par_sort, sort};
+    use crate::bwt_algorithms::julian_sort::julian_sort;
+    use crate::tools::crc::do_crc;
+    use crate::tools::rle1::RLE1Block;
+
+    #[test]
+    fn fused_decode_test() {
+        // Runs of every length, including one that ends the block, so the last byte of the block is a count byte
+        let mut data = vec![];
+        for len in 1..300 {
+            data.extend(std::iter::repeat_n(b"xyz"[len % 3], len));
+        }
+        let (crc, block, _) = RLE1Block::new(&data[..], 100_000).next().unwrap().unwrap();
+        assert_eq!(block[block.len() - 5..block.len() - 1], [data[data.len() - 1]; 4]);
+
+        let (key, bwt) = native_encode(&block, 30);
+        let mut freq = [0_u32; 256];
+        bwt.iter().for_each(|&b| freq[b as usize] += 1);
+        let (mut t_vec, mut out) = (vec![], vec![]);
+        assert_eq!(bwt_rle1_decode(key, &bwt, &freq, &mut t_vec, &mut out, usize::MAX), Some(crc));
+        assert_eq!(out, data);
+        // One byte short of the data is refused
+        out.clear();
+        assert_eq!(bwt_rle1_decode(key, &bwt, &freq, &mut t_vec, &mut out, data.len() - 1), None);
+        assert_eq!(do_crc(0, &data), crc);
+    }
+
+    #[test]
+    fn over_budget_test() {
+        // A long repeated phrase makes every comparison run deep into the block, exhausting the budget.
+        let mut data = b"she sells sea shells by the sea shore. ".repeat(2_000);
+        data.extend_from_slice(b"the shells she sells are sea shells for sure.");
+        assert_eq!(native_encode(&data, 1), julian_sort(&data, 30));
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut index = (0..data.len() as u32).collect::<Vec<u32>>();
+        assert!(!sort(&mut index, &data, data.len()));
+        assert!(!pool.install(|| par_sort(&mut index, &data, data.len())));
+    }
+
+    #[test]
+    fn sort_test() {
+        // The sorts on one thread and on several must both match the naive order of the rotations, and both must
+        // stop when the budget runs out
+        let mut seed = 7_u32;
+        let data = (0..20_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                b"abcd"[(seed >> 16) as usize % 4]
+            })
+            .collect::<Vec<u8>>();
+        let rotation = |i: u32| data[i as usize..].iter().chain(&data[..i as usize]);
+        let mut expected = (0..data.len() as u32).collect::<Vec<u32>>();
+        expected.sort_by(|&a, &b| rotation(a).cmp(rotation(b)));
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for parallel in [false, true] {
+            let sort = |index: &mut [u32], budget| match parallel {
+                true => pool.install(|| par_sort(index, &data, budget)),
+                false => sort(index, &data, budget),
+            };
+            let mut index = (0..data.len() as u32).collect::<Vec<u32>>();
+            assert!(sort(&mut index, usize::MAX / 2));
+            assert_eq!(index, expected, "parallel {}", parallel);
+        }
+    }
+
+    /// A periodic block with a few bytes changed, like the inputs that made the old sort panic when it was stopped.
+    fn periodic_block() -> Vec<u8> {
+        let mut data = b"abcabdabcabcabdabcabcx".repeat(100);
+        for i in [500, 1_111, 1_800] {
+            data[i] = b'z';
+        }
+        data
+    }
+
+    /// Total chunks charged by a complete sort of data: the smallest budget it succeeds with.
+    fn chunks_needed(data: &[u8]) -> usize {
+        let sorts = |budget| sort(&mut (0..data.len() as u32).collect::<Vec<u32>>(), data, budget);
+        let mut high = data.len();
+        while !sorts(high) {
+            high *= 2;
+        }
+        let mut low = high / 2;
+        while low < high {
+            let mid = (low + high) / 2;
+            if sorts(mid) {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        low
+    }
+
+    #[test]
+    fn budget_cut_off_test() {
+        // Stopping the sort at any point must never panic, and a sort that finishes must be in order
+        let data = periodic_block();
+        let needed = chunks_needed(&data);
+        let mut expected = (0..data.len() as u32).collect::<Vec<u32>>();
+        assert!(sort(&mut expected, &data, needed));
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for step in 0..=20 {
+            let budget = needed * step / 16;
+            for parallel in [false, true] {
+                let mut index = (0..data.len() as u32).collect::<Vec<u32>>();
+                let sorted = match parallel {
+                    true => pool.install(|| par_sort(&mut index, &data, budget)),
+                    false => sort(&mut index, &data, budget),
+                };
+                assert_eq!(sorted, budget >= needed, "budget {} parallel {}", budget, parallel);
+                if sorted {
+                    assert_eq!(index, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn budget_per_sort_test() {
+        // The parallel sort makes the same comparisons as the sort on one thread. Its budget must not carry over from
+        // one sort to the next, so the exact budget is always enough and one chunk less never is.
+        let data = periodic_block();
+        let needed = chunks_needed(&data);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for _ in 0..10 {
+            for budget in [needed - 1, needed] {
+                let mut index = (0..data.len() as u32).collect::<Vec<u32>>();
+                assert_eq!(pool.install(|| par_sort(&mut index, &data, budget)), budget == needed);
+            }
+        }
+    }
+
+    #[test]
+    fn repetitive_tail_test() {
+        // Text followed by a long repetitive dump. Only looking at the start of the block would miss the dump.
+        let text = include_bytes!("../../LICENSE");
+        let mut data = text.to_vec();
+        let budget = data.len() * 30;
+        assert!(estimate_native_work(&data) < budget / 2);
+
+        data.extend_from_slice(&b"0123456789abcdef".repeat(20_000));
+        let budget = data.len() * 30;
+        assert!(estimate_native_work(&data) > budget / 2);
+    }
+}
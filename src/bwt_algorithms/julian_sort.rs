@@ -0,0 +1,763 @@
+//! A port of Julian Seward's block sorting algorithm (blocksort.c) from the C version of BZIP2.
+//!
+//! The native sort in bwt_sort.rs and the SA-IS sort both produce a correct BWT, but when a block contains identical
+//! rotations (for example, a block of a single repeated byte), the order of those rotations is arbitrary and each
+//! algorithm picks a different one. The key (origPtr) written to the stream then differs from the C version. This port
+//! reproduces the C ordering exactly and is used when byte-for-byte reference output is requested.
+//!
+//! Like the C version, it has two parts:
+//! - main_sort: a radix sort on the first two bytes followed by a three way quicksort of each bucket. Sorted buckets
+//!   are used to synthesise the order of other buckets, and the "quadrant" array caches partial sort results.
+//! - fallback_sort: an exponential radix sort (Manber-Myers style) used for small blocks and for repetitive blocks
+//!   where main_sort runs over its work budget.
+//!
+//! The code intentionally follows the structure and naming of the C version so the two can be compared side by side.
+//!
+use log::info;
+
+// Radix depth, quicksort depth and shell sort depth. The overshoot area must cover comparisons to all these depths.
+const N_RADIX: i32 = 2;
+const N_QSORT: i32 = 12;
+const N_SHELL: i32 = 18;
+const N_OVERSHOOT: usize = (N_RADIX + N_QSORT + N_SHELL + 2) as usize;
+
+const MAIN_QSORT_SMALL_THRESH: i32 = 20;
+const MAIN_QSORT_DEPTH_THRESH: i32 = N_RADIX + N_QSORT;
+const MAIN_QSORT_STACK_SIZE: usize = 100;
+
+const FALLBACK_QSORT_SMALL_THRESH: i32 = 10;
+const FALLBACK_QSORT_STACK_SIZE: usize = 100;
+
+const SETMASK: u32 = 1 << 21;
+const CLEARMASK: u32 = !SETMASK;
+
+/// Shell sort increments used by main_simple_sort.
+const INCS: [i32; 14] = [
+    1, 4, 13, 40, 121, 364, 1093, 3280, 9841, 29524, 88573, 265720, 797161, 2391484,
+];
+
+/// Encode data using the Burrows-Wheeler-Transform with the C version's sorting algorithm. Requires a u8 slice of data
+/// to be sorted and the work factor (1-100, default 30) that decides when to switch to the fallback sort.
+/// This returns a u32 key and a u8 vec of the BWT data.
+pub fn julian_sort(rle1_data: &[u8], work_factor: usize) -> (u32, Vec<u8>) {
+    let nblock = rle1_data.len();
+    let mut ptr = vec![0_u32; nblock];
+
+    if nblock < 10_000 {
+        fallback_sort(&mut ptr, rle1_data);
+    } else {
+        // The block needs an overshoot area so that comparisons can run past the end without wrapping.
+        let mut block = Vec::with_capacity(nblock + N_OVERSHOOT);
+        block.extend_from_slice(rle1_data);
+        block.resize(nblock + N_OVERSHOOT, 0);
+        let mut quadrant = vec![0_u16; nblock + N_OVERSHOOT];
+
+        // (wfact-1) / 3 puts the default-factor-30 transition point at the same place as the C version.
+        let wfact = work_factor.clamp(1, 100) as i32;
+        let mut budget = nblock as i32 * ((wfact - 1) / 3);
+
+        main_sort(&mut ptr, &mut block, &mut quadrant, nblock as i32, &mut budget);
+        if budget < 0 {
+            info!("Too repetitive; using fallback sorting algorithm.");
+            fallback_sort(&mut ptr, rle1_data);
+        }
+    }
+
+    // Get key and BWT output
+    let mut key = 0_u32;
+    let mut bwt = vec![0; nblock];
+    for (i, &p) in ptr.iter().enumerate() {
+        if p == 0 {
+            key = i as u32;
+            bwt[i] = rle1_data[nblock - 1];
+        } else {
+            bwt[i] = rle1_data[p as usize - 1];
+        }
+    }
+    (key, bwt)
+}
+
+/*--------------------------------------------------------------------------------------------------------------------
+Fallback O(N log(N)^2) sorting algorithm, for repetitive blocks.
+--------------------------------------------------------------------------------------------------------------------*/
+
+/// Insertion sort of fmap[lo..=hi] by equivalence class.
+fn fallback_simple_sort(fmap: &mut [u32], eclass: &[u32], lo: i32, hi: i32) {
+    if lo == hi {
+        return;
+    }
+
+    if hi - lo > 3 {
+        let mut i = hi - 4;
+        while i >= lo {
+            let tmp = fmap[i as usize];
+            let ec_tmp = eclass[tmp as usize];
+            let mut j = i + 4;
+            while j <= hi && ec_tmp > eclass[fmap[j as usize] as usize] {
+                fmap[(j - 4) as usize] = fmap[j as usize];
+                j += 4;
+            }
+            fmap[(j - 4) as usize] = tmp;
+            i -= 1;
+        }
+    }
+
+    let mut i = hi - 1;
+    while i >= lo {
+        let tmp = fmap[i as usize];
+        let ec_tmp = eclass[tmp as usize];
+        let mut j = i + 1;
+        while j <= hi && ec_tmp > eclass[fmap[j as usize] as usize] {
+            fmap[(j - 1) as usize] = fmap[j as usize];
+            j += 1;
+        }
+        fmap[(j - 1) as usize] = tmp;
+        i -= 1;
+    }
+}
+
+/// Swap n elements starting at p1 and p2.
+fn vswap(fmap: &mut [u32], mut p1: i32, mut p2: i32, mut n: i32) {
+    while n > 0 {
+        fmap.swap(p1 as usize, p2 as usize);
+        p1 += 1;
+        p2 += 1;
+        n -= 1;
+    }
+}
+
+/// Three way quicksort of fmap[lo_st..=hi_st] by equivalence class.
+fn fallback_qsort3(fmap: &mut [u32], eclass: &[u32], lo_st: i32, hi_st: i32) {
+    let mut stack = Vec::with_capacity(FALLBACK_QSORT_STACK_SIZE);
+    // Pseudo random number for choosing the pivot, exactly as the C version does.
+    let mut r = 0_u32;
+
+    stack.push((lo_st, hi_st));
+
+    while let Some((lo, hi)) = stack.pop() {
+        if hi - lo < FALLBACK_QSORT_SMALL_THRESH {
+            fallback_simple_sort(fmap, eclass, lo, hi);
+            continue;
+        }
+
+        // Random partitioning. The magic constants come from Sedgewick's algorithms book, chapter 35.
+        r = ((r * 7621) + 1) % 32768;
+        let med = match r % 3 {
+            0 => eclass[fmap[lo as usize] as usize],
+            1 => eclass[fmap[((lo + hi) >> 1) as usize] as usize],
+            _ => eclass[fmap[hi as usize] as usize],
+        } as i64;
+
+        let (mut un_lo, mut lt_lo) = (lo, lo);
+        let (mut un_hi, mut gt_hi) = (hi, hi);
+
+        loop {
+            while un_lo <= un_hi {
+                let n = eclass[fmap[un_lo as usize] as usize] as i64 - med;
+                if n == 0 {
+                    fmap.swap(un_lo as usize, lt_lo as usize);
+                    lt_lo += 1;
+                    un_lo += 1;
+                    continue;
+                }
+                if n > 0 {
+                    break;
+                }
+                un_lo += 1;
+            }
+            while un_lo <= un_hi {
+                let n = eclass[fmap[un_hi as usize] as usize] as i64 - med;
+                if n == 0 {
+                    fmap.swap(un_hi as usize, gt_hi as usize);
+                    gt_hi -= 1;
+                    un_hi -= 1;
+                    continue;
+                }
+                if n < 0 {
+                    break;
+                }
+                un_hi -= 1;
+            }
+            if un_lo > un_hi {
+                break;
+            }
+            fmap.swap(un_lo as usize, un_hi as usize);
+            un_lo += 1;
+            un_hi -= 1;
+        }
+
+        if gt_hi < lt_lo {
+            continue;
+        }
+
+        let n = (lt_lo - lo).min(un_lo - lt_lo);
+        vswap(fmap, lo, un_lo - n, n);
+        let m = (hi - gt_hi).min(gt_hi - un_hi);
+        vswap(fmap, un_lo, hi - m + 1, m);
+
+        let n = lo + un_lo - lt_lo - 1;
+        let m = hi - (gt_hi - un_hi) + 1;
+
+        if n - lo > hi - m {
+            stack.push((lo, n));
+            stack.push((m, hi));
+        } else {
+            stack.push((m, hi));
+            stack.push((lo, n));
+        }
+    }
+}
+
+/// Bucket header bits used by fallback_sort.
+struct BucketHeads(Vec<u32>);
+impl BucketHeads {
+    #[inline(always)]
+    fn set(&mut self, zz: i32) {
+        self.0[(zz >> 5) as usize] |= 1 << (zz & 31);
+    }
+    #[inline(always)]
+    fn clear(&mut self, zz: i32) {
+        self.0[(zz >> 5) as usize] &= !(1 << (zz & 31));
+    }
+    #[inline(always)]
+    fn is_set(&self, zz: i32) -> bool {
+        self.0[(zz >> 5) as usize] & (1 << (zz & 31)) != 0
+    }
+    #[inline(always)]
+    fn word(&self, zz: i32) -> u32 {
+        self.0[(zz >> 5) as usize]
+    }
+}
+
+/// Exponential radix sort of the block into fmap.
+fn fallback_sort(fmap: &mut [u32], block: &[u8]) {
+    let nblock = block.len() as i32;
+    let mut ftab = [0_i32; 257];
+    let mut eclass = vec![0_u32; block.len()];
+
+    // Initial 1-char radix sort to generate initial fmap and initial bucket header bits.
+    for &b in block {
+        ftab[b as usize] += 1;
+    }
+    for i in 1..257 {
+        ftab[i] += ftab[i - 1];
+    }
+    for (i, &b) in block.iter().enumerate() {
+        let k = ftab[b as usize] - 1;
+        ftab[b as usize] = k;
+        fmap[k as usize] = i as u32;
+    }
+
+    // Allow room for the sentinel bits set below.
+    let mut bhtab = BucketHeads(vec![0_u32; 2 + (block.len() / 32) + 2]);
+    for &f in ftab.iter().take(256) {
+        bhtab.set(f);
+    }
+
+    // Inductively refine the buckets. Set sentinel bits for block-end detection first.
+    for i in 0..32 {
+        bhtab.set(nblock + 2 * i);
+        bhtab.clear(nblock + 2 * i + 1);
+    }
+
+    // The log(N) loop
+    let mut h = 1;
+    loop {
+        let mut j = 0;
+        for i in 0..nblock {
+            if bhtab.is_set(i) {
+                j = i;
+            }
+            let mut k = fmap[i as usize] as i32 - h;
+            if k < 0 {
+                k += nblock;
+            }
+            eclass[k as usize] = j as u32;
+        }
+
+        let mut n_not_done = 0;
+        let mut r = -1;
+        loop {
+            // Find the next non-singleton bucket
+            let mut k = r + 1;
+            while bhtab.is_set(k) && (k & 0x1f) != 0 {
+                k += 1;
+            }
+            if bhtab.is_set(k) {
+                while bhtab.word(k) == 0xffff_ffff {
+                    k += 32;
+                }
+                while bhtab.is_set(k) {
+                    k += 1;
+                }
+            }
+            let l = k - 1;
+            if l >= nblock {
+                break;
+            }
+            while !bhtab.is_set(k) && (k & 0x1f) != 0 {
+                k += 1;
+            }
+            if !bhtab.is_set(k) {
+                while bhtab.word(k) == 0 {
+                    k += 32;
+                }
+                while !bhtab.is_set(k) {
+                    k += 1;
+                }
+            }
+            r = k - 1;
+            if r >= nblock {
+                break;
+            }
+
+            // Now [l, r] bracket the current bucket
+            if r > l {
+                n_not_done += r - l + 1;
+                fallback_qsort3(fmap, &eclass, l, r);
+
+                // Scan the bucket and generate header bits
+                let mut cc = -1_i64;
+                for i in l..=r {
+                    let cc1 = eclass[fmap[i as usize] as usize] as i64;
+                    if cc != cc1 {
+                        bhtab.set(i);
+                        cc = cc1;
+                    }
+                }
+            }
+        }
+
+        h *= 2;
+        if h > nblock || n_not_done == 0 {
+            break;
+        }
+    }
+}
+
+/*--------------------------------------------------------------------------------------------------------------------
+The main, O(N^2 log(N)) sorting algorithm. Faster for "normal" non-repetitive blocks.
+--------------------------------------------------------------------------------------------------------------------*/
+
+/// Returns true if the rotation starting at i1 sorts after the rotation starting at i2.
+#[inline(always)]
+fn main_gt_u(
+    mut i1: u32,
+    mut i2: u32,
+    block: &[u8],
+    quadrant: &[u16],
+    nblock: u32,
+    budget: &mut i32,
+) -> bool {
+    // The first 12 bytes are compared without the quadrant.
+    for _ in 0..12 {
+        let (c1, c2) = (block[i1 as usize], block[i2 as usize]);
+        if c1 != c2 {
+            return c1 > c2;
+        }
+        i1 += 1;
+        i2 += 1;
+    }
+
+    let mut k = nblock as i32 + 8;
+    loop {
+        for _ in 0..8 {
+            let (c1, c2) = (block[i1 as usize], block[i2 as usize]);
+            if c1 != c2 {
+                return c1 > c2;
+            }
+            let (s1, s2) = (quadrant[i1 as usize], quadrant[i2 as usize]);
+            if s1 != s2 {
+                return s1 > s2;
+            }
+            i1 += 1;
+            i2 += 1;
+        }
+
+        if i1 >= nblock {
+            i1 -= nblock;
+        }
+        if i2 >= nblock {
+            i2 -= nblock;
+        }
+
+        k -= 8;
+        *budget -= 1;
+        if k < 0 {
+            return false;
+        }
+    }
+}
+
+/// Shell sort of ptr[lo..=hi], comparing from depth d.
+#[allow(clippy::too_many_arguments)]
+fn main_simple_sort(
+    ptr: &mut [u32],
+    block: &[u8],
+    quadrant: &[u16],
+    nblock: i32,
+    lo: i32,
+    hi: i32,
+    d: i32,
+    budget: &mut i32,
+) {
+    let big_n = hi - lo + 1;
+    if big_n < 2 {
+        return;
+    }
+
+    let mut hp = 0;
+    while INCS[hp] < big_n {
+        hp += 1;
+    }
+
+    for &h in INCS[..hp].iter().rev() {
+        let mut i = lo + h;
+        loop {
+            // The C version unrolls this three times and checks the budget after each group of three.
+            for _ in 0..3 {
+                if i > hi {
+                    break;
+                }
+                let v = ptr[i as usize];
+                let mut j = i;
+                while main_gt_u(
+                    ptr[(j - h) as usize] + d as u32,
+                    v + d as u32,
+                    block,
+                    quadrant,
+                    nblock as u32,
+                    budget,
+                ) {
+                    ptr[j as usize] = ptr[(j - h) as usize];
+                    j -= h;
+                    if j <= (lo + h - 1) {
+                        break;
+                    }
+                }
+                ptr[j as usize] = v;
+                i += 1;
+            }
+            if i > hi {
+                break;
+            }
+            if *budget < 0 {
+                return;
+            }
+        }
+    }
+}
+
+/// Median of three.
+#[inline(always)]
+fn mmed3(mut a: u8, mut b: u8, c: u8) -> u8 {
+    if a > b {
+        std::mem::swap(&mut a, &mut b);
+    }
+    if b > c {
+        b = c;
+        if a > b {
+            b = a;
+        }
+    }
+    b
+}
+
+/// Three way quicksort of ptr[lo_st..=hi_st], comparing from depth d_st.
+#[allow(clippy::too_many_arguments)]
+fn main_qsort3(
+    ptr: &mut [u32],
+    block: &[u8],
+    quadrant: &[u16],
+    nblock: i32,
+    lo_st: i32,
+    hi_st: i32,
+    d_st: i32,
+    budget: &mut i32,
+) {
+    let mut stack = Vec::with_capacity(MAIN_QSORT_STACK_SIZE);
+    stack.push((lo_st, hi_st, d_st));
+
+    let byte_at = |ptr: &[u32], i: i32, d: i32| block[(ptr[i as usize] as i32 + d) as usize] as i32;
+
+    while let Some((lo, hi, d)) = stack.pop() {
+        if hi - lo < MAIN_QSORT_SMALL_THRESH || d > MAIN_QSORT_DEPTH_THRESH {
+            main_simple_sort(ptr, block, quadrant, nblock, lo, hi, d, budget);
+            if *budget < 0 {
+                return;
+            }
+            continue;
+        }
+
+        let med = mmed3(
+            byte_at(ptr, lo, d) as u8,
+            byte_at(ptr, hi, d) as u8,
+            byte_at(ptr, (lo + hi) >> 1, d) as u8,
+        ) as i32;
+
+        let (mut un_lo, mut lt_lo) = (lo, lo);
+        let (mut un_hi, mut gt_hi) = (hi, hi);
+
+        loop {
+            while un_lo <= un_hi {
+                let n = byte_at(ptr, un_lo, d) - med;
+                if n == 0 {
+                    ptr.swap(un_lo as usize, lt_lo as usize);
+                    lt_lo += 1;
+                    un_lo += 1;
+                    continue;
+                }
+                if n > 0 {
+                    break;
+                }
+                un_lo += 1;
+            }
+            while un_lo <= un_hi {
+                let n = byte_at(ptr, un_hi, d) - med;
+                if n == 0 {
+                    ptr.swap(un_hi as usize, gt_hi as usize);
+                    gt_hi -= 1;
+                    un_hi -= 1;
+                    continue;
+                }
+                if n < 0 {
+                    break;
+                }
+                un_hi -= 1;
+            }
+            if un_lo > un_hi {
+                break;
+            }
+            ptr.swap(un_lo as usize, un_hi as usize);
+            un_lo += 1;
+            un_hi -= 1;
+        }
+
+        if gt_hi < lt_lo {
+            stack.push((lo, hi, d + 1));
+            continue;
+        }
+
+        let n = (lt_lo - lo).min(un_lo - lt_lo);
+        vswap(ptr, lo, un_lo - n, n);
+        let m = (hi - gt_hi).min(gt_hi - un_hi);
+        vswap(ptr, un_lo, hi - m + 1, m);
+
+        let n = lo + un_lo - lt_lo - 1;
+        let m = hi - (gt_hi - un_hi) + 1;
+
+        // Push the largest partition first so the smallest is sorted next.
+        let mut next = [(lo, n, d), (m, hi, d), (n + 1, m - 1, d + 1)];
+        let size = |p: (i32, i32, i32)| p.1 - p.0;
+        if size(next[0]) < size(next[1]) {
+            next.swap(0, 1);
+        }
+        if size(next[1]) < size(next[2]) {
+            next.swap(1, 2);
+        }
+        if size(next[0]) < size(next[1]) {
+            next.swap(0, 1);
+        }
+        stack.extend_from_slice(&next);
+    }
+}
+
+/// Radix sort on the first two bytes, then sort each bucket, using each sorted bucket to order the others.
+fn main_sort(
+    ptr: &mut [u32],
+    block: &mut [u8],
+    quadrant: &mut [u16],
+    nblock: i32,
+    budget: &mut i32,
+) {
+    let n = nblock as usize;
+    let mut ftab = vec![0_u32; 65537];
+    let mut running_order = [0_i32; 256];
+    let mut big_done = [false; 256];
+    let mut copy_start = [0_i32; 256];
+    let mut copy_end = [0_i32; 256];
+
+    // Set up the 2-byte frequency table
+    let mut j = (block[0] as usize) << 8;
+    for i in (0..n).rev() {
+        quadrant[i] = 0;
+        j = (j >> 8) | ((block[i] as usize) << 8);
+        ftab[j] += 1;
+    }
+
+    // Copy the start of the block into the overshoot area
+    for i in 0..N_OVERSHOOT {
+        block[n + i] = block[i];
+        quadrant[n + i] = 0;
+    }
+
+    // Complete the initial radix sort
+    for i in 1..=65536 {
+        ftab[i] += ftab[i - 1];
+    }
+
+    let mut s = (block[0] as usize) << 8;
+    for i in (0..n).rev() {
+        s = (s >> 8) | ((block[i] as usize) << 8);
+        let j = ftab[s] - 1;
+        ftab[s] = j;
+        ptr[j as usize] = i as u32;
+    }
+
+    // Now ftab contains the first loc of every small bucket. Calculate the running order, from smallest to
+    // largest big bucket.
+    let big_freq = |ftab: &[u32], b: i32| ftab[((b + 1) << 8) as usize] - ftab[(b << 8) as usize];
+    for (i, r) in running_order.iter_mut().enumerate() {
+        *r = i as i32;
+    }
+    {
+        let mut h = 1;
+        while h <= 256 {
+            h = 3 * h + 1;
+        }
+        while h != 1 {
+            h /= 3;
+            for i in h..=255 {
+                let vv = running_order[i];
+                let mut j = i;
+                while big_freq(&ftab, running_order[j - h]) > big_freq(&ftab, vv) {
+                    running_order[j] = running_order[j - h];
+                    j -= h;
+                    if j < h {
+                        break;
+                    }
+                }
+                running_order[j] = vv;
+            }
+        }
+    }
+
+    // The main sorting loop
+    for (i, &ss) in running_order.iter().enumerate() {
+        // Process big buckets, starting with the least full.
+        let ss = ss as usize;
+
+        // Step 1: Complete the big bucket [ss] by quicksorting any unsorted small buckets [ss, j], for j != ss.
+        for j in 0..=255 {
+            if j != ss {
+                let sb = (ss << 8) + j;
+                if ftab[sb] & SETMASK == 0 {
+                    let lo = (ftab[sb] & CLEARMASK) as i32;
+                    let hi = (ftab[sb + 1] & CLEARMASK) as i32 - 1;
+                    if hi > lo {
+                        main_qsort3(ptr, block, quadrant, nblock, lo, hi, N_RADIX, budget);
+                        if *budget < 0 {
+                            return;
+                        }
+                    }
+                }
+                ftab[sb] |= SETMASK;
+            }
+        }
+
+        // Step 2: Scan this big bucket [ss] to synthesise the sorted order for small buckets [t, ss] for all t,
+        // including, magically, the bucket [ss, ss] too.
+        for j in 0..=255 {
+            copy_start[j] = (ftab[(j << 8) + ss] & CLEARMASK) as i32;
+            copy_end[j] = (ftab[(j << 8) + ss + 1] & CLEARMASK) as i32 - 1;
+        }
+        let mut j = (ftab[ss << 8] & CLEARMASK) as i32;
+        while j < copy_start[ss] {
+            let mut k = ptr[j as usize] as i32 - 1;
+            if k < 0 {
+                k += nblock;
+            }
+            let c1 = block[k as usize] as usize;
+            if !big_done[c1] {
+                ptr[copy_start[c1] as usize] = k as u32;
+                copy_start[c1] += 1;
+            }
+            j += 1;
+        }
+        let mut j = (ftab[(ss + 1) << 8] & CLEARMASK) as i32 - 1;
+        while j > copy_end[ss] {
+            let mut k = ptr[j as usize] as i32 - 1;
+            if k < 0 {
+                k += nblock;
+            }
+            let c1 = block[k as usize] as usize;
+            if !big_done[c1] {
+                ptr[copy_end[c1] as usize] = k as u32;
+                copy_end[c1] -= 1;
+            }
+            j -= 1;
+        }
+
+        for j in 0..=255 {
+            ftab[(j << 8) + ss] |= SETMASK;
+        }
+
+        // Step 3: The [ss] big bucket is now done. Record this fact, and update the quadrant descriptors,
+        // including the overshoot area. Updating for the last bucket is pointless.
+        big_done[ss] = true;
+
+        if i < 255 {
+            let bb_start = (ftab[ss << 8] & CLEARMASK) as usize;
+            let bb_size = (ftab[(ss + 1) << 8] & CLEARMASK) as usize - bb_start;
+            let mut shifts = 0;
+            while (bb_size >> shifts) > 65534 {
+                shifts += 1;
+            }
+
+            for j in (0..bb_size).rev() {
+                let a2update = ptr[bb_start + j] as usize;
+                let q_val = (j >> shifts) as u16;
+                quadrant[a2update] = q_val;
+                if a2update < N_OVERSHOOT {
+                    quadrant[a2update + n] = q_val;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::julian_sort;
+    use crate::bwt_algorithms::bwt_sort::bwt_encode;
+
+    /// Pseudo random test data with a limited alphabet so buckets have some depth.
+    fn lcg_data(len: usize, alphabet: u8) -> Vec<u8> {
+        let mut seed = 12345_u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                b'a' + ((seed >> 16) as u8 % alphabet)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_block_matches_native_test() {
+        // Small blocks use the fallback sort
+        let data = lcg_data(5_000, 4);
+        assert_eq!(julian_sort(&data, 30), bwt_encode(&data, 30));
+    }
+
+    #[test]
+    fn large_block_matches_native_test() {
+        // Large blocks use the main sort
+        let data = lcg_data(50_000, 3);
+        assert_eq!(julian_sort(&data, 30), bwt_encode(&data, 30));
+    }
+
+    #[test]
+    fn repetitive_block_test() {
+        // Periodic data runs the main sort over budget. The keys were taken from the C version's output.
+        for (data, c_key) in [
+            (b"abc".repeat(7_000), 6_999),
+            (b"ab".repeat(10_000), 9_999),
+            (b"abcdefg".repeat(2_000), 1_999),
+        ] {
+            let (key, bwt) = julian_sort(&data, 30);
+            assert_eq!(key, c_key);
+            // The work factor does not change the result
+            assert_eq!(julian_sort(&data, 1), (key, bwt));
+        }
+    }
+}
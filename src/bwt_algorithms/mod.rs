@@ -5,9 +5,15 @@
 //! 
 //! The Burrow-Wheeler Transform requires "computationally expensive" sorting. Since different sorting algorithms are better
 //! suited for different kinds of data, this module contains multiple sorting algorithms. (Currently two are employed, but numerous
-//! alternatives were tested.)
+//! alternatives were tested.) A port of the C version's sorting algorithm is also included for byte-for-byte reference output.
+//! 
+//! The backend module lets the caller choose the algorithm. The suffix_array module offers the SA-IS and BWT code as a
+//! general purpose public API.
 //! 
 //! Of all the phases involved in BZIP2, this phase has the greatest impact on compression speed.
 //! 
+pub mod backend;
 pub mod bwt_sort;
+pub mod julian_sort;
 pub mod sais_fallback;
+pub mod suffix_array;
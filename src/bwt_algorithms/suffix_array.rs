@@ -0,0 +1,155 @@
+//! A stable public API for suffix arrays and the Burrows-Wheeler-Transform, for use outside of BZIP2.
+//!
+//! The functions in bwt_sort.rs are tied to the needs of the compressor (work budgets, the choice of algorithm and
+//! the 24 bit key of the BZIP2 stream). The functions here are built on the same SA-IS and Duval rotation code, but
+//! have no such limits:
+//! - suffix_array: the suffix array of a byte slice.
+//! - sa_is: the suffix array of a slice of any unsigned integer type, with a caller supplied alphabet size.
+//! - bwt: the rotation based Burrows-Wheeler-Transform used by BZIP2, returning the primary index.
+//! - inverse_bwt: the inverse of bwt.
+//!
+//! All four run in O(n) time. The suffix array functions need the 4n byte result plus n/4 bytes of working memory
+//! (plus the buckets, 8 bytes per alphabet symbol). bwt needs about 5.5n bytes, and inverse_bwt needs 5n bytes.
+//!
+use super::sais_fallback;
+
+/// An unsigned integer symbol that sa_is can sort. Implemented for u8, u16, u32 and usize.
+pub trait Symbol: Copy + Sync {
+    /// The symbol as an index into the alphabet.
+    fn index(self) -> usize;
+}
+
+impl Symbol for u8 {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+impl Symbol for u16 {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+impl Symbol for u32 {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+impl Symbol for usize {
+    fn index(self) -> usize {
+        self
+    }
+}
+
+/// Return the suffix array of data: the start positions of all suffixes in sorted order. A shorter suffix sorts
+/// before a longer suffix it is a prefix of. O(n) time.
+pub fn suffix_array(data: &[u8]) -> Vec<u32> {
+    sa_is(data, 256)
+}
+
+/// Return the suffix array of data over an alphabet of alphabet_size symbols (0..alphabet_size). O(n) time.
+///
+/// Panics if data has 2^32 or more elements, or if a symbol is not less than alphabet_size.
+pub fn sa_is<T: Symbol>(data: &[T], alphabet_size: usize) -> Vec<u32> {
+    assert!(data.len() < u32::MAX as usize, "Data is too long for a u32 suffix array");
+    assert!(
+        data.iter().all(|s| s.index() < alphabet_size),
+        "Data contains a symbol outside the alphabet"
+    );
+    let mut sa = vec![0_u32; data.len()];
+    sais_fallback::sa_is(data, &mut sa, alphabet_size, false);
+    sa
+}
+
+/// Return the Burrows-Wheeler-Transform of data, sorting all rotations of the data as BZIP2 does. The result is
+/// the primary index (the row of the sorted rotations that holds the original data) and the last column of the
+/// sorted rotations. O(n) time.
+pub fn bwt(data: &[u8]) -> (u32, Vec<u8>) {
+    sais_fallback::sais_entry(data)
+}
+
+/// Invert the Burrows-Wheeler-Transform produced by bwt, given its primary index. O(n) time.
+///
+/// Panics if primary is not a valid row of a non-empty transform.
+pub fn inverse_bwt(primary: u32, bwt: &[u8]) -> Vec<u8> {
+    if bwt.is_empty() {
+        return vec![];
+    }
+    assert!((primary as usize) < bwt.len(), "Primary index is out of range");
+
+    // The first column of the sorted rotations is the sorted last column. Find where each symbol starts in it.
+    let mut starts = [0_u32; 256];
+    bwt.iter().for_each(|&b| starts[b as usize] += 1);
+    let mut total = 0;
+    for start in starts.iter_mut() {
+        let count = *start;
+        *start = total;
+        total += count;
+    }
+
+    // Link each row to the row of the following rotation
+    let mut next = vec![0_u32; bwt.len()];
+    for (i, &b) in bwt.iter().enumerate() {
+        next[starts[b as usize] as usize] = i as u32;
+        starts[b as usize] += 1;
+    }
+
+    // Follow the links from the primary row. Each row's first byte is the last column byte of the linked row.
+    let mut data = Vec::with_capacity(bwt.len());
+    let mut row = next[primary as usize];
+    for _ in 0..bwt.len() {
+        data.push(bwt[row as usize]);
+        row = next[row as usize];
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bwt, inverse_bwt, sa_is, suffix_array};
+
+    fn lcg_data(len: usize, alphabet: u32) -> Vec<u32> {
+        let mut seed = 99_u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) % alphabet
+            })
+            .collect()
+    }
+
+    #[test]
+    fn suffix_array_test() {
+        let data = lcg_data(5_000, 3).iter().map(|&s| s as u8).collect::<Vec<u8>>();
+        let mut expected = (0..data.len() as u32).collect::<Vec<u32>>();
+        expected.sort_by(|&a, &b| data[a as usize..].cmp(&data[b as usize..]));
+        assert_eq!(suffix_array(&data), expected);
+        assert_eq!(suffix_array(b"banana"), vec![5, 3, 1, 0, 4, 2]);
+        assert!(suffix_array(b"").is_empty());
+    }
+
+    #[test]
+    fn large_alphabet_test() {
+        let data = lcg_data(5_000, 1_000);
+        let mut expected = (0..data.len() as u32).collect::<Vec<u32>>();
+        expected.sort_by(|&a, &b| data[a as usize..].cmp(&data[b as usize..]));
+        assert_eq!(sa_is(&data, 1_000), expected);
+        let data16 = data.iter().map(|&s| s as u16).collect::<Vec<u16>>();
+        assert_eq!(sa_is(&data16, 1_000), expected);
+    }
+
+    #[test]
+    fn bwt_round_trip_test() {
+        for data in [
+            b"banana".to_vec(),
+            b"abcabcabc".to_vec(),
+            vec![7; 1_000],
+            b"x".to_vec(),
+            vec![],
+            lcg_data(20_000, 4).iter().map(|&s| s as u8).collect(),
+        ] {
+            let (primary, transformed) = bwt(&data);
+            assert_eq!(inverse_bwt(primary, &transformed), data);
+        }
+        assert_eq!(bwt(b"banana"), (3, b"nnbaaa".to_vec()));
+    }
+}
@@ -1,8 +1,9 @@
 //! This manages the compression process for BZIP2 encoding.
 //!
 //! The compression process is multi-threaded. A thread is spawned first to receive the blocks of 
-//! data that are compressed by the block compression routine. Then the input is broken into blocks and a thread 
-//! is spawned to compress that block.
+//! data that are compressed by the block compression routine. Then the input is broken into blocks a batch at a
+//! time (on several threads, see ParallelRle1), and the blocks of each batch are compressed in parallel while the
+//! next batch is being broken into blocks.
 //! 
 //! When a block is compressed, the compressed data along with a sequence number is passed to the aggregator/receiver. 
 //! If the sequence number is the next block to be written out, the block is added to the output. If it arrived
@@ -10,6 +11,12 @@
 //! 
 //! Once all blocks are written, the stream footer is written and the process is completed.
 //! 
+//! Inputs small enough to be a single block skip all of this. They are read into memory and compressed on the
+//! calling thread with an EncoderContext.
+//! 
+//! Large regular files are mapped into memory (see ByteSource), so the blocks are split from the file data in place.
+//! Pipes and other sources are read a batch at a time.
+//! 
 //! NOTE 1: THE ROUTINES FOR FILE I/O ARE RUDEMENTARY, AND DO NOT PROPERLY RESOLVE ALL I/O ERRORS.
 //! 
 //! NOTE 2: BZIP2 should default to deleting the source file (if input comes from a file), and set the creation date
@@ -17,14 +24,17 @@
 //! 
 //! 
 use super::compress_block::compress_block;
-use crate::bitstream::bitwriter::BitWriter;
-use crate::tools::{cli::BzOpts, rle1::RLE1Block};
+use super::context::EncoderContext;
+use crate::bitstream::{
+    bitwriter::BitWriter,
+    byte_source::{open_input, ByteSource, FileSource},
+};
+use crate::tools::{cli::BzOpts, rle1::ParallelRle1};
 use rayon::prelude::*;
 use simplelog::info;
 use std::fs::File;
 use std::io;
 
-#[allow(clippy::unusual_byte_groupings)]
 /*
     This is repsonsible for creating the bitstream writer, a struct that
     contains the block data passes to the block compression routine,
@@ -46,57 +56,67 @@ pub fn compress(opts: &mut BzOpts) -> io::Result<()> {
       THE ROUTINES FOR FILE I/O ARE RUDEMENTARY, AND DO NOT PROPERLY RESOLVE ALL I/O ERRORS.
     */
 
-    // Prepare to read the data.
-    let fname = opts.files[0].clone();
-    let source_file = File::open(&fname)?;
-
-    // Initialize the RLE1 reader/iterator. This reads the input file and creates blocks of the
-    // proper size to then be compressed.
-    let block_size = (opts.block_size as usize * 100000) - 19;
-    let rle1_blocks = RLE1Block::new(source_file, block_size);
+    // Prepare to read the data. Large files are mapped into memory and split into blocks in place.
+    let (input, input_size) = open_input(&opts.files[0], opts.mmap)?;
 
     // Prepare to write the compressed data. 
     let mut fname = opts.files[0].clone();
     fname.push_str(".bz2");
 
+    match input {
+        FileSource::Mapped(source) => compress_source(source, input_size, opts, &fname),
+        FileSource::Read(source) => compress_source(source, input_size, opts, &fname),
+    }
+}
+
+/// Compress source into the file fname. input_size is the size of a regular file, or None when the size isn't known
+/// (pipes and other special files).
+fn compress_source<S: ByteSource + Send>(
+    mut source: S,
+    input_size: Option<usize>,
+    opts: &BzOpts,
+    fname: &str,
+) -> io::Result<()> {
+    let block_size = (opts.block_size * 100000) - 19;
+
+    // RLE1 grows data by at most 5/4, so an input this small is always a single block. There is nothing to run in
+    // parallel, so compress it on this thread and skip the BitWriter thread and the channel.
+    if fits_one_block(&mut source, input_size, block_size / 5 * 4)? {
+        info!("Compressing {} bytes as a single block.", source.data().len());
+        return compress_single_block(source, opts, fname);
+    }
+
+    // Initialize the RLE1 splitter. This takes the input a batch at a time and creates blocks of the
+    // proper size to then be compressed.
+    let mut rle1_blocks = ParallelRle1::from_source(source, block_size);
+
     /*
     This works by compressing each block in parallel. Depending on the sequence of when those blocks finish,
     this will hold compressed blocks in memory until it is their turn to be written.
     */
 
-    // Initialize thread channel communication. Sends block data, sequence number, and indicator whether
-    //  this is the last block
-    let (tx, rx) = std::sync::mpsc::channel();
+    // Initialize thread channel communication. Sends block data and sequence number. The channel closes once
+    // every block has been sent.
+    let (tx, rx) = std::sync::mpsc::channel::<((Vec<u8>, u8), usize)>();
     // Initialize a bitwriter.
-    let mut bw = BitWriter::new(&fname, opts.block_size as u8);
+    let mut bw = BitWriter::new(File::create(fname)?, opts.block_size as u8);
 
     // Spawn the BitWriter thread and wait for blocks to write.
-    let handle = std::thread::spawn(move || {
+    let handle = std::thread::spawn(move || -> io::Result<()> {
         // Set the current block (the block we are waiting to write) to 0.
         let mut current_block = 0;
         // Initialize a vec to hold out-of-sequence blocks we might receive
         let mut results = vec![];
 
-        'outer: loop {
-            info!(
-                "RX: Waiting for block {}. The queue contains {} blocks.",
-                current_block,
-                results.len(),
-            );
-
-            // Wait for a block to be sent to this thread.
-            let result: ((Vec<u8>, u8), usize, bool) = rx.recv().unwrap();
+        info!("RX: Waiting for block {}.", current_block);
+        // Wait for a block to be sent to this thread.
+        while let Ok(result) = rx.recv() {
             // If the block is the one we are waiting for, process it.
             if result.1 == current_block {
                 info!("RX: Found block {}. Writing it...", current_block,);
-                let data = &result.0 .0;
-                let padding = result.0 .1;
-                let last = result.2;
-                bw.add_block(last, data, padding).unwrap();
+                let (data, padding) = &result.0;
+                bw.add_block(data, *padding)?;
                 current_block += 1;
-                if last {
-                    break;
-                }
             } else {
                 info!(
                     "RX: Adding block {} to the queue. The queue will now contain {} blocks.",
@@ -108,29 +128,306 @@ pub fn compress(opts: &mut BzOpts) -> io::Result<()> {
             }
             while let Some(idx) = results.iter().position(|x| x.1 == current_block) {
                 info!("RX: Found block {}. Writing it...", current_block,);
-                let data = &results[idx].0 .0;
-                let last_bits = results[idx].0 .1;
-                let last = results[idx].2;
-                bw.add_block(last, data, last_bits).unwrap();
+                let (data, padding) = &results[idx].0;
+                bw.add_block(data, *padding)?;
                 results.swap_remove(idx);
                 current_block += 1;
-                if last {
-                    break 'outer;
-                }
             }
+            info!(
+                "RX: Waiting for block {}. The queue contains {} blocks.",
+                current_block,
+                results.len(),
+            );
         }
+        // All blocks are written (there may be none), so end the stream.
+        bw.finish()
     });
 
-    // Build the RLE1 blocks and compress them
-    rle1_blocks
-        .into_iter()
-        .enumerate()
-        .par_bridge()
-        .for_each_with(tx, |tx, (i, (crc, block, last_block))| {
-            let result = compress_block(&block, crc);
-            tx.send((result, i, last_block)).unwrap();
-        });
-    let joined =  handle.join();
+    // Build the RLE1 blocks a batch at a time, and compress each batch while the next one is built.
+    let split = (|| -> io::Result<()> {
+        // One context for each block of a batch, kept from batch to batch
+        let mut contexts: Vec<EncoderContext> = vec![];
+        let mut batch = rle1_blocks.next_batch()?;
+        let mut first_block = 0;
+        while !batch.is_empty() {
+            let count = batch.len();
+            if contexts.len() < count {
+                contexts.resize_with(count, EncoderContext::new);
+            }
+            let (sent, next_batch) = rayon::join(
+                || {
+                    batch
+                        .into_par_iter()
+                        .zip(contexts.par_iter_mut())
+                        .enumerate()
+                        .map(|(i, ((crc, block, _last_block), ctx))| {
+                            let padding = compress_block(&block, crc, opts, ctx);
+                            ((ctx.take_packed(), padding), first_block + i)
+                        })
+                        .try_for_each_with(tx.clone(), |tx, result| tx.send(result))
+                },
+                || rle1_blocks.next_batch(),
+            );
+            // Sending only fails if the BitWriter thread stopped on an error, which the join below returns. Stop
+            // here, but keep an error from splitting the next batch as well.
+            if sent.is_err() {
+                next_batch?;
+                break;
+            }
+            batch = next_batch?;
+            first_block += count;
+        }
+        Ok(())
+    })();
+    // Close the channel so the BitWriter thread ends the stream.
+    drop(tx);
+    let joined = handle.join().expect("The BitWriter thread panicked");
     info!("RX: Thread returned {:?}", joined);
-    Ok(())
+    // A BitWriter error comes first, since it stops the split early.
+    joined.and(split)
+}
+
+/// Return true if the input is at most max bytes. When the size isn't known, up to max + 1 bytes are read from the
+/// source to find out. They stay in the source, so nothing is lost whichever way the input is compressed.
+fn fits_one_block<S: ByteSource>(source: &mut S, input_size: Option<usize>, max: usize) -> io::Result<bool> {
+    match input_size {
+        Some(size) => Ok(size <= max),
+        None => {
+            source.fill(max + 1)?;
+            Ok(source.is_done() && source.data().len() <= max)
+        }
+    }
+}
+
+/// Compress an input that fits in one block. The input is compressed with an EncoderContext straight from the
+/// source data, so the only allocations are sized to the input.
+fn compress_single_block<S: ByteSource>(mut source: S, opts: &BzOpts, fname: &str) -> io::Result<()> {
+    // Read the whole input (a memory source has all of it already)
+    while !source.is_done() {
+        source.fill(source.data().len() + 1)?;
+    }
+    let mut out = Vec::with_capacity(source.data().len() / 2 + 64);
+    EncoderContext::new().compress(source.data(), opts, &mut out)?;
+    std::fs::write(fname, out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, fits_one_block};
+    use crate::bitstream::byte_source::{ByteSource, ReadSource};
+    use crate::compression::decompress::decompress;
+    use crate::tools::cli::BzOpts;
+
+    /// Simple pseudo random generator so the generated corpus inputs don't need to be stored.
+    struct Lcg(u32);
+    impl Lcg {
+        fn next(&mut self) -> u32 {
+            self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+            self.0 >> 16
+        }
+    }
+
+    /// Build a corpus input. Must match tests/corpus/make_corpus.py, which created the C outputs.
+    fn corpus_input(name: &str) -> Vec<u8> {
+        let mut lcg = Lcg(2023);
+        match name {
+            "empty" => vec![],
+            "hello" => b"hello world\n".to_vec(),
+            "text" => include_bytes!("../../tests/corpus/text.txt").to_vec(),
+            "words" => {
+                let words = [
+                    "the", "quick", "brown", "fox", "jumps", "over", "lazy", "dog", "\n", "aaaaaa",
+                    "zzzzzzzzzzzz",
+                ];
+                (0..60_000)
+                    .map(|_| words[lcg.next() as usize % words.len()])
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .into_bytes()
+            }
+            "zeros" => vec![0; 300_000],
+            "abc" => b"abc".repeat(40_000),
+            "runs" => (0..1_500)
+                .flat_map(|_| {
+                    let byte = (lcg.next() % 4) as u8;
+                    vec![byte; 1 + lcg.next() as usize % 600]
+                })
+                .collect(),
+            "binary" => (0..20_000).map(|_| lcg.next() as u8).collect(),
+            _ => unreachable!("Unknown corpus input {}", name),
+        }
+    }
+
+    /// Compress data with the given options through a temporary file and return the compressed bytes.
+    fn compress_data(name: &str, data: &[u8], opts: &mut BzOpts) -> Vec<u8> {
+        let path = temp_path(name);
+        std::fs::write(&path, data).unwrap();
+
+        opts.files = vec![path.clone()];
+        compress(opts).unwrap();
+
+        let out_path = format!("{}.bz2", path);
+        let compressed = std::fs::read(&out_path).unwrap();
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&out_path).ok();
+        compressed
+    }
+
+    /// Decompress data through a temporary file and return the decompressed bytes.
+    fn decompress_data(name: &str, compressed: &[u8]) -> Vec<u8> {
+        let path = temp_path(name);
+        let in_path = format!("{}.bz2", path);
+        std::fs::write(&in_path, compressed).unwrap();
+
+        let mut opts = BzOpts::new();
+        opts.files = vec![in_path.clone()];
+        decompress(&opts).unwrap();
+
+        // Decompress currently names its output .txt
+        let out_path = format!("{}.txt", path);
+        let data = std::fs::read(&out_path).unwrap();
+        std::fs::remove_file(&in_path).ok();
+        std::fs::remove_file(&out_path).ok();
+        data
+    }
+
+    /// A temporary file name unique to this test run.
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bzip2_test_{}_{}", std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn reference_corpus_test() {
+        let corpus: [(&str, usize, &[u8]); 8] = [
+            ("empty", 9, include_bytes!("../../tests/corpus/empty.bz2")),
+            ("hello", 9, include_bytes!("../../tests/corpus/hello.bz2")),
+            ("text", 9, include_bytes!("../../tests/corpus/text.bz2")),
+            ("words", 1, include_bytes!("../../tests/corpus/words.bz2")),
+            ("zeros", 9, include_bytes!("../../tests/corpus/zeros.bz2")),
+            ("abc", 9, include_bytes!("../../tests/corpus/abc.bz2")),
+            ("runs", 1, include_bytes!("../../tests/corpus/runs.bz2")),
+            ("binary", 9, include_bytes!("../../tests/corpus/binary.bz2")),
+        ];
+        for (name, block_size, expected) in corpus {
+            let mut opts = BzOpts::new();
+            opts.block_size = block_size;
+            opts.reference = true;
+            let compressed = compress_data(name, &corpus_input(name), &mut opts);
+            assert!(
+                compressed == expected,
+                "Reference output for {} differs from the C output",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn iterations_test() {
+        let data = corpus_input("words");
+        let mut sizes = vec![];
+        for iterations in [1, 2, 8] {
+            let mut opts = BzOpts::new();
+            opts.iterations = iterations;
+            let name = format!("iterations_{}", iterations);
+            let compressed = compress_data(&name, &data, &mut opts);
+            assert!(decompress_data(&name, &compressed) == data);
+            sizes.push(compressed.len());
+        }
+        // More passes should not make the output larger
+        assert!(sizes[0] >= sizes[1] && sizes[1] >= sizes[2], "{:?}", sizes);
+    }
+
+    #[test]
+    fn runs_round_trip_test() {
+        // Blocks that end in the middle of a run, and runs that end a block
+        for name in ["runs", "zeros"] {
+            let data = corpus_input(name);
+            let mut opts = BzOpts::new();
+            opts.block_size = 1;
+            let compressed = compress_data(name, &data, &mut opts);
+            assert!(decompress_data(name, &compressed) == data);
+        }
+    }
+
+    #[test]
+    fn mmap_test() {
+        // Mapped and read inputs give the same stream, for single block and multi block inputs
+        for (name, block_size) in [("zeros", 1), ("words", 1), ("words", 9)] {
+            let data = corpus_input(name);
+            let mut outputs = vec![];
+            for mmap in [true, false] {
+                let mut opts = BzOpts::new();
+                opts.block_size = block_size;
+                opts.mmap = mmap;
+                outputs.push(compress_data(&format!("mmap_{}_{}", name, mmap), &data, &mut opts));
+            }
+            assert!(outputs[0] == outputs[1], "Mapped input of {} compressed differently", name);
+            assert!(decompress_data(name, &outputs[0]) == data);
+        }
+    }
+
+    #[test]
+    fn fits_one_block_test() {
+        let data = (0..1_000).map(|i| i as u8).collect::<Vec<u8>>();
+        // A known size is trusted without reading
+        let mut source = ReadSource::new(&data[..]);
+        assert!(!fits_one_block(&mut source, Some(1_000), 999).unwrap());
+        assert!(source.data().is_empty());
+        // Otherwise up to one byte past the limit is read, and kept
+        for (max, fits) in [(1_000, true), (999, false), (10, false)] {
+            let mut source = ReadSource::with_capacity(&data[..], 3);
+            assert_eq!(fits_one_block(&mut source, None, max).unwrap(), fits);
+            assert!(source.data().len() > max || source.is_done());
+            assert!(source.data() == &data[..source.data().len()]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipe_input_test() {
+        // A pipe has no known size, so a small input is found by reading it and a large one is split as usual
+        let text = corpus_input("text");
+        for (name, size) in [("pipe_small", 1_000), ("pipe_large", 350_000)] {
+            let data = text.iter().copied().cycle().take(size).collect::<Vec<u8>>();
+            let path = temp_path(name);
+            std::fs::remove_file(&path).ok();
+            assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+            let writer = {
+                let (path, data) = (path.clone(), data.clone());
+                std::thread::spawn(move || std::fs::write(path, data).unwrap())
+            };
+            let mut opts = BzOpts::new();
+            opts.block_size = 1;
+            opts.files = vec![path.clone()];
+            compress(&mut opts).unwrap();
+            writer.join().unwrap();
+
+            let out_path = format!("{}.bz2", path);
+            let compressed = std::fs::read(&out_path).unwrap();
+            std::fs::remove_file(&path).ok();
+            std::fs::remove_file(&out_path).ok();
+            assert!(decompress_data(name, &compressed) == data);
+        }
+    }
+
+    #[test]
+    fn empty_input_test() {
+        let compressed = compress_data("empty", b"", &mut BzOpts::new());
+        assert!(decompress_data("empty", &compressed).is_empty());
+    }
+
+    #[test]
+    fn high_effort_test() {
+        // Clustering the tables should make every one of these smaller than the standard tables do
+        for name in ["text", "words", "runs", "binary"] {
+            let data = corpus_input(name);
+            let standard = compress_data(name, &data, &mut BzOpts::new());
+            let mut opts = BzOpts::new();
+            opts.high_effort = true;
+            let compressed = compress_data(name, &data, &mut opts);
+            assert!(decompress_data(name, &compressed) == data);
+            assert!(compressed.len() < standard.len(), "{}: {} >= {}", name, compressed.len(), standard.len());
+        }
+    }
 }
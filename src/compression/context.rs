@@ -0,0 +1,178 @@
+//! Reusable contexts that own the scratch buffers for compressing and decompressing blocks.
+//!
+//! Compressing a block needs a BWT index and output, an RLE2 vec, selector vecs and a buffer for the packed bits.
+//! Decompressing a block needs selector vecs, huffman decode tables, the MTF output, the BWT
+//! transformation vec and the decoded data. Allocating all of these for every block costs more than the work itself
+//! when many small inputs are processed. A context keeps them from block to block and from call to call.
+//!
+//! The file based compress and decompress functions use a context for each thread. Library users can keep a context
+//! and call:
+//! - EncoderContext::compress: compress a slice into a complete BZIP2 stream.
+//! - DecoderContext::decompress: decompress complete BZIP2 streams held in a slice, within the DecodeLimits given to
+//!   DecoderContext::with_limits.
+//!
+//! A context is used by one block at a time, so give each thread its own.
+//!
+use super::compress_block::compress_block;
+use super::decode_limits::DecodeLimits;
+use super::decompress::{decode_streams, read_stream_header};
+use crate::bitstream::{bitpacker::BitPacker, bitreader::BitReader, bitwriter::BitWriter};
+use crate::huffman_coding::{decode_table::HufDecodeTable, huffman::HufScratch};
+use crate::tools::{cli::BzOpts, crc::do_crc, rle1::rle1_encode_runs};
+use std::io;
+
+/// Scratch buffers for compressing blocks.
+#[derive(Default)]
+pub struct EncoderContext {
+    /// RLE1 data of the block being compressed by compress().
+    pub(crate) rle1: Vec<u8>,
+    /// Workspace for the BWT sort.
+    pub(crate) bwt_index: Vec<u32>,
+    /// BWT output.
+    pub(crate) bwt: Vec<u8>,
+    /// RLE2 output.
+    pub(crate) rle2: Vec<u16>,
+    /// Selector vecs for the huffman stage.
+    pub(crate) huffman: HufScratch,
+    /// Packed bits of the block.
+    pub(crate) packer: BitPacker,
+    /// Output buffer of the BitWriter used by compress().
+    pub(crate) stream: Vec<u8>,
+}
+
+impl EncoderContext {
+    /// Create a context with empty buffers. They grow to the size needed by the first blocks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compress data as a complete BZIP2 stream, using the block size and algorithms set in opts. The stream is
+    /// appended to out. Blocks are compressed one after the other on this thread.
+    pub fn compress(&mut self, data: &[u8], opts: &BzOpts, out: &mut Vec<u8>) -> io::Result<()> {
+        let block_size = opts.block_size.clamp(1, 9);
+        let rle1_size = block_size * 100000 - 19;
+        let mut bw = BitWriter::with_buffer(out, block_size as u8, std::mem::take(&mut self.stream));
+
+        let mut cursor = 0;
+        while cursor < data.len() {
+            // The RLE1 vec is taken out of the context while the block is compressed
+            let mut block = std::mem::take(&mut self.rle1);
+            block.clear();
+            let used = rle1_encode_runs(&data[cursor..], &mut block, rle1_size, 1);
+            let crc = do_crc(0, &data[cursor..cursor + used]);
+            cursor += used;
+
+            let padding = compress_block(&block, crc, opts, self);
+            self.rle1 = block;
+            bw.add_block(self.packed(), padding)?;
+        }
+        bw.finish()?;
+        self.stream = bw.into_parts().1;
+        Ok(())
+    }
+
+    /// The packed data of the last block compressed with this context.
+    pub fn packed(&self) -> &[u8] {
+        &self.packer.output
+    }
+
+    /// Take the packed data of the last block, leaving an empty buffer in its place.
+    pub(crate) fn take_packed(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.packer.output)
+    }
+}
+
+/// Scratch buffers for decompressing blocks.
+#[derive(Default)]
+pub struct DecoderContext {
+    /// Selectors as read from the stream (still Move-To-Front transformed).
+    pub(crate) raw_selectors: Vec<u8>,
+    /// The table used for each 50 symbol chunk.
+    pub(crate) selectors: Vec<usize>,
+    /// Symbol and code length pairs of the table being read.
+    pub(crate) code_lengths: Vec<(u16, u32)>,
+    /// Huffman decode tables. Only the first table_count of the current block are in use.
+    pub(crate) tables: Vec<HufDecodeTable>,
+    /// RLE2 and MTF decoded data.
+    pub(crate) mtf_out: Vec<u8>,
+    /// BWT transformation vec.
+    pub(crate) t_vec: Vec<u32>,
+    /// Decoded block.
+    pub(crate) block_out: Vec<u8>,
+    /// Limits checked by decompress().
+    pub(crate) limits: DecodeLimits,
+}
+
+impl DecoderContext {
+    /// Create a context with empty buffers. They grow to the size needed by the first blocks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a context whose decompress() stops with DecodeError::LimitExceeded rather than pass limits. Use this
+    /// for data that can't be trusted.
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Decompress the BZIP2 streams held in data, appending the decompressed data to out. The streams are read in
+    /// place.
+    pub fn decompress(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let mut br = BitReader::from_slice(data);
+        let limits = self.limits;
+        read_stream_header(&mut br).and_then(|block_size| decode_streams(self, &mut br, block_size, limits, out))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DecoderContext, EncoderContext};
+    use crate::tools::cli::BzOpts;
+
+    #[test]
+    fn reuse_test() {
+        let mut encoder = EncoderContext::new();
+        let mut decoder = DecoderContext::new();
+        let mut opts = BzOpts::new();
+        opts.block_size = 1;
+        let inputs = [
+            b"hello world\n".repeat(20_000),
+            vec![],
+            b"a".to_vec(),
+            (0..250_000_u32).map(|i| (i * 7 % 251) as u8).collect(),
+            vec![9; 1_000],
+        ];
+        for data in &inputs {
+            let mut compressed = vec![];
+            encoder.compress(data, &opts, &mut compressed).unwrap();
+            // Nothing from the earlier payloads may leak into the stream
+            let mut fresh = vec![];
+            EncoderContext::new().compress(data, &opts, &mut fresh).unwrap();
+            assert!(compressed == fresh);
+
+            let mut decompressed = vec![];
+            decoder.decompress(&compressed, &mut decompressed).unwrap();
+            assert!(decompressed == *data);
+        }
+    }
+
+    #[test]
+    fn reference_output_test() {
+        // In reference mode the stream must match the C output, as the file based compress does
+        let mut opts = BzOpts::new();
+        opts.reference = true;
+        let mut compressed = vec![];
+        let mut encoder = EncoderContext::new();
+        for data in [&b"hello world\n"[..], include_bytes!("../../tests/corpus/text.txt")] {
+            compressed.clear();
+            encoder.compress(data, &opts, &mut compressed).unwrap();
+        }
+        assert!(compressed == include_bytes!("../../tests/corpus/text.bz2"));
+        compressed.clear();
+        encoder.compress(b"", &opts, &mut compressed).unwrap();
+        assert!(compressed == include_bytes!("../../tests/corpus/empty.bz2"));
+    }
+}
@@ -0,0 +1,104 @@
+//! Errors found in the data while decoding a BZIP2 stream.
+//!
+//! The decoder checks everything it reads that could otherwise make it index out of bounds or loop forever, as the
+//! C version does, and stops at the first problem. It also stops when the stream would pass the DecodeLimits set. The decompress functions return io::Result, so a DecodeError is
+//! returned inside an io::Error of kind InvalidData. DecodeError::from_io gets it back:
+//! ```ignore
+//! if let Err(e) = ctx.decompress(&data, &mut out) {
+//!     match DecodeError::from_io(&e) {
+//!         Some(DecodeError::UnexpectedEof) => println!("The stream is truncated"),
+//!         Some(error) => println!("The stream is corrupt: {}", error),
+//!         None => println!("I/O error: {}", e),
+//!     }
+//! }
+//! ```
+//!
+use super::decode_limits::Limit;
+use std::{fmt, io};
+
+/// A problem found in the compressed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The stream does not start with "BZh".
+    BadSignature,
+    /// The block size in the stream header is not 1-9.
+    BadBlockSize(u8),
+    /// The data ended before the end of the stream.
+    UnexpectedEof,
+    /// A block starts with neither the block header magic nor the stream footer magic.
+    BadBlockHeader,
+    /// The BWT origin pointer is outside the block.
+    BadKey(usize),
+    /// The symbol map is inconsistent, or lists no symbols.
+    BadSymbolMap,
+    /// The table count is not 2-6.
+    BadTableCount(usize),
+    /// The selector count is zero.
+    NoSelectors,
+    /// A selector names a table past the table count.
+    BadSelector(usize),
+    /// A huffman table has no symbols, or more than 258.
+    BadSymbolCount(usize),
+    /// A code length is outside 1-20.
+    BadCodeLength(i32),
+    /// The code lengths of a huffman table give more codes than can exist (the Kraft sum is over 1).
+    OversubscribedTable,
+    /// The bits don't form a code of the huffman table, or the data ended inside a code.
+    BadCode,
+    /// The end of block symbol came before the chunk of the last selector.
+    EarlyEndOfBlock,
+    /// The chunk of the last selector has no end of block symbol.
+    MissingEndOfBlock,
+    /// The block decodes to more than the block size.
+    BlockTooLarge,
+    /// The CRC of the decoded block does not match the CRC in the block header.
+    BadBlockCrc,
+    /// The CRC combined from the blocks does not match the CRC in the stream footer.
+    BadStreamCrc,
+    /// Decoding further would pass one of the DecodeLimits.
+    LimitExceeded(Limit),
+    /// Data that is not a stream follows the last stream.
+    TrailingGarbage,
+}
+
+impl DecodeError {
+    /// Return the DecodeError held in an io::Error returned by the decoder, or None for other errors.
+    pub fn from_io(error: &io::Error) -> Option<&DecodeError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadSignature => write!(f, "Invalid compressed file"),
+            DecodeError::BadBlockSize(size) => write!(f, "Invalid block size {}", size),
+            DecodeError::UnexpectedEof => write!(f, "Unexpected End Of File"),
+            DecodeError::BadBlockHeader => write!(f, "Invalid block header or footer"),
+            DecodeError::BadKey(key) => write!(f, "Invalid key pointer {}", key),
+            DecodeError::BadSymbolMap => write!(f, "Invalid symbol map"),
+            DecodeError::BadTableCount(count) => write!(f, "Invalid table count {}", count),
+            DecodeError::NoSelectors => write!(f, "No selectors"),
+            DecodeError::BadSelector(selector) => write!(f, "Selector {} is past the table count", selector),
+            DecodeError::BadSymbolCount(count) => write!(f, "Invalid huffman table size {}", count),
+            DecodeError::BadCodeLength(length) => write!(f, "Invalid code length {}", length),
+            DecodeError::OversubscribedTable => write!(f, "Huffman code lengths are oversubscribed"),
+            DecodeError::BadCode => write!(f, "Invalid huffman code"),
+            DecodeError::EarlyEndOfBlock => write!(f, "Found end of block too early"),
+            DecodeError::MissingEndOfBlock => write!(f, "Did not find end of block"),
+            DecodeError::BlockTooLarge => write!(f, "Block is larger than the block size"),
+            DecodeError::BadBlockCrc => write!(f, "Block CRC does not match the data"),
+            DecodeError::BadStreamCrc => write!(f, "Stream CRC does not match the blocks"),
+            DecodeError::LimitExceeded(limit) => write!(f, "The {} limit was exceeded", limit),
+            DecodeError::TrailingGarbage => write!(f, "Trailing garbage after the end of the stream"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(error: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
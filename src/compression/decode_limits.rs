@@ -0,0 +1,161 @@
+//! Limits on the resources a stream may use while it is decoded.
+//!
+//! A few hundred bytes of BZIP2 data can decode to tens of megabytes, and a file may hold any number of streams and
+//! blocks. When the input can't be trusted, set DecodeLimits so the decoder stops with
+//! DecodeError::LimitExceeded before the output or its buffers grow past what the caller allows. No limit is set by
+//! default.
+//!
+//! The limits are checked as each stream and block starts, and the decoded size of a block is capped before it is
+//! written, so no limit is passed by more than one block of BWT data.
+//!
+use std::fmt;
+
+/// Bytes of decoder buffers for each byte of block size: the MTF output (1 byte) and the BWT transformation vec (4).
+const BUFFER_BYTES_PER_BLOCK_BYTE: u64 = 5;
+
+/// Limits on the resources used to decode. None means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Most bytes of decompressed data.
+    pub max_output: Option<u64>,
+    /// Most bytes of decompressed data for each byte of compressed data read.
+    pub max_ratio: Option<u64>,
+    /// Most bytes of decoder buffers: 5 bytes per byte of block size for the MTF output and BWT, plus the decoded
+    /// block.
+    pub max_memory: Option<u64>,
+    /// Most streams in the input (a file can hold several streams one after the other).
+    pub max_streams: Option<u64>,
+    /// Most blocks in all streams.
+    pub max_blocks: Option<u64>,
+}
+
+/// The limit a stream would pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Output,
+    Ratio,
+    Memory,
+    Streams,
+    Blocks,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Output => write!(f, "output size"),
+            Limit::Ratio => write!(f, "compression ratio"),
+            Limit::Memory => write!(f, "memory"),
+            Limit::Streams => write!(f, "stream count"),
+            Limit::Blocks => write!(f, "block count"),
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Create limits with nothing limited.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Counts what has been decoded so far, against the limits.
+pub(crate) struct LimitTracker {
+    limits: DecodeLimits,
+    streams: u64,
+    blocks: u64,
+    output: u64,
+    /// Size in bytes of the buffers for a block of the current stream.
+    buffers: u64,
+}
+
+impl LimitTracker {
+    pub(crate) fn new(limits: DecodeLimits) -> Self {
+        Self {
+            limits,
+            streams: 0,
+            blocks: 0,
+            output: 0,
+            buffers: 0,
+        }
+    }
+
+    /// Count a new stream with blocks of block_size (1-9) hundred thousand bytes.
+    pub(crate) fn start_stream(&mut self, block_size: u8) -> Result<(), Limit> {
+        self.streams += 1;
+        if self.limits.max_streams.is_some_and(|max| self.streams > max) {
+            return Err(Limit::Streams);
+        }
+        // The buffers are sized by the block size, so a stream that needs too much is refused before they grow
+        self.buffers = block_size as u64 * 100000 * BUFFER_BYTES_PER_BLOCK_BYTE;
+        if self.limits.max_memory.is_some_and(|max| self.buffers > max) {
+            return Err(Limit::Memory);
+        }
+        Ok(())
+    }
+
+    /// Count a new block.
+    pub(crate) fn start_block(&mut self) -> Result<(), Limit> {
+        self.blocks += 1;
+        if self.limits.max_blocks.is_some_and(|max| self.blocks > max) {
+            return Err(Limit::Blocks);
+        }
+        Ok(())
+    }
+
+    /// Return the most bytes the next block may decode to, and the limit that sets it, once input_bytes of
+    /// compressed data have been read.
+    pub(crate) fn block_budget(&self, input_bytes: u64) -> (usize, Limit) {
+        let budgets = [
+            (self.limits.max_output, Limit::Output),
+            (self.limits.max_ratio.map(|ratio| ratio.saturating_mul(input_bytes)), Limit::Ratio),
+            (self.limits.max_memory.map(|max| max.saturating_sub(self.buffers)), Limit::Memory),
+        ];
+        budgets
+            .into_iter()
+            .filter_map(|(max, limit)| max.map(|max| (max, limit)))
+            .map(|(max, limit)| {
+                // Output and ratio limit all the output, memory limits each block
+                let used = if limit == Limit::Memory { 0 } else { self.output };
+                (max.saturating_sub(used).try_into().unwrap_or(usize::MAX), limit)
+            })
+            .min_by_key(|&(budget, _)| budget)
+            .unwrap_or((usize::MAX, Limit::Output))
+    }
+
+    /// Count bytes of decoded output.
+    pub(crate) fn add_output(&mut self, bytes: usize) {
+        self.output += bytes as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DecodeLimits, Limit, LimitTracker};
+
+    #[test]
+    fn tracker_test() {
+        let mut tracker = LimitTracker::new(DecodeLimits::new());
+        assert_eq!(tracker.start_stream(9), Ok(()));
+        assert_eq!(tracker.block_budget(10), (usize::MAX, Limit::Output));
+
+        let limits = DecodeLimits {
+            max_output: Some(1_000),
+            max_ratio: Some(20),
+            max_memory: Some(600_000),
+            max_streams: Some(1),
+            max_blocks: Some(2),
+        };
+        let mut tracker = LimitTracker::new(limits);
+        assert_eq!(tracker.start_stream(2), Err(Limit::Memory));
+        let mut tracker = LimitTracker::new(limits);
+        assert_eq!(tracker.start_stream(1), Ok(()));
+        assert_eq!(tracker.start_block(), Ok(()));
+        // 20 bytes for each of 30 bytes read is below the output limit, and memory allows 100k for the block
+        assert_eq!(tracker.block_budget(30), (600, Limit::Ratio));
+        tracker.add_output(600);
+        assert_eq!(tracker.block_budget(100), (400, Limit::Output));
+        assert_eq!(tracker.start_block(), Ok(()));
+        assert_eq!(tracker.start_block(), Err(Limit::Blocks));
+        assert_eq!(tracker.start_stream(1), Err(Limit::Streams));
+    }
+}
@@ -9,57 +9,70 @@
 //! NOTE 2: BZIP2 should default to deleting the source file (if input comes from a file), and set the creation date
 //! of the compressed file to mirror the original file. This is **not** yet implemented.
 //! 
-//! NOTE 3: TBD: It may be possible to improve performance by enhancing cache coherency during the BWT decoding.
+//! NOTE 3: The Huffman decoding, the RLE2 decoding and the MTF decoding are done in a single pass (Rle2MtfDecoder).
+//! The BWT decoding, the RLE1 decoding and the block CRC are done in a second pass (bwt_rle1_decode). Both write into
+//! buffers that are reused for every block.
 //! 
+//! NOTE 4: A file may hold several streams one after the other. They are decoded in turn. Anything after the last
+//! stream that is not a stream is an error (the C version only warns about it), as is a stream cut short.
+//! DecodeLimits (set in BzOpts or a DecoderContext) cap the output, ratio, memory, streams and blocks for untrusted
+//! input.
+//!
 use crate::{
-    bitstream::bitreader::BitReader,
-    bwt_algorithms::bwt_sort::bwt_decode,
+    bitstream::{
+        bitreader::BitReader,
+        byte_source::{open_input, ByteSource, FileSource},
+    },
+    compression::{
+        context::DecoderContext,
+        decode_error::DecodeError,
+        decode_limits::{DecodeLimits, Limit, LimitTracker},
+    },
+    bwt_algorithms::bwt_sort::bwt_rle1_decode,
+    huffman_coding::decode_table::{HufDecodeTable, MAX_CODE_LEN},
     tools::{
         cli::BzOpts,
-        crc::{do_crc, do_stream_crc},
-        rle1::rle1_decode,
-        rle2_mtf::rle2_mtf_decode_fast,
+        crc::do_stream_crc,
+        rle2_mtf::Rle2MtfDecoder,
         symbol_map::decode_sym_map,
     },
 };
 use log::{error, info, trace, warn};
 use std::{
     fs::File,
-    io::{self, Error, Write},
+    io::{self, Write},
 };
 
 //const BUFFER_SIZE: usize = 100000;
-const EOF_MESSAGE: &str = "Unexpected End Of File";
+const EOF: DecodeError = DecodeError::UnexpectedEof;
 const CHUNK_SIZE: usize = 50; // Bzip2 chunk size
 const FOOTER: [u8; 6] = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
 const HEADER: [u8; 6] = [0x31_u8, 0x41, 0x59, 0x26, 0x53, 0x59];
 
 /// Decompress the file specified in opts (BzOpts).
 pub fn decompress(opts: &BzOpts) -> io::Result<()> {
-    // Start bitreader from input file in the command line
-    let mut br = BitReader::new(File::open(opts.files[0].clone())?);
+    // Large files are mapped into memory and read in place. Small files get a read buffer of their own size.
+    match open_input(&opts.files[0], opts.mmap)?.0 {
+        FileSource::Mapped(source) => decompress_source(source, opts),
+        FileSource::Read(source) => decompress_source(source, opts),
+    }
+}
+
+/// Decompress the stream in source, writing the output file named in opts.
+fn decompress_source<S: ByteSource>(source: S, opts: &BzOpts) -> io::Result<()> {
+    // Start bitreader from the input file in the command line.
+    let mut br = BitReader::from_source(source);
 
     // We will eventually need to mark the output file with the timestamp of the compresssed file.
     //let metadata = std::fs::metadata(opts.file.as_ref().unwrap().to_string())?;
 
-    // Look for a valid signature.
-    if "BZh".as_bytes() == br.bytes(3).expect(EOF_MESSAGE) {
-        info!("Found a valid bzip2 signature.");
-    } else {
+    // Look for a valid signature and block size.
+    let block_size = read_stream_header(&mut br).map_err(|e| br.take_error().unwrap_or(e)).inspect_err(|_| {
         error!(
             "Fatal error: {} is not a valid bzip2 compressed file.",
             opts.files[0]
-        );
-        return Err(Error::new(io::ErrorKind::Other, "Invalid compressed file."));
-    }
-
-    // Use the block size to validate the max number of selectors.
-    // (Use saturating_sub in case there is a data error - to prevent underflow)
-    let block_size = br.byte().expect(EOF_MESSAGE).saturating_sub(0x30);
-    if !(1..=9).contains(&block_size) {
-        error!("Fatal error: Found invalid block size.");
-        return Err(Error::new(io::ErrorKind::Other, "Invalid block size"));
-    }
+        )
+    })?;
 
     // Good so far. Prepare to write the data. (Drop temp variables after this block)
     let mut f_out: File;
@@ -70,6 +83,93 @@ pub fn decompress(opts: &BzOpts) -> io::Result<()> {
         f_out = File::create(fname)?;
     }
 
+    decode_streams(&mut DecoderContext::new(), &mut br, block_size, opts.limits, &mut f_out)
+}
+
+/// Read the stream signature and return the block size (1-9).
+pub(crate) fn read_stream_header<S: ByteSource>(br: &mut BitReader<S>) -> io::Result<u8> {
+    // Look for a valid signature.
+    if br.bytes(3).is_some_and(|signature| signature == "BZh".as_bytes()) {
+        info!("Found a valid bzip2 signature.");
+    } else {
+        return Err(DecodeError::BadSignature.into());
+    }
+
+    // Use the block size to validate the max number of selectors.
+    // (Use saturating_sub in case there is a data error - to prevent underflow)
+    let block_size = br.byte().ok_or(EOF)?.saturating_sub(0x30);
+    if !(1..=9).contains(&block_size) {
+        error!("Fatal error: Found invalid block size.");
+        return Err(DecodeError::BadBlockSize(block_size).into());
+    }
+    Ok(block_size)
+}
+
+/// Decode the stream whose header has been read (giving block_size), and any streams that follow it, writing the
+/// data to out. Decoding stops with an error if it would pass limits. The buffers in ctx are reused for every block.
+/// If reading the input fails, that error is returned.
+pub(crate) fn decode_streams<S: ByteSource, W: Write>(
+    ctx: &mut DecoderContext,
+    br: &mut BitReader<S>,
+    block_size: u8,
+    limits: DecodeLimits,
+    out: &mut W,
+) -> io::Result<()> {
+    let result = decode_stream_list(ctx, br, block_size, limits, out);
+    // A read error looks like the end of the data to the decoder, so report it instead of what the decoder found.
+    match br.take_error() {
+        Some(e) => Err(e),
+        None => result,
+    }
+}
+
+/// Decode the streams for decode_streams().
+fn decode_stream_list<S: ByteSource, W: Write>(
+    ctx: &mut DecoderContext,
+    br: &mut BitReader<S>,
+    mut block_size: u8,
+    limits: DecodeLimits,
+    out: &mut W,
+) -> io::Result<()> {
+    let mut tracker = LimitTracker::new(limits);
+    loop {
+        tracker.start_stream(block_size).map_err(limit_error)?;
+        decode_blocks(ctx, br, block_size, &mut tracker, out)?;
+
+        // Each stream ends on a byte boundary. Another stream may follow it, as the C version writes when files are
+        // concatenated.
+        br.align_to_byte();
+        br.refill();
+        if br.remaining_in_buffer() == 0 {
+            return Ok(());
+        }
+        // Anything else after the last stream is an error. The data decoded so far has already been written.
+        match read_stream_header(br) {
+            Ok(size) => block_size = size,
+            Err(e) if DecodeError::from_io(&e) == Some(&DecodeError::BadSignature) => {
+                error!("Found trailing garbage after the end of the stream.");
+                return Err(DecodeError::TrailingGarbage.into());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Log the limit that was reached and return it as an error.
+fn limit_error(limit: Limit) -> io::Error {
+    error!("Stopped decoding: the {} limit was reached.", limit);
+    DecodeError::LimitExceeded(limit).into()
+}
+
+/// Decode the blocks and the stream footer that follow the stream header, writing the data to out. The buffers in
+/// ctx are reused for every block.
+fn decode_blocks<S: ByteSource, W: Write>(
+    ctx: &mut DecoderContext,
+    br: &mut BitReader<S>,
+    block_size: u8,
+    tracker: &mut LimitTracker,
+    out: &mut W,
+) -> io::Result<()> {
     // Initialize steam CRC value
     let mut stream_crc = 0;
     // Initialize block_counter for reporting purposes
@@ -82,47 +182,47 @@ pub fn decompress(opts: &BzOpts) -> io::Result<()> {
         block_counter += 1;
 
         // Block header (or footer) should come next.
-        if let Some(header_footer) = br.bytes(6) {
-            // Check for footer first. Exit the loop block when we find it.
-            if header_footer == FOOTER {
-                break 'block;
-            }
-            // We must now have a block header. Create an error if not.
-            if header_footer != HEADER {
-                return Err(Error::new(io::ErrorKind::Other, "Invalid block footer"));
-            }
-            info!("Found a valid header for block {}.", block_counter);
-        };
+        let header_footer = br.bytes(6).ok_or(EOF)?;
+        // Check for footer first. Exit the loop block when we find it.
+        if header_footer == FOOTER {
+            break 'block;
+        }
+        // We must now have a block header. Create an error if not.
+        if header_footer != HEADER {
+            return Err(DecodeError::BadBlockHeader.into());
+        }
+        info!("Found a valid header for block {}.", block_counter);
+        tracker.start_block().map_err(limit_error)?;
 
         // Get crc
-        let block_crc = br.bint(32).expect(EOF_MESSAGE);
+        let block_crc = br.bint(32).ok_or(EOF)?;
         info!("CRC is {}.", block_crc);
 
         // Get randomize flag - should almost always be zero
-        let rand = br.bool_bit().expect(EOF_MESSAGE);
+        let rand = br.bool_bit().ok_or(EOF)?;
         trace!("\nRandomized is {:?}.", rand);
 
         // Get key (origin pointer)
-        let key = br.bint(24).expect(EOF_MESSAGE);
+        let key = br.bint(24).ok_or(EOF)?;
         if key > block_size as usize * 100000 + 10 {
             error!("Invalid key pointer");
-            return Err(Error::new(io::ErrorKind::Other, "Invalid key pointer"));
+            return Err(DecodeError::BadKey(key).into());
         }
         info!("Key is {}.", key);
 
         // Get the symbol info. (Use block to drop the temporary vec used to grab the data)
         {
             // First set up a temporary map vec starting with the map "index".
-            let mut sym_map: Vec<u16> = vec![br.bint(16).expect(EOF_MESSAGE) as u16];
+            let mut sym_map: Vec<u16> = vec![br.bint(16).ok_or(EOF)? as u16];
 
             // Now get as many 16-symbol maps as indicated by the set bits in the "index"
             let symbol_loc = br.loc();
             for _i in 0..sym_map[0].count_ones() as usize {
-                sym_map.push(br.bint(16).expect(EOF_MESSAGE) as u16);
+                sym_map.push(br.bint(16).ok_or(EOF)? as u16);
             }
 
             // Decode the symbol map and save it
-            symbol_set = decode_sym_map(&sym_map);
+            symbol_set = decode_sym_map(&sym_map)?;
             //symbol_set = symbol_set[1..symbol_set.len()].to_vec();
 
             // Count how many symbols are in the symbol map. The +2 adds in RUNA / RUNB plus EOB.
@@ -137,44 +237,55 @@ pub fn decompress(opts: &BzOpts) -> io::Result<()> {
         }
 
         // Read NumTrees
-        let table_count = br.bint(3).expect(EOF_MESSAGE);
+        let table_count = br.bint(3).ok_or(EOF)?;
         if !(2..=6).contains(&table_count) {
             error!("Invalid table count");
-            return Err(Error::new(io::ErrorKind::Other, "Invalid table count"));
+            return Err(DecodeError::BadTableCount(table_count).into());
         }
 
         // Read Selector_count (NumSels in Julian speak) (mutable, because we may need to adjust it)
-        let mut selector_count = br.bint(15).expect(EOF_MESSAGE);
+        let mut selector_count = br.bint(15).ok_or(EOF)?;
+        if selector_count == 0 {
+            error!("Found no selectors");
+            return Err(DecodeError::NoSelectors.into());
+        }
 
         // Read Selectors based on the actual number of selectors reported
         // (But only save the ones we can use! Hence max_selectors.)
-        let mut selector_map = vec![0_usize; selector_count];
+        let selector_map = &mut ctx.selectors;
         // Use block to drop temporary variables
         {
             // First read the "raw" selector map
-            let mut raw_selector_map = Vec::with_capacity(selector_count as usize);
+            let raw_selector_map = &mut ctx.raw_selectors;
+            raw_selector_map.clear();
             // Set selector maximum
             let max_selectors = block_size as usize * 100000 / 50;
-            let mut group: u8 = 0;
             for _ in 0..selector_count {
-                while br.bool_bit().expect(EOF_MESSAGE) {
+                // Each selector is a unary count, which must name one of the tables
+                let mut group: u8 = 0;
+                while br.bool_bit().ok_or(EOF)? {
                     group += 1;
+                    if group as usize >= table_count {
+                        error!("Found a selector past the {} tables", table_count);
+                        return Err(DecodeError::BadSelector(group as usize).into());
+                    }
                 }
                 // Like Julian, ignore  excessive selectors, only push maps that can be used.
-                if selector_count <= max_selectors {
+                if raw_selector_map.len() < max_selectors {
                     raw_selector_map.push(group);
                 }
-                group = 0;
             }
             // Adjust the selector_count if needed. This should never happen.
             if selector_count > max_selectors {
                 warn!("Found {} selectors were reported, but the maximum is {}. Adjust the selector count down.", selector_count, max_selectors);
                 selector_count = max_selectors;
             }
+            selector_map.clear();
+            selector_map.resize(selector_count, 0);
 
             // Time to reverse the MTF on the selectors that we received
             // Create an index vec for the number of tables we need
-            let mut table_idx: Vec<usize> = (0..table_count as usize).collect();
+            let mut table_idx: Vec<usize> = (0..table_count).collect();
 
             // Iterate through the input
             for (i, &selector) in raw_selector_map.iter().enumerate() {
@@ -208,172 +319,132 @@ pub fn decompress(opts: &BzOpts) -> io::Result<()> {
             );
         }
 
-        // Read the Huffman symbol lengths and create decode maps which have decoding info
-        //  and a level-specific vec of the symbols.
-        let mut huf_decode_maps: Vec<(Vec<Level>, Vec<u16>)> =
-            vec![(Vec::new(), Vec::with_capacity(symbols)); table_count];
-
-        for huffman_decode_map in huf_decode_maps.iter_mut().take(table_count) {
+        // Read the Huffman symbol lengths and create decode tables for each huffman table.
+        // Tables left from earlier blocks are rebuilt in place.
+        for table_index in 0..table_count {
             // Tracing info
             let mark_loc = br.loc();
 
             // Create a temporary vec for the next map
-            let mut map: Vec<(u16, u32)> = vec![(0_u16, 0_u32); symbols + 1];
+            let map = &mut ctx.code_lengths;
+            map.clear();
+            map.resize(symbols + 1, (0_u16, 0_u32));
             // Read the origin length - five bits long
-            let mut l: i32 = br.bint(5).expect(EOF_MESSAGE) as i32;
+            let mut l: i32 = br.bint(5).ok_or(EOF)? as i32;
             // For each known symbol at this level (including a repeat of the origin we just read)
             // calculate the symbol length based on the relative bit length from the base symbol we just read.
             for symbol in 0..symbols as u16 + 1 {
-                let mut diff: i32 = 0;
-                // Look for offset pairs
-                while br.bool_bit().expect(EOF_MESSAGE) {
-                    // Get the second bit. If it is a 1, subract 1 from diff. Otherwise add one to diff.
-                    if br.bool_bit().expect(EOF_MESSAGE) {
-                        diff -= 1 // Found "11" - subtract 1
+                // Look for offset pairs. Like Julian, the length must stay within 1-20 at every step.
+                loop {
+                    if !(1..=MAX_CODE_LEN as i32).contains(&l) {
+                        error!("Symbol length of {} is invalid for sym {} in table {}", l, symbol, table_index);
+                        return Err(DecodeError::BadCodeLength(l).into());
+                    }
+                    if !br.bool_bit().ok_or(EOF)? {
+                        break;
+                    }
+                    // Get the second bit. If it is a 1, subract 1 from the length. Otherwise add one to it.
+                    if br.bool_bit().ok_or(EOF)? {
+                        l -= 1 // Found "11" - subtract 1
                     } else {
-                        diff += 1 // Found "10" - add 1
+                        l += 1 // Found "10" - add 1
                     }
                 }
-                // No more offsets. Calculate the total offset and map the symbol.
-                map[symbol as usize] = (symbol, (l + diff) as u32);
-                if l + diff > 17 {
-                    error!(
-                        "Symbol length of {} exceeds max for sym {} in table {}",
-                        l + diff,
-                        symbol,
-                        table_count
-                    );
-                }
-                // The next code is calculated offset from the length of the symbol we just decoded.
-                l += diff;
+                // No more offsets. Map the symbol. The next code is offset from the length of this one.
+                map[symbol as usize] = (symbol, l as u32);
             }
 
             // Maps must be sorted by length for the next step.
-            map.sort_by(|a, b| a.1.cmp(&b.1));
+            map.sort_by_key(|a| a.1);
 
-            // Build the decode map and store it along with the symbol list for decoding this map.
-            *huffman_decode_map = (
-                huf_decode_map(&map),
-                map.iter().map(|(s, _)| *s).collect::<Vec<u16>>(),
-            );
+            // Build the decode table (lookup table plus level info for long codes) and store it.
+            // A table whose codes don't fit is an error.
+            match ctx.tables.get_mut(table_index) {
+                Some(table) => table.rebuild(map),
+                None => HufDecodeTable::new(map).map(|table| ctx.tables.push(table)),
+            }
+            .inspect_err(|e| error!("Huffman table {} of block {} is invalid: {}", table_index, block_counter, e))?;
             trace!("\rFound huffman maps at {}.  ", mark_loc);
         }
 
-        // We are now ready to read the data and decode it.
-        // Set aside a output vec to store the data we decode (size based on the table count)
-        let mut out = vec![
-            0_u16;
-            match table_count {
-                2 => 200,
-                3 => 600,
-                4 => 1200,
-                5 => 2400,
-                _ => (block_size as usize * 100000) + 19,
-            }
-        ];
+        // We are now ready to read the data and decode it. Each symbol goes straight through the RLE2 and MTF
+        // decoding as it comes off the bitstream. The block can't decode to more than the block size.
+        let mut decoder = Rle2MtfDecoder::new(&symbol_set, &mut ctx.mtf_out, block_size as usize * 100000);
 
         // Now read the input block in chunks of 50 symbols using the huffman map for that chunk indicated by the selector map
         {
             // Isolate temporary variable in this block.
             // Initialize key variables
             let mut block_index = 0;
-            //let mut bit_count: u32 = 0;
-            let mut code = 0_u32;
-            let mut depth = 0;
             // Set the eob symbol.
             let eob = symbols as u16;
 
-            // Get references to the current level variables and symbol set.
-            //   Too bad we have to do a "double" assignment here and about line 375.
-            let (l, s) = &huf_decode_maps[selector_map[block_index]];
-            let mut level = l;
-            let mut symbol_index = s;
+            // Get a reference to the decode table for the first chunk.
+            let mut table = &ctx.tables[selector_map[block_index]];
 
-            // Loop through the data in chunks trying to find valid symbols in the bit stream
+            // Loop through the data in chunks decoding symbols from the bit stream
             loop {
-                // Left shift any code bits we are currently holding so we can add in the next level of bits
-                code <<= level[depth].bits;
-
-                // Get the required bits at this level depth and add them to our code
-                code |= br.bint(level[depth].bits as usize).expect(EOF_MESSAGE) as u32;
-
-                // If the code is bigger than the end code at this level, try the next level
-                if code >= level[depth].end_code {
-                    depth += 1;
-                    continue;
-                } else {
-                    // We found a code in this level. Calculate the offset and grab the symbol
-                    let sym = symbol_index
-                        [(level[depth].offset + code - level[depth].start_code) as usize];
-
-                    // Put it into the output vec.
-                    out[block_index] = sym;
-                    let bitlength = (0..=depth).map(|i| level[i].bits).sum::<u32>() as usize;
-                    trace!(
-                        "\r\x1b[43m{:>6}: {:>3}  {:0bitlength$b}  {} \x1b[0m",
-                        block_index,
-                        sym,
-                        code,
-                        br.loc()
-                    );
-
-                    // Check if we have reached the end of block
-                    if sym == eob {
-                        // If we are, check if we are at the end of the block too early
-                        if block_index / CHUNK_SIZE < selector_count as usize - 1 {
-                            error!("Found EOB before working through all selectors. (Chunk {} instead of {}.)", block_index/50, selector_count);
-                            return Err(Error::new(
-                                io::ErrorKind::Other,
-                                "Found end of block too early",
-                            ));
-                        }
-                        // Adjust the vec length to the block_index plus 1
-                        out.truncate(block_index + 1);
-                        // All done.
-                        break;
+                // Most symbols are resolved with a single lookup. Longer codes are handled inside decode().
+                let sym = table.decode(br).ok_or(DecodeError::BadCode)?;
+                trace!(
+                    "\r\x1b[43m{:>6}: {:>3}  {} \x1b[0m",
+                    block_index,
+                    sym,
+                    br.loc()
+                );
+
+                // Check if we have reached the end of block
+                if sym == eob {
+                    // If we are, check if we are at the end of the block too early
+                    if block_index / CHUNK_SIZE < selector_count - 1 {
+                        error!("Found EOB before working through all selectors. (Chunk {} instead of {}.)", block_index/50, selector_count);
+                        return Err(DecodeError::EarlyEndOfBlock.into());
                     }
+                    // All done.
+                    break;
+                }
 
-                    // Update the block index
-                    block_index += 1;
-
-                    // Update the level variables if we are starting a new chunk.
-                    if block_index % CHUNK_SIZE == 0 {
-                        // Make sure we don't exceed the number of selectors
-                        if block_index / CHUNK_SIZE == selector_count as usize {
-                            error!("Did not find EOB while working through final chunk.");
-                            return Err(Error::new(
-                                io::ErrorKind::Other,
-                                "Did not find end of block",
-                            ));
-                        }
-                        let (l, s) = &huf_decode_maps[selector_map[block_index / 50]];
-                        level = l;
-                        symbol_index = s;
-                    }
+                // Undo the RLE2 and MTF for this symbol
+                if !decoder.push(sym) {
+                    error!("Block {} decodes to more than the block size.", block_counter);
+                    return Err(DecodeError::BlockTooLarge.into());
+                }
+
+                // Update the block index
+                block_index += 1;
 
-                    // Reset the depth index and code before looking for the next symbol.
-                    depth = 0;
-                    code = 0;
+                // Switch to the next decode table if we are starting a new chunk.
+                if block_index % CHUNK_SIZE == 0 {
+                    // Make sure we don't exceed the number of selectors
+                    if block_index / CHUNK_SIZE == selector_count {
+                        error!("Did not find EOB while working through final chunk.");
+                        return Err(DecodeError::MissingEndOfBlock.into());
+                    }
+                    table = &ctx.tables[selector_map[block_index / CHUNK_SIZE]];
                 }
             }
         }
+        let Some(freq) = decoder.finish() else {
+            error!("Block {} decodes to more than the block size.", block_counter);
+            return Err(DecodeError::BlockTooLarge.into());
+        };
 
-        // Undo the RLE2 and MTF, converting to u8 in the process
-        // Set aside a vec to store the data we decode (size based on the block size)
-        let size = block_size as usize * 100000;
-
-        let (mtf_out, freq) = rle2_mtf_decode_fast(&out, &mut symbol_set, size);
+        // The key must point into the block
+        if key >= ctx.mtf_out.len() {
+            error!("Invalid key pointer");
+            return Err(DecodeError::BadKey(key).into());
+        }
 
-        // Undo the BWTransform
-        let bwt_v = bwt_decode(key as u32, &mtf_out, &freq);
-        trace!("{:?}", String::from_utf8(bwt_v.clone()));
-        
-        // Undo the initial RLE1
-        let rle1_v = rle1_decode(&bwt_v);
-        trace!("{:?}", String::from_utf8(rle1_v.clone()));
+        // Undo the BWTransform and the initial RLE1, computing the CRC as we go. The block is only decoded as far as
+        // the limits allow.
+        ctx.block_out.clear();
+        let (budget, limit) = tracker.block_budget(br.bit_position() / 8);
+        let this_block_crc = bwt_rle1_decode(key as u32, &ctx.mtf_out, &freq, &mut ctx.t_vec, &mut ctx.block_out, budget)
+            .ok_or_else(|| limit_error(limit))?;
+        tracker.add_output(ctx.block_out.len());
+        trace!("{:?}", String::from_utf8_lossy(&ctx.block_out));
 
-        // Compute and check the CRCs
-        let this_block_crc = do_crc(0, &rle1_v);
+        // Check the CRCs
         stream_crc = do_stream_crc(stream_crc, this_block_crc);
 
         if block_crc == this_block_crc as usize {
@@ -383,104 +454,241 @@ pub fn decompress(opts: &BzOpts) -> io::Result<()> {
                 "Block {} CRC failed!!! Found {} looking for {}.",
                 block_counter, this_block_crc, block_crc
             );
+            return Err(DecodeError::BadBlockCrc.into());
         }
 
         // Done!! Write the data.
-        let result = f_out.write(&rle1_v);
-        info!("Wrote a block of data with {} bytes.", result.unwrap());
+        out.write_all(&ctx.block_out)?;
+        info!("Wrote a block of data with {} bytes.", ctx.block_out.len());
     }
 
-    let final_crc = br.bint(32).expect(EOF_MESSAGE);
+    let final_crc = br.bint(32).ok_or(EOF)?;
     if final_crc == stream_crc as usize {
         info!("Stream CRCs matched: {}.", final_crc);
     } else {
-        // This should never happen unless a block CRC also failed - or unless there is a missing block.
+        // The block CRCs all matched, so a block is missing or the footer is corrupt.
         error!(
             "Stream CRC failed!!! Found {} looking for {}. (Data may be corrupt.)",
             stream_crc, final_crc
         );
+        return Err(DecodeError::BadStreamCrc.into());
     }
     Result::Ok(())
 }
 
-#[derive(Debug, Clone)]
-struct Level {
-    bits: u32,
-    offset: u32,
-    start_code: u32,
-    end_code: u32,
-}
-impl Level {
-    fn new() -> Self {
-        Self {
-            bits: 0,
-            offset: 0,
-            start_code: 0,
-            end_code: 0,
-        }
-    }
-}
 
-/// Decode a vec of symbols and lengths into the level structure needed to efficiently
-/// decode the bit stream.
-fn huf_decode_map(map: &[(u16, u32)]) -> Vec<Level> {
-    // Initialize result vector
-    let mut result = Vec::new();
+#[cfg(test)]
+mod test {
+    use super::{decode_streams, read_stream_header};
+    use crate::bitstream::{bitpacker::BitPacker, bitreader::BitReader};
+    use crate::compression::{
+        context::DecoderContext,
+        decode_error::DecodeError,
+        decode_limits::{DecodeLimits, Limit},
+    };
 
-    // Current_length is the number of bits sured for the code length at this level
-    let mut current_bit_length = map[0].1;
+    /// Decode a stream in memory, returning the data or the DecodeError found.
+    fn decode(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        decode_limited(data, DecodeLimits::new())
+    }
 
-    // Bits_to_add is the number of bits we need to check codes at this level. (First time
-    // it is also the bit length of the code)
-    let mut bits_to_add = current_bit_length;
+    /// Decode a stream in memory within limits, returning the data or the DecodeError found.
+    fn decode_limited(data: &[u8], limits: DecodeLimits) -> Result<Vec<u8>, DecodeError> {
+        let mut out = vec![];
+        match DecoderContext::with_limits(limits).decompress(data, &mut out) {
+            Ok(()) => Ok(out),
+            Err(e) => Err(*DecodeError::from_io(&e).expect("Decoding errors must be DecodeErrors")),
+        }
+    }
 
-    // Current_code is the starting code at this level
-    let mut current_code = 0;
+    /// Build a stream holding the start of one block, up to and including its huffman tables. Each table gets the
+    /// same code lengths.
+    fn block_start(sym_map: &[u16], table_count: u64, selectors: &[usize], lengths: &[u32]) -> Vec<u8> {
+        let mut bp = BitPacker::new(100);
+        bp.put_bits(u32::from_be_bytes(*b"BZh9") as u64, 32);
+        bp.put_bits(0x3141_5926_5359, 48);
+        // Block crc, randomized bit and key
+        bp.put_bits(0, 32);
+        bp.put_bits(0, 25);
+        for &word in sym_map {
+            bp.put_bits(word as u64, 16);
+        }
+        bp.put_bits(table_count, 3);
+        bp.put_bits(selectors.len() as u64, 15);
+        for &selector in selectors {
+            bp.put_bits((1 << (selector + 1)) - 2, selector as u8 + 1);
+        }
+        for _ in 0..table_count {
+            let mut origin = lengths[0];
+            bp.put_bits(origin as u64, 5);
+            for &len in lengths {
+                while origin < len {
+                    bp.put_bits(0b10, 2);
+                    origin += 1;
+                }
+                while origin > len {
+                    bp.put_bits(0b11, 2);
+                    origin -= 1;
+                }
+                bp.put_bits(0, 1);
+            }
+        }
+        bp.flush();
+        bp.output
+    }
 
-    // Set symbol count variables
-    let mut count = 0_u32;
-    let mut last_count = count;
+    #[test]
+    fn error_kinds_test() {
+        assert_eq!(decode(b"BZ"), Err(DecodeError::BadSignature));
+        assert_eq!(decode(b"ZZh9"), Err(DecodeError::BadSignature));
+        assert_eq!(decode(b"BZh0"), Err(DecodeError::BadBlockSize(0)));
+        assert_eq!(decode(b"BZh"), Err(DecodeError::UnexpectedEof));
+        assert_eq!(decode(b"BZh9\x31\x41"), Err(DecodeError::UnexpectedEof));
+        assert_eq!(decode(b"BZh9\x31\x41\x59\x26\x53\x58"), Err(DecodeError::BadBlockHeader));
+
+        // One byte value in use, so 3 symbols: RUNA, RUNB and EOB
+        let sym_map = [0x8000, 0x8000];
+        // The block data is missing, so a valid start ends in the data
+        assert_eq!(decode(&block_start(&sym_map, 2, &[0, 1], &[1, 2, 2])), Err(DecodeError::BadCode));
+        assert_eq!(decode(&block_start(&[0x8000, 0], 2, &[0], &[1, 2, 2])), Err(DecodeError::BadSymbolMap));
+        assert_eq!(decode(&block_start(&[0], 2, &[0], &[1, 2, 2])), Err(DecodeError::BadSymbolMap));
+        assert_eq!(decode(&block_start(&sym_map, 1, &[0], &[1, 2, 2])), Err(DecodeError::BadTableCount(1)));
+        assert_eq!(decode(&block_start(&sym_map, 7, &[0], &[1, 2, 2])), Err(DecodeError::BadTableCount(7)));
+        assert_eq!(decode(&block_start(&sym_map, 2, &[], &[1, 2, 2])), Err(DecodeError::NoSelectors));
+        assert_eq!(decode(&block_start(&sym_map, 2, &[0, 2], &[1, 2, 2])), Err(DecodeError::BadSelector(2)));
+        assert_eq!(decode(&block_start(&sym_map, 3, &[2, 1], &[1, 2, 2])), Err(DecodeError::BadCode));
+        assert_eq!(decode(&block_start(&sym_map, 2, &[0], &[1, 1, 1])), Err(DecodeError::OversubscribedTable));
+        assert_eq!(decode(&block_start(&sym_map, 2, &[0], &[0, 1, 1])), Err(DecodeError::BadCodeLength(0)));
+        assert_eq!(decode(&block_start(&sym_map, 2, &[0], &[21, 1, 1])), Err(DecodeError::BadCodeLength(21)));
+        // Lengths must stay within 1-20 between symbols too
+        assert_eq!(decode(&block_start(&sym_map, 2, &[0], &[20, 1, 21])), Err(DecodeError::BadCodeLength(21)));
+        // An incomplete code and the longest codes are accepted
+        assert_eq!(decode(&block_start(&sym_map, 2, &[0], &[2, 2, 2])), Err(DecodeError::BadCode));
+        assert_eq!(decode(&block_start(&sym_map, 2, &[0], &[1, 20, 20])), Err(DecodeError::BadCode));
+    }
 
-    // For each bit level (number of bits in the code), get the symbol list
-    for (_symbol, bit_length) in map.iter() {
-        count += 1;
-        if *bit_length == current_bit_length {
-            continue;
-        } else {
-            // Done at this level. Record the level.
-            let mut level = Level::new();
+    #[test]
+    fn truncated_stream_test() {
+        for stream in [&include_bytes!("../../tests/corpus/hello.bz2")[..], include_bytes!("../../tests/corpus/runs.bz2")] {
+            assert!(decode(stream).is_ok());
+            for len in 0..stream.len() {
+                assert!(decode(&stream[..len]).is_err(), "A stream cut to {} bytes decoded", len);
+            }
+        }
+    }
 
-            level.bits = bits_to_add;
-            level.offset = last_count;
-            level.start_code = current_code;
-            level.end_code = (current_code + count - 1) as u32;
-            result.push(level);
+    /// A reader that returns its data, then fails.
+    struct Broken<'a>(&'a [u8]);
+    impl std::io::Read for Broken<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(std::io::Error::other("device error"));
+            }
+            self.0.read(buf)
+        }
+    }
 
-            // Calculate the number of bits needed to get to the next level
-            bits_to_add = bit_length - current_bit_length;
+    #[test]
+    fn read_error_test() {
+        // A read error part way through is returned as it is, not as a truncated stream
+        let stream = include_bytes!("../../tests/corpus/runs.bz2");
+        let mut br = BitReader::new(Broken(&stream[..stream.len() / 2]));
+        let block_size = read_stream_header(&mut br).unwrap();
+        let mut out = vec![];
+        let error = decode_streams(&mut DecoderContext::new(), &mut br, block_size, DecodeLimits::new(), &mut out)
+            .unwrap_err();
+        assert!(DecodeError::from_io(&error).is_none());
+        assert_eq!(error.to_string(), "device error");
+    }
 
-            // Update current_code for the next iteration before we change count
-            current_code = (current_code + count - 1) << bits_to_add;
+    #[test]
+    fn corrupt_stream_test() {
+        // Any bit flipped must give the original data or an error, never a panic or wrong data. The checks guard the
+        // headers, selectors and tables, so only the start of the larger stream is corrupted, and the CRCs catch
+        // the rest.
+        let streams = [&include_bytes!("../../tests/corpus/hello.bz2")[..], include_bytes!("../../tests/corpus/runs.bz2")];
+        for (stream, checked) in streams.into_iter().zip([52, 160]) {
+            let expected = decode(stream).unwrap();
+            let mut corrupt = stream.to_vec();
+            for bit in 0..checked * 8 {
+                corrupt[bit / 8] ^= 0x80 >> (bit % 8);
+                if let Ok(data) = decode(&corrupt) {
+                    assert!(data == expected, "Flipping bit {} gave different data", bit);
+                }
+                corrupt[bit / 8] ^= 0x80 >> (bit % 8);
+            }
+        }
+    }
 
-            // Update last_count for the next iteration
-            last_count += count - 1;
+    #[test]
+    fn crc_test() {
+        // hello.bz2 is one block: the stream header (4 bytes), block magic (6), block CRC (4) and so on. The stream
+        // CRC is not byte aligned, and is followed by 0-7 bits of padding, so the byte before the last is inside it.
+        let hello = include_bytes!("../../tests/corpus/hello.bz2");
+        let mut corrupt = hello.to_vec();
+        corrupt[10] ^= 1;
+        assert_eq!(decode(&corrupt), Err(DecodeError::BadBlockCrc));
+        let mut corrupt = hello.to_vec();
+        corrupt[hello.len() - 2] ^= 1;
+        assert_eq!(decode(&corrupt), Err(DecodeError::BadStreamCrc));
+    }
 
-            // Reset count to 1 (because we counted one already)
-            count = 1;
+    #[test]
+    fn concatenated_streams_test() {
+        let hello = include_bytes!("../../tests/corpus/hello.bz2");
+        let zeros = include_bytes!("../../tests/corpus/zeros.bz2");
+        let mut data = [&hello[..], zeros, hello].concat();
+        let mut expected = decode(hello).unwrap();
+        expected.extend(vec![0; 300_000]);
+        expected.extend(decode(hello).unwrap());
+        assert!(decode(&data).unwrap() == expected);
+
+        // A stream cut short is an error
+        data.extend(b"BZh");
+        assert_eq!(decode(&data), Err(DecodeError::UnexpectedEof));
+        data.extend(&hello[3..20]);
+        assert_eq!(decode(&data), Err(DecodeError::UnexpectedEof));
+    }
 
-            // Set current_length for the next level
-            current_bit_length = *bit_length;
+    #[test]
+    fn trailing_garbage_test() {
+        // Anything after the last stream that is not a stream is an error, even a single byte
+        let hello = include_bytes!("../../tests/corpus/hello.bz2");
+        for garbage in [&b"\n"[..], b"BZ", b"garbage after the stream", &[0; 100]] {
+            let data = [&hello[..], garbage].concat();
+            assert_eq!(decode(&data), Err(DecodeError::TrailingGarbage), "{:?}", garbage);
         }
     }
-    // Done at the last level. Record the level information.
-    let mut level = Level::new();
-    //let symbol = map[map.len() - 1].0;
-
-    level.bits = bits_to_add;
-    level.offset = last_count;
-    level.start_code = current_code;
-    level.end_code = current_code + count as u32;
-    result.push(level);
 
-    result
+    #[test]
+    fn limits_test() {
+        let zeros = include_bytes!("../../tests/corpus/zeros.bz2");
+        let words = include_bytes!("../../tests/corpus/words.bz2");
+        let limited = |data: &[u8], limits| decode_limited(data, limits).err();
+        let exceeded = |limit| Some(DecodeError::LimitExceeded(limit));
+
+        // 47 bytes decode to 300k zeros
+        let output = |max| DecodeLimits { max_output: Some(max), ..DecodeLimits::new() };
+        assert_eq!(limited(zeros, output(300_000)), None);
+        assert_eq!(limited(zeros, output(299_999)), exceeded(Limit::Output));
+        let ratio = |max| DecodeLimits { max_ratio: Some(max), ..DecodeLimits::new() };
+        assert_eq!(limited(zeros, ratio(10_000)), None);
+        assert_eq!(limited(zeros, ratio(1_000)), exceeded(Limit::Ratio));
+        // Block size 9 needs 4.5M bytes of buffers before the 300k byte block
+        let memory = |max| DecodeLimits { max_memory: Some(max), ..DecodeLimits::new() };
+        assert_eq!(limited(zeros, memory(4_800_000)), None);
+        assert_eq!(limited(zeros, memory(4_700_000)), exceeded(Limit::Memory));
+        assert_eq!(limited(zeros, memory(4_400_000)), exceeded(Limit::Memory));
+
+        // words.bz2 has three blocks of 100k
+        let blocks = |max| DecodeLimits { max_blocks: Some(max), ..DecodeLimits::new() };
+        assert_eq!(limited(words, blocks(3)), None);
+        assert_eq!(limited(words, blocks(2)), exceeded(Limit::Blocks));
+        let streams = |max| DecodeLimits { max_streams: Some(max), ..DecodeLimits::new() };
+        let two = [&zeros[..], zeros].concat();
+        assert_eq!(limited(&two, streams(2)), None);
+        assert_eq!(limited(&two, streams(1)), exceeded(Limit::Streams));
+        // The output limit counts every stream
+        assert_eq!(limited(&two, output(599_999)), exceeded(Limit::Output));
+    }
 }
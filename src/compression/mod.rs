@@ -23,4 +23,7 @@
 
 pub mod compress;
 pub mod compress_block;
+pub mod context;
+pub mod decode_error;
+pub mod decode_limits;
 pub mod decompress;
\ No newline at end of file
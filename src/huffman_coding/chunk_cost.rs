@@ -0,0 +1,207 @@
+//! Find the cheapest coding table for each 50 symbol chunk.
+//!
+//! The encoder costs every chunk against every table on each pass, which is table_count adds per symbol. Julian packs
+//! the costs of two tables into each 32 bit word, so it takes three adds. Here the code lengths of a symbol in all
+//! six tables are packed into the 16 bit lanes of one 128 bit word (PackedTables), so one add costs a symbol in every
+//! table. A chunk costs at most 50 * 20 bits in each table, so a lane never carries into the next.
+//!
+//! On x86_64 the lanes are added with SSE2, which every x86_64 CPU has. Elsewhere a u128 add gives the same result,
+//! since the lanes never carry.
+//!
+//! Large blocks are costed on several threads, a group of chunks at a time. The counts from each group are added
+//! together, so the result is the same on any number of threads.
+//!
+use rayon::prelude::*;
+
+/// Count of symbols in a chunk.
+const CHUNK_SIZE: usize = 50;
+/// Highest cost of a symbol that keeps the lanes of a full chunk from carrying.
+const MAX_SYMBOL_COST: u32 = u16::MAX as u32 / CHUNK_SIZE as u32;
+/// Count of chunks costed together on one thread.
+const GROUP_CHUNKS: usize = 256;
+/// Blocks with fewer symbols than this are costed on the calling thread.
+const PARALLEL_MIN_SYMBOLS: usize = 4 * GROUP_CHUNKS * CHUNK_SIZE;
+
+/// The code lengths of each symbol in up to six tables, packed into the 16 bit lanes of a u128 (table t in lane t).
+pub(crate) struct PackedTables {
+    lanes: [u128; 258],
+    table_count: usize,
+}
+
+impl PackedTables {
+    /// Pack the first table_count tables.
+    pub(crate) fn new(tables: &[[u32; 258]; 6], table_count: usize) -> Self {
+        let mut lanes = [0_u128; 258];
+        for (t, table) in tables.iter().enumerate().take(table_count) {
+            for (lane, &len) in lanes.iter_mut().zip(table) {
+                debug_assert!(len <= MAX_SYMBOL_COST, "Code length {} is too long to pack", len);
+                *lane |= (len as u128) << (16 * t);
+            }
+        }
+        Self { lanes, table_count }
+    }
+
+    /// Return the cost of chunk (at most 50 symbols) in each table, packed as the tables are.
+    #[inline]
+    pub(crate) fn chunk_cost(&self, chunk: &[u16]) -> u128 {
+        debug_assert!(chunk.len() <= CHUNK_SIZE);
+        #[cfg(target_arch = "x86_64")]
+        return sse2::chunk_cost(&self.lanes, chunk);
+        #[cfg(not(target_arch = "x86_64"))]
+        return portable_chunk_cost(&self.lanes, chunk);
+    }
+
+    /// Return the cheapest table for chunk (the first if several cost the same) and its cost in bits.
+    #[inline]
+    pub(crate) fn best_table(&self, chunk: &[u16]) -> (usize, u32) {
+        let cost = self.chunk_cost(chunk);
+        let lane = |t: usize| (cost >> (16 * t)) as u16;
+        let mut best = 0;
+        for t in 1..self.table_count {
+            if lane(t) < lane(best) {
+                best = t;
+            }
+        }
+        (best, lane(best) as u32)
+    }
+}
+
+/// Add the lanes of the symbols of chunk with u128 adds.
+#[cfg_attr(target_arch = "x86_64", allow(dead_code))]
+fn portable_chunk_cost(lanes: &[u128; 258], chunk: &[u16]) -> u128 {
+    chunk.iter().fold(0, |cost, &symbol| cost + lanes[symbol as usize])
+}
+
+#[cfg(target_arch = "x86_64")]
+mod sse2 {
+    use std::arch::x86_64::{__m128i, _mm_add_epi16, _mm_loadu_si128, _mm_setzero_si128};
+
+    /// Add up the lanes of each symbol of chunk in turn. One add covers all eight u16 lanes, one lane per table.
+    #[inline]
+    pub(super) fn chunk_cost(lanes: &[u128; 258], chunk: &[u16]) -> u128 {
+        // Safety: SSE2 is part of the x86_64 baseline, and each load reads one whole u128 of lanes.
+        unsafe {
+            let mut cost = _mm_setzero_si128();
+            for &symbol in chunk {
+                let lane = &lanes[symbol as usize] as *const u128 as *const __m128i;
+                cost = _mm_add_epi16(cost, _mm_loadu_si128(lane));
+            }
+            std::mem::transmute::<__m128i, u128>(cost)
+        }
+    }
+}
+
+/// What one pass over the chunks found.
+pub(crate) struct PassStats {
+    /// Count of each symbol in the chunks that chose each table.
+    pub(crate) rfreq: [[u32; 258]; 6],
+    /// Count of chunks that chose each table.
+    pub(crate) favorites: [usize; 6],
+    /// Cost in bits of the whole block with the chosen tables.
+    pub(crate) total_cost: u64,
+}
+
+impl PassStats {
+    fn new() -> Self {
+        Self {
+            rfreq: [[0; 258]; 6],
+            favorites: [0; 6],
+            total_cost: 0,
+        }
+    }
+
+    /// Add the counts of other to these.
+    fn merge(mut self, other: Self) -> Self {
+        for (rfreq, other_rfreq) in self.rfreq.iter_mut().zip(&other.rfreq) {
+            rfreq.iter_mut().zip(other_rfreq).for_each(|(a, b)| *a += b);
+        }
+        self.favorites.iter_mut().zip(other.favorites).for_each(|(a, b)| *a += b);
+        self.total_cost += other.total_cost;
+        self
+    }
+}
+
+/// Choose the cheapest table for each 50 symbol chunk of rle2, putting it in selectors (one per chunk), and count
+/// the symbols coded by each table. Large blocks are done on several threads.
+pub(crate) fn choose_tables(rle2: &[u16], tables: &PackedTables, selectors: &mut [usize]) -> PassStats {
+    debug_assert_eq!(selectors.len(), rle2.len().div_ceil(CHUNK_SIZE));
+    // Only look at the thread count for large blocks, so small inputs don't start the thread pool
+    if rle2.len() >= PARALLEL_MIN_SYMBOLS && rayon::current_num_threads() > 1 {
+        rle2.par_chunks(GROUP_CHUNKS * CHUNK_SIZE)
+            .zip(selectors.par_chunks_mut(GROUP_CHUNKS))
+            .map(|(group, group_selectors)| choose_group(group, tables, group_selectors))
+            .reduce(PassStats::new, PassStats::merge)
+    } else {
+        choose_group(rle2, tables, selectors)
+    }
+}
+
+/// Choose the tables for the chunks of rle2 on this thread.
+fn choose_group(rle2: &[u16], tables: &PackedTables, selectors: &mut [usize]) -> PassStats {
+    let mut stats = PassStats::new();
+    for (chunk, selector) in rle2.chunks(CHUNK_SIZE).zip(selectors.iter_mut()) {
+        let (bt, cost) = tables.best_table(chunk);
+        *selector = bt;
+        stats.favorites[bt] += 1;
+        stats.total_cost += cost as u64;
+        // Count the symbols of the chunk against the table chosen, to improve the table for the next pass
+        let rfreq = &mut stats.rfreq[bt];
+        chunk.iter().for_each(|&symbol| rfreq[symbol as usize] += 1);
+    }
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::{choose_tables, portable_chunk_cost, PackedTables};
+
+    /// Tables of pseudo random code lengths (1-20) and symbols to cost against them.
+    fn test_data(symbols: usize) -> ([[u32; 258]; 6], Vec<u16>) {
+        let mut seed = 11_u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            seed >> 16
+        };
+        let mut tables = [[0; 258]; 6];
+        tables.iter_mut().flatten().for_each(|len| *len = 1 + next() % 20);
+        let rle2 = (0..symbols).map(|_| (next() % 258) as u16).collect();
+        (tables, rle2)
+    }
+
+    #[test]
+    fn chunk_cost_test() {
+        let (tables, rle2) = test_data(5_000);
+        for table_count in 2..=6 {
+            let packed = PackedTables::new(&tables, table_count);
+            for chunk in rle2.chunks(50) {
+                let cost = packed.chunk_cost(chunk);
+                assert_eq!(cost, portable_chunk_cost(&packed.lanes, chunk));
+                // Each lane holds the sum a table at a time would give, and the first of the cheapest tables wins
+                let costs = (0..table_count)
+                    .map(|t| chunk.iter().map(|&s| tables[t][s as usize]).sum::<u32>())
+                    .collect::<Vec<_>>();
+                for (t, &c) in costs.iter().enumerate() {
+                    assert_eq!((cost >> (16 * t)) as u16 as u32, c);
+                }
+                let best = (0..table_count).min_by_key(|&t| costs[t]).unwrap();
+                assert_eq!(packed.best_table(chunk), (best, costs[best]));
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_choice_test() {
+        // The threaded pass must choose and count exactly what the single threaded pass does
+        let (tables, rle2) = test_data(200_003);
+        let packed = PackedTables::new(&tables, 6);
+        let mut results = vec![];
+        for threads in [1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let mut selectors = vec![0; rle2.len().div_ceil(50)];
+            let stats = pool.install(|| choose_tables(&rle2, &packed, &mut selectors));
+            results.push((selectors, stats.rfreq, stats.favorites, stats.total_cost));
+        }
+        assert!(results[0] == results[1]);
+        assert_eq!(results[0].2.iter().sum::<usize>(), rle2.len().div_ceil(50));
+    }
+}
@@ -0,0 +1,280 @@
+//! Table-driven huffman decoding for the Rust version of the standard BZIP2 library.
+//!
+//! Each of the 2-6 huffman tables in a block is converted into a lookup table before the block data is decoded.
+//! The decoder peeks LOOKUP_BITS bits from the BitReader and uses them as an index into the lookup table. Every code
+//! that is LOOKUP_BITS long or shorter appears in the table (repeated for every possible value of the bits that
+//! follow it), so most symbols are resolved with a single table hit.
+//!
+//! The few codes that are longer than LOOKUP_BITS fall back to a walk through the code length levels, which is
+//! how all symbols were decoded before the lookup table was added.
+//!
+//! Lookup table entries hold the code length in the upper bits and the symbol in the lower 9 bits. An entry of
+//! zero means the code is longer than LOOKUP_BITS.
+//!
+//! A table is only built from code lengths of 1-20 whose codes fit (the Kraft sum is at most 1), so a corrupt stream
+//! can't make the decoder index outside the table. An incomplete set of codes is accepted, as the C version accepts
+//! it. The bit patterns left over decode as None.
+//!
+use crate::bitstream::{bitreader::BitReader, byte_source::ByteSource};
+use crate::compression::decode_error::DecodeError;
+
+/// Number of bits used to index the lookup table. Most bzip2 codes are shorter than this.
+pub const LOOKUP_BITS: u32 = 10;
+/// Longest code the C version decodes.
+pub const MAX_CODE_LEN: u32 = 20;
+/// Most symbols in a table: RUNA, RUNB, the MTF positions 1-255 and EOB.
+const MAX_SYMBOLS: usize = 258;
+/// Symbols (0-257) fit in the lower 9 bits of a lookup table entry.
+const SYMBOL_MASK: u16 = 0x1ff;
+const LENGTH_SHIFT: u16 = 9;
+
+/// Decoding information for all codes of one bit length.
+#[derive(Debug, Clone)]
+struct Level {
+    bits: u32,
+    offset: u32,
+    start_code: u32,
+    end_code: u32,
+}
+impl Level {
+    fn new() -> Self {
+        Self {
+            bits: 0,
+            offset: 0,
+            start_code: 0,
+            end_code: 0,
+        }
+    }
+}
+
+/// Decoder for one huffman table. Built from the symbol lengths read from the stream.
+#[derive(Debug, Clone)]
+pub struct HufDecodeTable {
+    /// Lookup table indexed by the next LOOKUP_BITS bits of the stream.
+    lookup: Vec<u16>,
+    /// Level-by-level decoding information, used for codes longer than LOOKUP_BITS.
+    levels: Vec<Level>,
+    /// Symbols sorted by code length, indexed from the levels.
+    symbols: Vec<u16>,
+}
+
+impl HufDecodeTable {
+    /// Build a decode table from a vec of (symbol, code length) pairs which must be sorted by length.
+    pub fn new(map: &[(u16, u32)]) -> Result<Self, DecodeError> {
+        let mut table = Self {
+            lookup: vec![],
+            levels: vec![],
+            symbols: vec![],
+        };
+        table.rebuild(map)?;
+        Ok(table)
+    }
+
+    /// Rebuild this decode table for a new map, reusing its memory. The map must be sorted by length. If the map
+    /// can't be a huffman table the error is returned and the table is left as it was.
+    pub fn rebuild(&mut self, map: &[(u16, u32)]) -> Result<(), DecodeError> {
+        check_code_lengths(map)?;
+        let lookup = &mut self.lookup;
+        lookup.clear();
+        lookup.resize(1 << LOOKUP_BITS, 0);
+
+        // Codes are assigned sequentially within each length, just as the encoder did.
+        let mut code = 0_u32;
+        let mut current_len = map[0].1;
+        for &(symbol, len) in map {
+            if len != current_len {
+                code <<= len - current_len;
+                current_len = len;
+            }
+            if len <= LOOKUP_BITS {
+                // Fill every entry that starts with this code.
+                let start = (code << (LOOKUP_BITS - len)) as usize;
+                let end = ((code + 1) << (LOOKUP_BITS - len)) as usize;
+                let entry = (len as u16) << LENGTH_SHIFT | symbol;
+                lookup[start..end].fill(entry);
+            }
+            code += 1;
+        }
+
+        huf_decode_map(map, &mut self.levels);
+        self.symbols.clear();
+        self.symbols.extend(map.iter().map(|(s, _)| *s));
+        Ok(())
+    }
+
+    /// Decode the next symbol from the bitstream. Returns None if we run out of data, or if the bits
+    /// don't match any code in the table.
+    #[inline(always)]
+    pub fn decode<S: ByteSource>(&self, br: &mut BitReader<S>) -> Option<u16> {
+        let entry = self.lookup[br.peek(LOOKUP_BITS) as usize];
+        if entry != 0 {
+            if br.consume((entry >> LENGTH_SHIFT) as u32) {
+                return Some(entry & SYMBOL_MASK);
+            }
+            return None;
+        }
+        self.decode_long(br)
+    }
+
+    /// Decode a code that is longer than LOOKUP_BITS by walking the levels.
+    fn decode_long<S: ByteSource>(&self, br: &mut BitReader<S>) -> Option<u16> {
+        let mut code = 0_u32;
+        for level in &self.levels {
+            // Left shift any code bits we are currently holding so we can add in the next level of bits
+            code <<= level.bits;
+            code |= br.bint(level.bits as usize)? as u32;
+            // If the code is less than the end code at this level, we found it
+            if code < level.end_code {
+                return self
+                    .symbols
+                    .get((level.offset + code - level.start_code) as usize)
+                    .copied();
+            }
+        }
+        None
+    }
+}
+
+/// Check that map holds 1-258 symbols (each below 258) with code lengths of 1-20, and that their codes fit.
+fn check_code_lengths(map: &[(u16, u32)]) -> Result<(), DecodeError> {
+    if map.is_empty() || map.len() > MAX_SYMBOLS || map.iter().any(|&(symbol, _)| symbol as usize >= MAX_SYMBOLS) {
+        return Err(DecodeError::BadSymbolCount(map.len()));
+    }
+    if let Some(&(_, len)) = map.iter().find(|(_, len)| !(1..=MAX_CODE_LEN).contains(len)) {
+        return Err(DecodeError::BadCodeLength(len as i32));
+    }
+    // Kraft: a code of len bits uses 2^(20 - len) of the 2^20 patterns of 20 bits
+    let used: u64 = map.iter().map(|&(_, len)| 1 << (MAX_CODE_LEN - len)).sum();
+    if used > 1 << MAX_CODE_LEN {
+        return Err(DecodeError::OversubscribedTable);
+    }
+    Ok(())
+}
+
+/// Decode a vec of symbols and lengths into the level structure needed to efficiently
+/// decode the bit stream. The levels replace the contents of result.
+fn huf_decode_map(map: &[(u16, u32)], result: &mut Vec<Level>) {
+    // Initialize result vector
+    result.clear();
+
+    // Current_length is the number of bits sured for the code length at this level
+    let mut current_bit_length = map[0].1;
+
+    // Bits_to_add is the number of bits we need to check codes at this level. (First time
+    // it is also the bit length of the code)
+    let mut bits_to_add = current_bit_length;
+
+    // Current_code is the starting code at this level
+    let mut current_code = 0;
+
+    // Set symbol count variables
+    let mut count = 0_u32;
+    let mut last_count = count;
+
+    // For each bit level (number of bits in the code), get the symbol list
+    for (_symbol, bit_length) in map.iter() {
+        count += 1;
+        if *bit_length == current_bit_length {
+            continue;
+        } else {
+            // Done at this level. Record the level.
+            let mut level = Level::new();
+
+            level.bits = bits_to_add;
+            level.offset = last_count;
+            level.start_code = current_code;
+            level.end_code = current_code + count - 1;
+            result.push(level);
+
+            // Calculate the number of bits needed to get to the next level
+            bits_to_add = bit_length - current_bit_length;
+
+            // Update current_code for the next iteration before we change count
+            current_code = (current_code + count - 1) << bits_to_add;
+
+            // Update last_count for the next iteration
+            last_count += count - 1;
+
+            // Reset count to 1 (because we counted one already)
+            count = 1;
+
+            // Set current_length for the next level
+            current_bit_length = *bit_length;
+        }
+    }
+    // Done at the last level. Record the level information.
+    let mut level = Level::new();
+    //let symbol = map[map.len() - 1].0;
+
+    level.bits = bits_to_add;
+    level.offset = last_count;
+    level.start_code = current_code;
+    level.end_code = current_code + count;
+    result.push(level);
+}
+
+#[cfg(test)]
+mod test {
+    use super::HufDecodeTable;
+    use crate::bitstream::{bitpacker::BitPacker, bitreader::BitReader};
+    use crate::compression::decode_error::DecodeError;
+
+    #[test]
+    fn short_and_long_codes_test() {
+        // Lengths 1, 2, 3 .. 12, 12 form a complete code. Codes over 10 bits use the slow path.
+        let mut map: Vec<(u16, u32)> = (0..12).map(|s| (s, s as u32 + 1)).collect();
+        map.push((12, 12));
+        let table = HufDecodeTable::new(&map).unwrap();
+
+        // Canonical codes: 0, 10, 110, ... 111111111110, 111111111111
+        let mut bp = BitPacker::new(100);
+        let message = [0_u16, 12, 3, 11, 1, 9, 10, 0];
+        for &sym in &message {
+            let len = map[sym as usize].1;
+            let code = if sym == 12 { 0xfff } else { (0xfff >> (12 - len)) - 1 };
+            bp.out24(len << 24 | code);
+        }
+        bp.flush();
+
+        let mut br = BitReader::new(bp.output.as_slice());
+        for &sym in &message {
+            assert_eq!(table.decode(&mut br), Some(sym));
+        }
+    }
+
+    #[test]
+    fn end_of_data_test() {
+        let table = HufDecodeTable::new(&[(0, 1), (1, 1)]).unwrap();
+        let data = [0b1010_1010_u8];
+        let mut br = BitReader::new(data.as_slice());
+        for _ in 0..4 {
+            assert_eq!(table.decode(&mut br), Some(1));
+            assert_eq!(table.decode(&mut br), Some(0));
+        }
+        assert_eq!(table.decode(&mut br), None);
+    }
+
+    #[test]
+    fn code_length_check_test() {
+        // Three codes of one bit can't exist
+        let mut table = HufDecodeTable::new(&[(0, 1), (1, 1)]).unwrap();
+        assert_eq!(table.rebuild(&[(0, 1), (1, 1), (2, 1)]), Err(DecodeError::OversubscribedTable));
+        assert_eq!(table.rebuild(&[(0, 0), (1, 1)]), Err(DecodeError::BadCodeLength(0)));
+        assert_eq!(table.rebuild(&[(0, 1), (1, 21)]), Err(DecodeError::BadCodeLength(21)));
+        assert_eq!(table.rebuild(&[]), Err(DecodeError::BadSymbolCount(0)));
+        assert_eq!(table.rebuild(&[(300, 1)]), Err(DecodeError::BadSymbolCount(1)));
+        // The table is left as it was
+        let mut br = BitReader::new([0b0100_0000_u8].as_slice());
+        assert_eq!(table.decode(&mut br), Some(0));
+        assert_eq!(table.decode(&mut br), Some(1));
+
+        // Codes of up to 20 bits are accepted, and so is an incomplete code. The unused patterns don't decode.
+        let mut map: Vec<(u16, u32)> = (0..19).map(|s| (s, s as u32 + 1)).collect();
+        map.push((19, 20));
+        table.rebuild(&map).unwrap();
+        table.rebuild(&[(0, 1), (1, 2)]).unwrap();
+        let mut br = BitReader::new([0b1011_0000_u8].as_slice());
+        assert_eq!(table.decode(&mut br), Some(1));
+        assert_eq!(table.decode(&mut br), None);
+    }
+}
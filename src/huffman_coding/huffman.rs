@@ -4,11 +4,13 @@
 //! 
 //! 
 //! The huffman coding algorithm is rather complex. The source code is generously commented to explain the algorithm.
-//! It may be helpful to know that the process is iterative. Each chunk of 50 bytes is analayzed four times to maximize
-//! the compression ratio. Each iteration seeks to improve the codes generated by the previous iteration.
+//! It may be helpful to know that the process is iterative. Each chunk of 50 bytes is analayzed four times (by default,
+//! see BzOpts.iterations) to maximize the compression ratio. Each iteration seeks to improve the codes generated by the
+//! previous iteration.
 //! 
 //! 
-//! The process of encoding each block is inherently sequential and does not benefit from multithreading.
+//! Each block is encoded on its own thread. Within a block the iterations are sequential, but on large blocks
+//! (at least 51,200 symbols) the chunk costs of each iteration are worked out in parallel, see chunk_cost.rs.
 //! 
 //! 
 
@@ -17,7 +19,10 @@ use log::{error, info, trace};
 
 use crate::bitstream::bitpacker::BitPacker;
 
-use super::huffman_code_from_weights::improve_code_len_from_weights;
+use super::chunk_cost::{choose_tables, PackedTables};
+use super::huffman_code_from_weights::make_code_lengths;
+use super::table_clustering::cluster_tables;
+use crate::tools::cli::BzOpts;
 use std::cmp::Ordering;
 
 
@@ -55,7 +60,7 @@ impl Node {
 impl PartialOrd for Node {
     /// Sort Nodes by decreasing weight and decreasing symbol value
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some((other.weight, other.syms).cmp(&(self.weight, self.syms)))
+        Some(self.cmp(other))
     }
 }
 impl Ord for Node {
@@ -72,132 +77,48 @@ impl PartialEq for Node {
 
 impl Eq for Node {}
 
-#[allow(clippy::unusual_byte_groupings)]
+/// Selector vecs used by huf_encode, kept from block to block so they don't need to be allocated each time.
+#[derive(Debug, Default)]
+pub struct HufScratch {
+    /// The table used for each 50 symbol chunk.
+    selectors: Vec<usize>,
+    /// The selectors of the table count being tried in high effort mode.
+    trial_selectors: Vec<usize>,
+    /// The selectors after the Move-To-Front transform.
+    mtf_selectors: Vec<usize>,
+}
+
 /// Encode MTF/RLE2 data using Julian Seward's multi-table system.
-/// We need a BitPacker, block data, frequency array for the data, end of block symbol, and the symbol 
-/// map that will be encoded with the data. Data is returned via the BitPacker.
+/// We need a BitPacker, block data, frequency array for the data, end of block symbol, the symbol 
+/// map that will be encoded with the data, the options and the scratch vecs. Data is returned via the BitPacker.
 pub fn huf_encode(
     bp: &mut BitPacker,
     rle2: &[u16],
-    freq: &[u32; 256],
+    freq: &[u32; 258],
     eob: u16,
     symbol_map: &[u16],
+    opts: &BzOpts,
+    scratch: &mut HufScratch,
 ) {
-    // We can have 2-6 coding tables depending on how much data we have coming in.
-    let table_count: usize = match rle2.len() {
-        0..=199 => 2,
-        200..=599 => 3,
-        600..=1199 => 4,
-        1200..=2399 => 5,
-        _ => 6,
+    // Choose the tables and the selectors for each 50 symbol chunk. In high effort mode, try every table count and
+    // cluster the chunks. Otherwise use the standard number of tables, refined opts.iterations times.
+    let (table_count, tables) = if opts.high_effort && !opts.reference {
+        cluster_tables(rle2, freq, eob, opts, &mut scratch.selectors, &mut scratch.trial_selectors)
+    } else {
+        // We can have 2-6 coding tables depending on how much data we have coming in.
+        let table_count: usize = match rle2.len() {
+            0..=199 => 2,
+            200..=599 => 3,
+            600..=1199 => 4,
+            1200..=2399 => 5,
+            _ => 6,
+        };
+        let tables = refine_tables(rle2, freq, table_count, eob, opts, &mut scratch.selectors);
+        (table_count, tables)
     };
-
-    // Now we can initialize the coding tables based on our frequency counts
-    let mut tables = init_tables(freq, table_count, eob);
-
-    // And initialize a count of how many selectors we need, a vec to store them,
-    let selector_count = rle2.len() / 50 + usize::from(rle2.len() % 50 != 0);
-    let mut selectors = vec![0_usize; selector_count];
+    let selectors = &scratch.selectors;
+    let selector_count = selectors.len();
 
-    /*
-     So now we have our tables divided out by frequency ratios. Each symbol in each table
-     is either a 0 or a 15. At the end of this next loop, those will be adjusted as we test
-     against real data. These adjusted numbers are used to build a huffman tree, and
-     thereby the huffman codes.
-
-     We will iterate four times (default value, can be changed in options) to improve the tables.
-     ds: Nov 2022: Looking at trial runs, it seems that three trial runs gains almost the same
-     value as four times. Each larger size gains slightly more in ascii texts. Therefor I added
-     an option to let the user change the number of trial runs -- mostly for more testing purposes.
-    */
-
-    for iter in 0..4 {
-        // initialize favorites[] to 0 for each table/group, for reporting only
-        let mut favorites = [0; 6];
-
-        // Initilalize the total cost for this iteration (used only in reporting)
-        let mut total_cost = 0;
-
-        // Initialize "recalculated" frequency array for each table/group (for adjusting the tables)
-        let mut rfreq = [[0u32; 258]; 6];
-
-        // Reset the selectors on each iteration - not needed because we record them only on the last iteration
-        //selectors.clear();
-
-        /*
-        Time to move through the input 50 bytes at a time. For each group of 50, we
-        compute the best table to use based on the one that has the lowest "weight" cost.
-
-        NOTE: Julian did a trick with rolling all six 16 bit arrays into 3 32 bit arrays.
-        I'm not doing that here. If we do in the future, I believe we could use 1 128 bit array
-        for the same purpose (or possibly 2 64 bit arrays, or a struct).
-
-        Our goal is to find the coding table which has the lowest cost for this chunk
-        of data, and record that in the selector table.
-        */
-
-        rle2.chunks(50).enumerate().for_each(|(i, chunk)| {
-            // the cost array helps us find which table is best for each 50 byte chunk
-            let mut cost = [0; 6];
-
-            // Find the best table for the next chunk
-            // For each symbol, iterate through each table and calculate that tables cost for the symbol
-            chunk.iter().for_each(|symbol| {
-                (0..table_count).for_each(|t| cost[t] += tables[t][*symbol as usize])
-            });
-
-            // Get the position of the lowest non-zero cost (or first if several have the same low cost)
-            let bt = cost
-                .iter()
-                .position(|n| n == cost[0..table_count as usize].iter().min().unwrap())
-                .unwrap() as usize;
-
-            // Add that lowest cost to total_cost for the entire input data set
-            total_cost += cost[bt];
-
-            // For reporting only, increment the appropriate fave array with the index
-            // so we know how many times this table was chosen as "best"
-            favorites[bt] += 1;
-
-            // Now that we know the best table, go get the frequency counts for
-            // the symbols in this group of 50 bytes and store the freq counts into rfreq.
-            // As we go through an iteration, this becomes cumulative.
-            // This is used to improve the tables for the next iteration.
-            chunk
-                .iter()
-                .for_each(|&symbol| rfreq[bt as usize][symbol as usize] += 1);
-
-            // On the last iteration, collect the selector list
-            if iter == 3 {
-                selectors[i] = bt;
-            } // End of the for_each loop, we've gone through the entire input (again).
-        });
-
-        info!(
-            " pass {}: best cost is {}, grp uses are {:?}",
-            iter + 1,
-            total_cost / 8,
-            favorites
-        );
-
-        if iter == 3 {
-            trace!("Final tables:",);
-            for (i, table) in tables.iter().enumerate().take(table_count) {
-                trace!(
-                    "\n      {}: {:?}",
-                    i,
-                    table.iter().take(eob as usize + 1).collect::<Vec<_>>()
-                );
-            }
-        }
-
-        // Next we will call improve_code_len_from_weights on each of the tables we made.
-        // This makes actual node trees based off our weighting. This will put the
-        // improved weights into the weight arrays. As mentioned, we do this 4 times.
-        (0..table_count).for_each(|t| {
-            improve_code_len_from_weights(&mut tables[t], &rfreq[t], eob);
-        });
-    }
     /*
       All iterations are now done, and we have good tables and selectors.
       Time to make actual binary codes for reach table. Since we have good lengths,
@@ -207,13 +128,13 @@ pub fn huf_encode(
     // Write out the the symbol maps, 16 bit L1 + 0-16 words of 16 bit L2 maps.
     trace!("\r\x1b[43mSymbol maps written at {}.     \x1b[0m", bp.loc());
     for word in symbol_map {
-        bp.out16(*word);
+        bp.put_bits(*word as u64, 16);
         trace!("\r\x1b[43m{:0>16b}     \x1b[0m", word);
     }
 
     // Symbol maps are followed by a 3 bit number of Huffman trees that exist
     trace!("\r\x1b[43mTable count written at {}.     \x1b[0m", bp.loc());
-    bp.out24((3 << 24) | table_count as u32);
+    bp.put_bits(table_count as u64, 3);
 
     // Then a 15 bit number indicating the how many selectors are used
     // (how many 50 byte groups are in this block of data)
@@ -221,7 +142,7 @@ pub fn huf_encode(
         "\r\x1b[43mSelector count written at {}.     \x1b[0m",
         bp.loc()
     );
-    bp.out24((15 << 24) | selector_count as u32);
+    bp.put_bits(selector_count as u64, 15);
 
     /*
     Selectors tell us which table is to be used for each 50 symbol chunk of input
@@ -234,17 +155,19 @@ pub fn huf_encode(
     */
 
     // Initialize an index to the selector tables in preparation for the MTF transform for the selectors
-    let mut table_idx = vec![0, 1, 2, 3, 4, 5];
+    let mut table_idx = [0, 1, 2, 3, 4, 5];
 
     // Prepare the output selector vec
-    let mut mtf_selectors = vec![0_usize; selector_count];
+    let mtf_selectors = &mut scratch.mtf_selectors;
+    mtf_selectors.clear();
+    mtf_selectors.resize(selector_count, 0);
 
     // ...then do the MTF transform of the selector vector
     for i in 0..selector_count {
         // Get the index of the next selector
         let mut idx = table_idx.iter().position(|c| c == &selectors[i]).unwrap();
         // Write it to the MTF version of the selector vector
-        mtf_selectors[i as usize] = idx;
+        mtf_selectors[i] = idx;
         // Adjust the  table index
         // For speed, don't take time to adjust the index if we don't need to.
         match idx {
@@ -254,17 +177,17 @@ pub fn huf_encode(
             }
             _ => {
                 // Shift each index at the front of selector "forward" one. Do blocks of 3 for speed.
-                let temp_sel = table_idx[idx as usize];
+                let temp_sel = table_idx[idx];
 
                 while idx > 2 {
-                    table_idx[idx as usize] = table_idx[idx as usize - 1];
-                    table_idx[idx as usize - 1] = table_idx[idx as usize - 2];
-                    table_idx[idx as usize - 2] = table_idx[idx as usize - 3];
+                    table_idx[idx] = table_idx[idx - 1];
+                    table_idx[idx - 1] = table_idx[idx - 2];
+                    table_idx[idx - 2] = table_idx[idx - 3];
                     idx -= 3;
                 }
                 // ...then clean up any odd ones
                 while idx > 0 {
-                    table_idx[idx as usize] = table_idx[idx as usize - 1];
+                    table_idx[idx] = table_idx[idx - 1];
                     idx -= 1;
                 }
                 // ...and finally put the "new" symbol index at the front of the index.
@@ -279,54 +202,43 @@ pub fn huf_encode(
         mtf_selectors.len(),
         bp.loc()
     );
-    for selector in &mtf_selectors {
-        match selector {
-            0 => bp.out24(0x01000000),
-            1 => bp.out24(0x02000002),
-            2 => bp.out24(0x03000006),
-            3 => bp.out24(0x0400000e),
-            4 => bp.out24(0x0500001e),
-            5 => bp.out24(0x0600003e),
-            _ => error!("Bad selector value of {}", selector),
-        };
+    // Each is written in unary: one 1 bit per position from the front, then a 0 bit.
+    for &selector in mtf_selectors.iter() {
+        if selector > 5 {
+            error!("Bad selector value of {}", selector);
+            continue;
+        }
+        bp.put_bits((1 << (selector + 1)) - 2, selector as u8 + 1);
     }
-    // All done with mtf_selectors.
-    drop(mtf_selectors);
-
     /*
     Now create the huffman codes. We need to convert our weights to huffman codes.
     (And later we will want to use the BitPacker with those codes also.)
-    We will need both a vec of all output code tables, and a temporary place
-    to build each output-style table.
+    We will need both an array of all output code tables, and a temporary place
+    to sort the lengths of each table.
 
     Remember, our tables are full 258 size arrays. We've done indexing and move-to-
     front transforms, so we are using only the bottom portion of that array.
-
-    We will shift from an array format to a vec, which allows us to use Rust's
-    optimized sorting functions.
     */
 
-    // Create the vec for the output-style code tables
-    let mut out_code_tables = vec![];
+    // Create the array for the output-style code tables of (code, length) for each symbol
+    let mut out_code_tables = [[(0_u32, 0_u8); 258]; 6];
 
     // For as many tables as we have, we have quite few steps to do
     #[allow(clippy::needless_range_loop)]
-    for i in 0..table_count as usize {
+    for i in 0..table_count {
         // Because we use a fixed array of tables for speed, we must use an index to get only the ones we want
         let table = tables[i];
         // Calculate the size once
         let sym_size = eob as usize + 1;
-        // Now create a output-style table
-        let mut out_codes = vec![(0_u16, 0_u32); sym_size];
-        // ... and create a vec of the symbols actually used
-        let mut len_sym: Vec<(u32, u16)> = table.iter().enumerate().take(sym_size).fold(
-            Vec::with_capacity(sym_size),
-            |mut vec, (i, t)| {
-                vec.push((*t, i as u16));
-                vec
-            },
-        );
-        // ... and sort that vec ascending by length
+        // Get the output-style table
+        let out_codes = &mut out_code_tables[i];
+        // ... and create a list of the lengths of the symbols actually used
+        let mut len_sym_array = [(0_u32, 0_u16); 258];
+        let len_sym = &mut len_sym_array[..sym_size];
+        for (sym, len) in table.iter().take(sym_size).enumerate() {
+            len_sym[sym] = (*len, sym as u16);
+        }
+        // ... and sort that list ascending by length
         len_sym.sort_unstable();
 
         /*
@@ -339,35 +251,21 @@ pub fn huf_encode(
         // Initialize next_code to the length of the first (smallest) length, and 0.
         let mut next_code: (u32, u32) = (len_sym[0].0, 0);
 
-        // Create a vec where we can store the codes.
-        let mut sym_codes = vec![(0_u16, 0_u32); sym_size];
-
         /*
         When the length changes, do a shift left for each increment and continue. So
         for example, if the length is now 5 and the last code had a length of 3 and
         was 010, we would now start with 01000, 01001, 01010, etc.
 
-        We store a version for the BitPacker, a format I also use in my hashmap
-        in the decompression side. This is in addition to the format we need below.
-
-        The length is in the most significant 8 bits, the code in the least.
-        For example if the length is 5 and the code is 11111, we'd see
-            01234567_XXXXXX_0123456780123456
-            00000101_000000_0000000000011111
-        X indicates space we never use. Excuse the odd _ marking. It is why I use
-        #[allow(clippy::unusual_byte_groupings)]
+        We store the code and its length by symbol for the BitPacker.
         */
         // For each symbol...
-        for (i, (len, sym)) in len_sym.iter().enumerate() {
+        for (len, sym) in len_sym.iter() {
             if *len != next_code.0 {
                 next_code.1 <<= len - next_code.0;
                 next_code.0 = *len;
             }
-            // ...save a version of the code in the BitPacker format
-            out_codes[i] = (*sym, len << 24 | next_code.1);
-
-            // ...and also save it for encoding below.
-            sym_codes[i] = (*sym, next_code.1);
+            // ...save the code and length for the BitPacker
+            out_codes[*sym as usize] = (next_code.1, *len as u8);
 
             // Increment the next_code.1 counter to generate the next code
             next_code.1 += 1;
@@ -385,7 +283,7 @@ pub fn huf_encode(
         */
 
         // The len_sym vec now needs to be sorted by symbol, not length
-        len_sym.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+        len_sym.sort_unstable_by_key(|a| a.1);
 
         // We write the origin as a five bit int
         let mut origin = len_sym[0].0;
@@ -395,7 +293,7 @@ pub fn huf_encode(
             i,
             bp.loc()
         );
-        bp.out24((5 << 24) | origin as u32);
+        bp.put_bits(origin as u64, 5);
 
         // ... and iterate through the entire symbol list writing the deltas
         for entry in len_sym.iter() {
@@ -417,13 +315,13 @@ pub fn huf_encode(
                 match delta.cmp(&0) {
                     // if the delta is greater than 0, write 0x10
                     Ordering::Greater => {
-                        bp.out24(0x02_000002);
+                        bp.put_bits(0b10, 2);
                         // subtract one from the delta and loop again
                         delta -= 1;
                     }
                     // if the delta is less than 0, write 0x11
                     Ordering::Less => {
-                        bp.out24(0x02_000003);
+                        bp.put_bits(0b11, 2);
                         // add one t the delta and loop again
                         delta += 1;
                     }
@@ -434,11 +332,8 @@ pub fn huf_encode(
                 }
             }
             // write a single 0 bit to indicate we are done with this symbol's length code
-            bp.out24(0x01_000000);
+            bp.put_bits(0, 1);
         }
-        // Sort the output codes by symbol before saving them in the output tables
-        out_codes.sort_unstable();
-        out_code_tables.push(out_codes);
     }
 
     /*
@@ -452,7 +347,8 @@ pub fn huf_encode(
     for (idx, chunk) in rle2.chunks(50).enumerate() {
         let table_idx = selectors[idx];
         chunk.iter().for_each(|symbol| {
-            bp.out24(out_code_tables[table_idx][*symbol as usize].1);
+            let (code, len) = out_code_tables[table_idx][*symbol as usize];
+            bp.put_bits(code as u64, len);
             // For debugging
             trace!(
                 "\r\x1b[43mBP: RLE2 {} ({}) written at {}.   \x1b[0m",
@@ -467,9 +363,91 @@ pub fn huf_encode(
     // All done
 }
 
+/// Build table_count coding tables from the symbol frequencies and refine them opts.iterations times against the
+/// data. Returns the code length tables, and puts the selector (table index) for each 50 symbol chunk in selectors.
+fn refine_tables(
+    rle2: &[u16],
+    freq: &[u32; 258],
+    table_count: usize,
+    eob: u16,
+    opts: &BzOpts,
+    selectors: &mut Vec<usize>,
+) -> [[u32; 258]; 6] {
+    // Now we can initialize the coding tables based on our frequency counts
+    let mut tables = if opts.reference {
+        init_tables_reference(freq, table_count, eob, rle2.len())
+    } else {
+        init_tables(freq, table_count, eob)
+    };
+
+    // And initialize a count of how many selectors we need, a vec to store them (the last iteration leaves the
+    // final selectors in it),
+    let selector_count = rle2.len() / 50 + usize::from(!rle2.len().is_multiple_of(50));
+    selectors.clear();
+    selectors.resize(selector_count, 0);
+
+    /*
+     So now we have our tables divided out by frequency ratios. Each symbol in each table
+     is either a 0 or a 15. At the end of this next loop, those will be adjusted as we test
+     against real data. These adjusted numbers are used to build a huffman tree, and
+     thereby the huffman codes.
+
+     We will iterate four times (default value, can be changed in options) to improve the tables.
+     ds: Nov 2022: Looking at trial runs, it seems that three trial runs gains almost the same
+     value as four times. Each larger size gains slightly more in ascii texts. Therefor I added
+     an option to let the user change the number of trial runs -- mostly for more testing purposes.
+     Reference mode always uses four, as the C version does.
+    */
+    let iterations = if opts.reference {
+        4
+    } else {
+        opts.iterations.max(1)
+    };
+    let last_iter = iterations - 1;
+
+    for iter in 0..iterations {
+        /*
+        Time to move through the input 50 bytes at a time. For each group of 50, we
+        compute the best table to use based on the one that has the lowest "weight" cost.
+
+        Our goal is to find the coding table which has the lowest cost for each chunk
+        of data, and record that in the selector table. The symbols of each chunk are
+        also counted against the table chosen (rfreq), which is used to improve the tables
+        for the next iteration. The costs of all the tables are added at once, see chunk_cost.rs.
+        */
+        let stats = choose_tables(rle2, &PackedTables::new(&tables, table_count), selectors);
+
+        info!(
+            " pass {}: best cost is {}, grp uses are {:?}",
+            iter + 1,
+            stats.total_cost / 8,
+            stats.favorites
+        );
+
+        if iter == last_iter {
+            trace!("Final tables:",);
+            for (i, table) in tables.iter().enumerate().take(table_count) {
+                trace!(
+                    "\n      {}: {:?}",
+                    i,
+                    table.iter().take(eob as usize + 1).collect::<Vec<_>>()
+                );
+            }
+        }
+
+        // Next we will build new code lengths for each of the tables we made, based off our weighting.
+        // This will put the improved weights into the weight arrays. As mentioned, we do this opts.iterations times.
+        // In reference mode, the port of the C version is used so equal weights are paired the same way.
+        (0..table_count).for_each(|t| {
+            make_code_lengths(&mut tables[t], &stats.rfreq[t], eob, opts);
+        });
+    }
+    tables
+}
+
 #[allow(clippy::unusual_byte_groupings)]
 /// Initialize 2-6 frequency tables based on the frequencies of the symbols in the data
-fn init_tables(freqs: &[u32], table_count: usize, eob: u16) -> [[u32; 258]; 6] {
+pub(crate) fn init_tables(freqs: &[u32], table_count: usize, eob: u16) -> [[u32; 258]; 6] {
     // Initialize the tables to weights of 15. Since Rust requires compile time array
     // sizing, let's just make 6 even though we might need less.
     let mut tables = [[15_u32; 258]; 6];
@@ -530,3 +508,44 @@ fn init_tables(freqs: &[u32], table_count: usize, eob: u16) -> [[u32; 258]; 6] {
     }
     tables
 }
+
+/// Initialize 2-6 frequency tables exactly as the C version does, for reference output. The symbols are split
+/// into consecutive ranges of roughly equal total frequency, and the range for every other table is made one
+/// symbol shorter. n_mtf is the total number of symbols (including EOB) in the block.
+fn init_tables_reference(
+    freqs: &[u32],
+    table_count: usize,
+    eob: u16,
+    n_mtf: usize,
+) -> [[u32; 258]; 6] {
+    let alpha_size = eob as i32 + 1;
+    let mut tables = [[15_u32; 258]; 6];
+
+    // The last table gets the lowest symbols, so fill from the last table to the first
+    let mut n_part = table_count;
+    let mut rem_f = n_mtf as u32;
+    let mut gs = 0_i32;
+    while n_part > 0 {
+        let t_freq = rem_f / n_part as u32;
+        let mut ge = gs - 1;
+        let mut a_freq = 0;
+        while a_freq < t_freq && ge < alpha_size - 1 {
+            ge += 1;
+            a_freq += freqs[ge as usize];
+        }
+
+        if ge > gs && n_part != table_count && n_part != 1 && (table_count - n_part) % 2 == 1 {
+            a_freq -= freqs[ge as usize];
+            ge -= 1;
+        }
+
+        for v in gs..=ge {
+            tables[n_part - 1][v as usize] = 0;
+        }
+
+        n_part -= 1;
+        gs = ge + 1;
+        rem_f -= a_freq;
+    }
+    tables
+}
@@ -3,14 +3,119 @@
 //! 
 //! The main huffman encoding function generates frequency weight tables for each chunk of 50 bytes of data. This helper
 //! function generates huffman codes (actually depth tables) from those frequency weights. The BZIP2 standard requries that the maximum
-//! code length is 17 bits. If the weights supplied create longer codes, the weights will be adjusted and another
-//! attempt will be made to generate the codes.
+//! code length is 17 bits.
+//!
+//! Three ways of building the lengths are provided:
+//! - package_merge_code_lengths (the default): the package-merge algorithm, which directly finds the optimal lengths
+//!   within the 17 bit limit. It works on flat arrays. Most tables fit in 17 bits anyway, so a plain huffman code is
+//!   built first with the heap of hb_make_code_lengths, and package-merge only runs when that code is too long.
+//! - improve_code_len_from_weights: builds a huffman tree. If the weights supplied create codes that are too long, the
+//!   weights will be adjusted and another attempt will be made to generate the codes. This is kept for comparison.
+//! - hb_make_code_lengths: a port of the C version's heap based function, for when the output must match the C version exactly.
 //! 
-//! The process of encoding each block is inherently sequential and does not benefit from multithreading.
+//! Building the lengths for one table is sequential. The tables of a block are built one after another, on the
+//! block's thread.
 //! 
 //! 
 
 use super::huffman::{Node, NodeData};
+use crate::tools::cli::{BzOpts, CodeLengths};
+
+/// Longest code length allowed by BZIP2 encoders.
+const MAX_CODE_LEN: usize = 17;
+
+/// Build the code lengths for one table using the algorithm selected in the options. Reference mode always uses
+/// the port of the C version.
+pub fn make_code_lengths<'a>(
+    codes: &'a mut [u32],
+    sym_weight: &'a [u32],
+    eob: u16,
+    opts: &BzOpts,
+) -> &'a [u32] {
+    if opts.reference {
+        return hb_make_code_lengths(codes, sym_weight, eob);
+    }
+    match opts.code_lengths {
+        // A plain huffman code is optimal too when it fits in MAX_CODE_LEN bits, and is several times faster to
+        // build, so package-merge only runs when it doesn't fit.
+        CodeLengths::PackageMerge => {
+            if huffman_fits(codes, sym_weight, eob) {
+                codes
+            } else {
+                package_merge_code_lengths(codes, sym_weight, eob)
+            }
+        }
+        CodeLengths::Halving => improve_code_len_from_weights(codes, sym_weight, eob),
+    }
+}
+
+/// Optimal length limited code lengths using the package-merge algorithm. Arguments and result are the same as
+/// improve_code_len_from_weights. Symbols with a weight of zero are given a weight of one, as all symbols need a code.
+pub fn package_merge_code_lengths<'a>(
+    codes: &'a mut [u32],  //[u32; 258]
+    sym_weight: &'a [u32], //[u32; 258]
+    eob: u16,              //symbol marking last valid byte in the above slice
+) -> &'a [u32] {
+    /*
+    Package-merge finds the cheapest set of "coins" adding up to n-1, where each symbol has a coin of its weight at
+    each of the 17 levels. The code length of a symbol is the number of its coins chosen.
+
+    Starting with the deepest level, each level's list is the symbols (sorted by weight) merged with "packages" made
+    from pairs of items in the list below. We only ever need the cheapest 2n-2 items of a list. The top list's first
+    2n-2 items are chosen. Each chosen package means its two items one level down are chosen too, so walking back
+    down, the chosen items at each level are always a prefix of that level's list.
+
+    Since symbols are merged in sorted order, the leaves in a prefix are always the lightest symbols. So for each
+    level we only need to remember which items are packages.
+    */
+    let n = eob as usize + 1;
+    let max_items = 2 * n - 2;
+
+    // Symbols sorted by weight (and symbol, to be deterministic)
+    let weight = |s: usize| sym_weight[s].max(1) as u64;
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_unstable_by_key(|&s| (weight(s), s));
+    let leaves: Vec<u64> = order.iter().map(|&s| weight(s)).collect();
+
+    // For each level (deepest first), true where the list item is a package. The levels share one allocation.
+    let mut is_package = vec![false; MAX_CODE_LEN * max_items];
+    let mut list: Vec<u64> = Vec::with_capacity(max_items);
+    let mut next: Vec<u64> = Vec::with_capacity(max_items);
+
+    for kinds in is_package.chunks_exact_mut(max_items) {
+        // Merge the leaves with packages made from pairs of the previous list
+        next.clear();
+        let (mut leaf, mut pair) = (0, 0);
+        while next.len() < max_items && (leaf < n || pair + 1 < list.len()) {
+            let package = if pair + 1 < list.len() {
+                list[pair] + list[pair + 1]
+            } else {
+                u64::MAX
+            };
+            if leaf < n && leaves[leaf] <= package {
+                next.push(leaves[leaf]);
+                leaf += 1;
+            } else {
+                kinds[next.len()] = true;
+                next.push(package);
+                pair += 2;
+            }
+        }
+        std::mem::swap(&mut list, &mut next);
+    }
+
+    // Walk back down from the top level, counting how many times each symbol is chosen
+    codes[..n].fill(0);
+    let mut chosen = max_items;
+    for kinds in is_package.chunks_exact(max_items).rev() {
+        let leaves_chosen = kinds[..chosen].iter().filter(|&&p| !p).count();
+        for &s in &order[..leaves_chosen] {
+            codes[s] += 1;
+        }
+        chosen = 2 * (chosen - leaves_chosen);
+    }
+    codes
+}
 
 /// Improve a slice of Huffman codes lengths (u8) using a slice of  
 /// codes, symbol weights, and knowlege of how many symbols are valid. Returns depth.
@@ -83,6 +188,129 @@ pub fn improve_code_len_from_weights<'a>(
     // Overwrite the codes and return the improved list.
 }
 
+/// Port of the C version's code length function (BZ2_hbMakeCodeLengths). It builds the tree with a heap instead of a
+/// sorted vec. Both give optimal lengths, but equal weights can be paired differently, so this version is used when the
+/// output must match the C version byte for byte. Arguments and result are the same as improve_code_len_from_weights.
+pub fn hb_make_code_lengths<'a>(
+    codes: &'a mut [u32],  //[u32; 258]
+    sym_weight: &'a [u32], //[u32; 258]
+    eob: u16,              //symbol marking last valid byte in the above slice
+) -> &'a [u32] {
+    let alpha_size = eob as usize + 1;
+    let mut weight = [0_u32; 258 * 2];
+    for i in 0..alpha_size {
+        weight[i + 1] = sym_weight[i].max(1) << 8;
+    }
+
+    while !hb_tree_lengths(codes, &mut weight, alpha_size) {
+        // Flatten the weights and try again, exactly as improve_code_len_from_weights does
+        for w in weight.iter_mut().skip(1).take(alpha_size) {
+            let j = 1 + ((*w >> 8) / 2);
+            *w = j << 8;
+        }
+    }
+    codes
+}
+
+/// Plain huffman code lengths, built once with the heap of hb_make_code_lengths. Returns false if a code is longer
+/// than MAX_CODE_LEN, in which case codes holds the too long lengths.
+fn huffman_fits(codes: &mut [u32], sym_weight: &[u32], eob: u16) -> bool {
+    let alpha_size = eob as usize + 1;
+    let mut weight = [0_u32; 258 * 2];
+    for i in 0..alpha_size {
+        weight[i + 1] = sym_weight[i].max(1) << 8;
+    }
+    hb_tree_lengths(codes, &mut weight, alpha_size)
+}
+
+/// One pass of hb_make_code_lengths: build the tree of the leaf weights in weight[1..=alpha_size] and put the depth
+/// of each leaf in codes. Returns false if a code is longer than MAX_CODE_LEN.
+fn hb_tree_lengths(codes: &mut [u32], weight: &mut [u32; 258 * 2], alpha_size: usize) -> bool {
+    // Index 0 of the heap and weight arrays is a sentinel. Leaves are 1..=alpha_size, internal nodes follow.
+    let mut heap = [0_usize; 258 + 2];
+    let mut parent = [0_i32; 258 * 2];
+    let mut n_nodes = alpha_size;
+    let mut n_heap = 0;
+    weight[0] = 0;
+    parent[0] = -2;
+
+    parent[1..=alpha_size].fill(-1);
+    for i in 1..=alpha_size {
+        n_heap += 1;
+        heap[n_heap] = i;
+        up_heap(&mut heap, weight, n_heap);
+    }
+
+    // Combine the two lightest nodes until only the root is left
+    while n_heap > 1 {
+        let n1 = heap[1];
+        heap[1] = heap[n_heap];
+        n_heap -= 1;
+        down_heap(&mut heap, weight, n_heap);
+        let n2 = heap[1];
+        heap[1] = heap[n_heap];
+        n_heap -= 1;
+        down_heap(&mut heap, weight, n_heap);
+        n_nodes += 1;
+        parent[n1] = n_nodes as i32;
+        parent[n2] = n_nodes as i32;
+        weight[n_nodes] = add_weights(weight[n1], weight[n2]);
+        parent[n_nodes] = -1;
+        n_heap += 1;
+        heap[n_heap] = n_nodes;
+        up_heap(&mut heap, weight, n_heap);
+    }
+
+    // The code length of each symbol is the number of steps to the root
+    let mut fits = true;
+    for (i, code) in codes.iter_mut().enumerate().take(alpha_size) {
+        let mut j = 0;
+        let mut k = i + 1;
+        while parent[k] >= 0 {
+            k = parent[k] as usize;
+            j += 1;
+        }
+        *code = j;
+        if j as usize > MAX_CODE_LEN {
+            fits = false;
+        }
+    }
+    fits
+}
+
+/// Move the last heap entry up to its place. Stops at the sentinel (weight 0) in heap[0].
+#[inline(always)]
+fn up_heap(heap: &mut [usize], weight: &[u32], mut zz: usize) {
+    let tmp = heap[zz];
+    while weight[tmp] < weight[heap[zz >> 1]] {
+        heap[zz] = heap[zz >> 1];
+        zz >>= 1;
+    }
+    heap[zz] = tmp;
+}
+
+/// Move the first heap entry down to its place.
+#[inline(always)]
+fn down_heap(heap: &mut [usize], weight: &[u32], n_heap: usize) {
+    let mut zz = 1;
+    let tmp = heap[zz];
+    loop {
+        let mut yy = zz << 1;
+        if yy > n_heap {
+            break;
+        }
+        if yy < n_heap && weight[heap[yy + 1]] < weight[heap[yy]] {
+            yy += 1;
+        }
+        if weight[tmp] < weight[heap[yy]] {
+            break;
+        }
+        heap[zz] = heap[yy];
+        zz = yy;
+    }
+    heap[zz] = tmp;
+}
+
 /// Recursively walk the tree and return in "leaves" how far (deep) from the root node each leaf is.
 /// Depth is the same as the code length, and will be used to create actual codes later.
 fn return_leaves(node: &Node, depth: u8, leaves: &mut Vec<(u16, u8)>) {
@@ -108,3 +336,55 @@ fn add_weights(a: u32, b: u32) -> u32 {
     ((a & weight_mask) + (b & weight_mask)) | (1 + (a & depth_mask).max(b & depth_mask))
 }
 
+#[cfg(test)]
+mod test {
+    use super::{hb_make_code_lengths, improve_code_len_from_weights, make_code_lengths, package_merge_code_lengths};
+    use crate::tools::cli::BzOpts;
+
+    /// Total bits to encode the symbols with these lengths, and the Kraft sum scaled by 2^17.
+    fn cost_and_kraft(lengths: &[u32], weights: &[u32]) -> (u64, u64) {
+        let cost = lengths.iter().zip(weights).map(|(&l, &w)| l as u64 * w.max(1) as u64).sum();
+        let kraft = lengths.iter().map(|&l| 1_u64 << (17 - l)).sum();
+        (cost, kraft)
+    }
+
+    #[test]
+    fn matches_unlimited_huffman_test() {
+        // When no code needs more than 17 bits, the total cost is the same as a huffman tree
+        let weights: Vec<u32> = (0..40).map(|i| (i * 37 % 23) * 100 + i).collect();
+        let eob = weights.len() as u16 - 1;
+        let mut pm = [0_u32; 258];
+        let mut hb = [0_u32; 258];
+        package_merge_code_lengths(&mut pm, &weights, eob);
+        hb_make_code_lengths(&mut hb, &weights, eob);
+        let n = weights.len();
+        assert_eq!(cost_and_kraft(&pm[..n], &weights), cost_and_kraft(&hb[..n], &weights));
+        assert_eq!(cost_and_kraft(&pm[..n], &weights).1, 1 << 17);
+    }
+
+    #[test]
+    fn length_limit_test() {
+        // Fibonacci weights would need codes of up to 29 bits without the limit
+        let mut weights = vec![1_u32, 1];
+        while weights.len() < 30 {
+            weights.push(weights[weights.len() - 1] + weights[weights.len() - 2]);
+        }
+        let eob = weights.len() as u16 - 1;
+        let n = weights.len();
+        let mut pm = [0_u32; 258];
+        let mut halving = [0_u32; 258];
+        package_merge_code_lengths(&mut pm, &weights, eob);
+        improve_code_len_from_weights(&mut halving, &weights, eob);
+
+        assert!(pm[..n].iter().all(|&l| (1..=17).contains(&l)));
+        let (pm_cost, pm_kraft) = cost_and_kraft(&pm[..n], &weights);
+        let (halving_cost, _) = cost_and_kraft(&halving[..n], &weights);
+        assert_eq!(pm_kraft, 1 << 17);
+        assert!(pm_cost <= halving_cost);
+
+        // The default first tries a plain huffman code, which is too long here, so it must fall back to package-merge
+        let mut default = [0_u32; 258];
+        make_code_lengths(&mut default, &weights, eob, &BzOpts::new());
+        assert_eq!(default[..n], pm[..n]);
+    }
+}
@@ -1,4 +1,5 @@
-//! The huffman module compresses the MTF/RLE2 data into a bistream. Decoding huffman (decompression) data happens in the decompress function.
+//! The huffman module compresses the MTF/RLE2 data into a bistream. Decoding huffman (decompression) data happens in the decompress function,
+//! using the lookup tables built by decode_table.
 //!
 //! Huffman encoding is used in lieu of arithmetic encoding because of an historical problem with licensing restrictions. 
 //! While that has been resolved in more recent years, the BZIP2 standard was set based on the huffman standard.
@@ -8,9 +9,15 @@
 //! bytes of data are encoded separately using one of six huffman tables. This allows for higher compression ratios compared to
 //! using one huffman table per block (or for the entire file).
 //! 
-//! The process of huffman encoding a block is inherently sequential and does not benefit from multithreading.
+//! The process of huffman encoding a block is mostly sequential. Choosing the table for each chunk is the exception:
+//! the costs of every table are added at once (chunk_cost), and large blocks are costed on several threads.
+//!
+//! An optional high effort mode (table_clustering) clusters the chunks to choose the tables and the table count.
 //! 
 //! 
 
+pub mod chunk_cost;
+pub mod decode_table;
 pub mod huffman;
 pub mod huffman_code_from_weights;
+pub mod table_clustering;
@@ -11,6 +11,12 @@
 //! - Provide fast, safe compression and decompression of files using the bzip2 format.
 //! - Utilize multi-core multi-threaded processing. 
 //! - Contain SA-IS sorting to improve compression speeds on repetative data.
+//! - Offer the SA-IS suffix array and Burrows-Wheeler-Transform code as a public API (bwt_algorithms::suffix_array).
+//! - Offer in-memory compression and decompression through reusable contexts (compression::context), which keep
+//!   their buffers from call to call.
+//! - Map large input files into memory, so they are split into blocks and decoded in place (--no-mmap reads them).
+//! - Limit the output, compression ratio, memory, streams and blocks of untrusted input when decompressing
+//!   (compression::decode_limits, or --max-output and friends).
 //!
 //! Basic usage to compress a files is as follows:
 //! 
@@ -30,11 +36,13 @@
 //! 
 //! NOTES: 
 //! - The C version is very well written. Julian Seward implemented many insightful optimizations. But documentation... well this is much more
-//! documented than the C version.
+//!   documented than the C version.
 //! - Developer feedback is welcome. If you have suggestions for improvement, please let me know!
-//! - This version compresses about 25% slower than the C version for tiny files. This is faster on larger files.
+//! - Inputs that fit in one block are compressed on a single thread. Below 100k bytes this takes about 0.1-3 ms longer
+//!   than the C version per file (the median wall times of `cargo bench --bench small_inputs` were 1.8-2.3 ms against
+//!   1.6-1.7 ms at 1k, and 11.4-12.2 ms against 8.3-10.4 ms at 50k). This is faster on larger files.
 //! - It is particularly faster when using the SA-IS sorting algorithm as the fallback sorting algorithm.
-//! - This version is about 25% slower than the C version for decompression. That said, BZIP2 decompression is pretty fast.
+//! - Decompression runs at about the speed of the C version.
 //!
 pub mod bitstream;
 pub mod compression;
@@ -1,17 +1,10 @@
 //Enable more cargo lint tests
 #![warn(rust_2018_idioms)]
 #![warn(clippy::disallowed_types)]
-mod bitstream;
-mod bwt_algorithms;
-mod compression;
-mod huffman_coding;
-mod tools;
-
-use crate::tools::cli::bzopts_init;
-use compression::{compress::compress, decompress::decompress};
-use log::{info, warn, LevelFilter};
+use bzip2::compression::{compress::compress, decompress::decompress};
+use bzip2::tools::cli::{bzopts_init, Mode};
+use log::{info, LevelFilter};
 use simplelog::{Config, TermLogger, TerminalMode};
-use tools::cli::Mode;
 
 fn main() -> Result<(), std::io::Error> {
     // Available log levels are Error, Warn, Info, Debug, Trace
@@ -4,13 +4,14 @@
 //! initialized.
 //! 
 //! Usage example:
-//! ```
+//! ```ignore
 //! let mut opts = BzOpts::new();
 //! opts.init();
 //! ```
 //! You can then access the options via the instance you created.
 //! 
 
+use crate::compression::decode_limits::DecodeLimits;
 use std::process::exit;
 use std::{fmt::Display, fmt::Formatter};
 
@@ -50,6 +51,40 @@ impl Display for Output {
     }
 }
 
+/// Algorithm used to build huffman code lengths
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeLengths {
+    /// Optimal lengths within the 17 bit limit using package-merge
+    PackageMerge,
+    /// Huffman tree, halving the weights and rebuilding when a code is over 17 bits
+    Halving,
+}
+impl Display for CodeLengths {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Algorithm used for the Burrows-Wheeler-Transform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BwtAlgorithm {
+    /// Native sort, switching to SA-IS for repetitive data
+    Auto,
+    /// Bucket sort followed by introsort
+    Native,
+    /// SA-IS suffix array algorithm
+    Sais,
+    /// SA-IS suffix array algorithm, multi-threaded where possible
+    SaisParallel,
+    /// Port of Julian Seward's C sorting algorithm
+    Julian,
+}
+impl Display for BwtAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 /// NOT YET IMPLEMENTED. Used during the library mode by the calling program.
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -68,7 +103,8 @@ pub struct BzOpts {
     pub force_overwrite: bool,
     /// Don't remove input files after processing
     pub keep_input_files: bool,
-    /// Iterations used to test/optimize small block compression
+    /// Number of passes used to refine the huffman tables of each block (1 or more, default 4).
+    /// Fewer passes compress faster, more passes can compress slightly smaller.
     pub iterations: usize,
     /// Compress/Decompress/Test
     pub op_mode: Mode,
@@ -80,8 +116,20 @@ pub struct BzOpts {
     pub status: Status,
     /// Verbosity of user information
     pub verbose: Verbosity,
-    /// Optional setting used for oddly constructed data - may be depricated
+    /// Effort (1-100, default 30) spent sorting repetitive data before switching to the fallback sort
     pub work_factor: usize,
+    /// Produce output identical to the C version (uses the C sorting and table building algorithms)
+    pub reference: bool,
+    /// Spend more time choosing the huffman tables for slightly smaller output (ignored in reference mode)
+    pub high_effort: bool,
+    /// Algorithm used to build huffman code lengths (ignored in reference mode)
+    pub code_lengths: CodeLengths,
+    /// Algorithm used for the Burrows-Wheeler-Transform (reference mode always uses Julian)
+    pub bwt: BwtAlgorithm,
+    /// Map large input files into memory instead of reading them (default true)
+    pub mmap: bool,
+    /// Limits on the output, ratio, memory, streams and blocks when decompressing (default none)
+    pub limits: DecodeLimits,
 }
 
 impl BzOpts {
@@ -98,6 +146,12 @@ impl BzOpts {
             status: Status::Init,
             verbose: Verbosity::Errors,
             work_factor: 30,
+            reference: false,
+            high_effort: false,
+            code_lengths: CodeLengths::PackageMerge,
+            bwt: BwtAlgorithm::Auto,
+            mmap: true,
+            limits: DecodeLimits::new(),
         }
     }
 }
@@ -141,6 +195,28 @@ pub fn bzopts_init() -> BzOpts {
                 "--small" => cli.small = true,
                 "--fast" => cli.block_size = 1,
                 "--best" => cli.block_size = 9,
+                "--reference" => cli.reference = true,
+                "--high-effort" => cli.high_effort = true,
+                "--no-mmap" => cli.mmap = false,
+                "--code-lengths=package-merge" => cli.code_lengths = CodeLengths::PackageMerge,
+                "--code-lengths=halving" => cli.code_lengths = CodeLengths::Halving,
+                "--bwt=auto" => cli.bwt = BwtAlgorithm::Auto,
+                "--bwt=native" => cli.bwt = BwtAlgorithm::Native,
+                "--bwt=sais" => cli.bwt = BwtAlgorithm::Sais,
+                "--bwt=sais-parallel" => cli.bwt = BwtAlgorithm::SaisParallel,
+                "--bwt=julian" => cli.bwt = BwtAlgorithm::Julian,
+                bwt if bwt.starts_with("--bwt=") => bad_argument(&format!("Unknown BWT algorithm: {}", bwt)),
+                iterations if iterations.starts_with("--iterations=") => {
+                    match iterations["--iterations=".len()..].parse::<usize>() {
+                        Ok(n) if n > 0 => cli.iterations = n,
+                        _ => bad_argument(&format!("Iterations must be a number of 1 or more: {}", iterations)),
+                    }
+                }
+                limit if limit.starts_with("--max-output=") => cli.limits.max_output = Some(limit_value(limit)),
+                limit if limit.starts_with("--max-ratio=") => cli.limits.max_ratio = Some(limit_value(limit)),
+                limit if limit.starts_with("--max-memory=") => cli.limits.max_memory = Some(limit_value(limit)),
+                limit if limit.starts_with("--max-streams=") => cli.limits.max_streams = Some(limit_value(limit)),
+                limit if limit.starts_with("--max-blocks=") => cli.limits.max_blocks = Some(limit_value(limit)),
 
                 other => eprintln!("Unexpected command line argument: {}", other),
             }
@@ -302,6 +378,21 @@ pub fn bzopts_init() -> BzOpts {
     cli
 }
 
+/// Return the number after the '=' of a --max-...=N argument. Exits with an error status if it isn't a number.
+fn limit_value(arg: &str) -> u64 {
+    let value = arg.split_once('=').map_or("", |(_, value)| value);
+    value
+        .parse()
+        .unwrap_or_else(|_| bad_argument(&format!("Limits must be a whole number: {}", arg)))
+}
+
+/// Report a command line argument that can't be used, and exit with an error status.
+fn bad_argument(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("Try `bzip2 --help' for more information.");
+    exit(1);
+}
+
 /// Prints help information
 fn help() {
     println!(
@@ -323,6 +414,18 @@ fn help() {
    -1 .. -9            set block size to 100k .. 900k
    --fast              alias for -1
    --best              alias for -9
+   --reference         produce output identical to the C version (slower)
+   --iterations=N      refine the huffman tables N times (default 4)
+   --high-effort       spend more time choosing huffman tables for smaller output
+   --code-lengths=X    build huffman code lengths with package-merge (default) or halving
+   --bwt=X             sort the BWT with auto (default), native, sais,
+                       sais-parallel or julian
+   --no-mmap           read input files instead of mapping them into memory
+   --max-output=N      stop decompressing after N bytes of output
+   --max-ratio=N       stop decompressing past N bytes of output per input byte
+   --max-memory=N      stop decompressing if the buffers need more than N bytes
+   --max-streams=N     stop decompressing after N streams
+   --max-blocks=N      stop decompressing after N blocks
    
     If invoked as `bzip2', default action is to compress.
               as `bunzip2',  default action is to decompress.
@@ -332,8 +435,7 @@ fn help() {
    from standard input to standard output.  You can combine
    short flags, so `-v -4' means the same as -v4 or -4v, &c.
 
-   Temporarily, you can specify one of these alogrithms for the BWT
-     -vvvvv (trace level debugging information)
+   For debugging, -vvvvv gives trace level information.
    "
     );
     exit(0);
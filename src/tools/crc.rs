@@ -7,23 +7,220 @@
 //!
 //! All the block CRCs are then combined to create an overall CRC value for the stream.
 //! 
-//! CRCs are build using a CRC value table included in this module.
+//! CRCs are build using a CRC value table included in this module. Long inputs are processed 16 bytes at a time
+//! using 16 derived tables (slicing-by-16). On x86_64 CPUs with the PCLMULQDQ instruction, inputs of CLMUL_MIN_LEN
+//! bytes or more are instead folded 64 bytes at a time using carry-less multiplication. All methods give the same
+//! result as the byte at a time loop.
+//!
+//! crc_combine joins the CRCs of two pieces of data, so a large block can be checksummed in parallel chunks.
 //!
  
 
 /// Calculate CRC on the block of data used to build each block that is compressed.
 pub fn do_crc(existing_crc: u32, data: &[u8]) -> u32 {
-    // For loop is a little faster than .fold
-    let mut crc = !existing_crc;
-    for b in data {
-        crc = (crc << 8) ^ BZ2_CRC32_TABLE[((crc >> 24) ^ (*b as u32)) as usize];
+    !crc_update(!existing_crc, data)
+}
+
+/// Add one byte to a CRC that is being built a byte at a time. Start with !0 and invert the final value.
+#[inline(always)]
+pub fn crc_byte(crc: u32, byte: u8) -> u32 {
+    (crc << 8) ^ BZ2_CRC32_TABLE[((crc >> 24) ^ (byte as u32)) as usize]
+}
+
+/// Calculate the CRC of data in parallel chunks, joining the chunk CRCs with crc_combine.
+pub fn do_crc_parallel(data: &[u8]) -> u32 {
+    use rayon::prelude::*;
+    const CHUNK: usize = 256 * 1024;
+    if data.len() <= CHUNK || rayon::current_num_threads() == 1 {
+        return do_crc(0, data);
     }
-    !crc
+    data.par_chunks(CHUNK)
+        .map(|chunk| (do_crc(0, chunk), chunk.len()))
+        .collect::<Vec<_>>()
+        .iter()
+        .fold(0, |crc, &(chunk_crc, len)| crc_combine(crc, chunk_crc, len))
+}
+
+/// Return the CRC of data_a followed by data_b, given the CRC of each and the length of data_b.
+pub fn crc_combine(crc_a: u32, crc_b: u32, len_b: usize) -> u32 {
+    /*
+    Ignoring the starting value and final inversion, the CRC register after data_b is the register from data_a
+    multiplied by x^(8 * len_b), plus what data_b alone would give. The starting value and final inversion cancel
+    out, so the same holds for the finished CRCs.
+    */
+    crc_b ^ gf2_mul_mod(crc_a, x8n_mod(len_b))
 }
 
 /// Calculate the stream CRC from each block_crc.
 pub fn do_stream_crc(strm_crc: u32, block_crc: u32) -> u32 {
-    (strm_crc << 1 | strm_crc >> 31) ^ block_crc
+    strm_crc.rotate_left(1) ^ block_crc
+}
+
+/// Run the CRC register (without the initial and final inversion) over data, using the fastest method available.
+fn crc_update(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if data.len() >= CLMUL_MIN_LEN && std::arch::is_x86_feature_detected!("pclmulqdq") {
+        // Safety: the CPU supports the instruction
+        return unsafe { clmul::crc_update(crc, data) };
+    }
+    slice16_update(crc, data)
+}
+
+/// Run the CRC register over data 16 bytes at a time.
+fn slice16_update(mut crc: u32, data: &[u8]) -> u32 {
+    let t = &SLICE16_TABLES;
+    let mut chunks = data.chunks_exact(16);
+    for c in &mut chunks {
+        // The register lines up with the first four bytes. Each byte is looked up in the table that shifts it past
+        // the bytes that follow it in the chunk.
+        let a = crc ^ u32::from_be_bytes([c[0], c[1], c[2], c[3]]);
+        crc = t[15][(a >> 24) as usize]
+            ^ t[14][(a >> 16) as usize & 0xFF]
+            ^ t[13][(a >> 8) as usize & 0xFF]
+            ^ t[12][a as usize & 0xFF]
+            ^ t[11][c[4] as usize]
+            ^ t[10][c[5] as usize]
+            ^ t[9][c[6] as usize]
+            ^ t[8][c[7] as usize]
+            ^ t[7][c[8] as usize]
+            ^ t[6][c[9] as usize]
+            ^ t[5][c[10] as usize]
+            ^ t[4][c[11] as usize]
+            ^ t[3][c[12] as usize]
+            ^ t[2][c[13] as usize]
+            ^ t[1][c[14] as usize]
+            ^ t[0][c[15] as usize];
+    }
+    for &b in chunks.remainder() {
+        crc = crc_byte(crc, b);
+    }
+    crc
+}
+
+/// The CRC polynomial, without its x^32 term.
+const POLY: u32 = 0x04c1_1db7;
+
+/// Multiply two polynomials modulo the CRC polynomial. The high bit is the highest power of x.
+fn gf2_mul_mod(a: u32, b: u32) -> u32 {
+    let mut product = 0_u32;
+    for bit in (0..32).rev() {
+        product = (product << 1) ^ if product & 0x8000_0000 != 0 { POLY } else { 0 };
+        if a >> bit & 1 != 0 {
+            product ^= b;
+        }
+    }
+    product
+}
+
+/// Return x^(8 * n) modulo the CRC polynomial, the effect of n bytes on the CRC register.
+fn x8n_mod(mut n: usize) -> u32 {
+    let mut result = 1;
+    let mut power = 1 << 8;
+    while n > 0 {
+        if n & 1 != 0 {
+            result = gf2_mul_mod(result, power);
+        }
+        power = gf2_mul_mod(power, power);
+        n >>= 1;
+    }
+    result
+}
+
+/// Table k gives the effect of a byte followed by k zero bytes. Table 0 is BZ2_CRC32_TABLE.
+static SLICE16_TABLES: [[u32; 256]; 16] = slice16_tables();
+
+const fn slice16_tables() -> [[u32; 256]; 16] {
+    let mut tables = [[0_u32; 256]; 16];
+    tables[0] = BZ2_CRC32_TABLE;
+    let mut k = 1;
+    while k < 16 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev << 8) ^ BZ2_CRC32_TABLE[(prev >> 24) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+/// Inputs shorter than this are not worth the setup of the carry-less multiply path.
+#[cfg(target_arch = "x86_64")]
+const CLMUL_MIN_LEN: usize = 256;
+
+#[cfg(target_arch = "x86_64")]
+mod clmul {
+    /*
+    The data is treated as one large polynomial, with the CRC register xored into its first four bytes. The CRC is
+    that polynomial times x^32 modulo the CRC polynomial P, so the data can be replaced by anything congruent to it
+    modulo P. Four 128 bit lanes hold consecutive 16 byte pieces of the data. Each step multiplies every lane by
+    x^512 (modulo P) and adds the piece 64 bytes further on, so the lanes stay congruent to the data read so far.
+    At the end the lanes are folded into one, and its 16 bytes are run through the table method.
+
+    Bit i of a u128 read big-endian from the data is the coefficient of x^i, which is the order PCLMULQDQ expects.
+    */
+    use std::arch::x86_64::{__m128i, _mm_clmulepi64_si128, _mm_set_epi64x};
+
+    /// x^k modulo P (with the x^32 term).
+    const fn x_pow_mod(k: u32) -> u64 {
+        let mut r = 1_u64;
+        let mut i = 0;
+        while i < k {
+            r <<= 1;
+            if r & (1 << 32) != 0 {
+                r ^= 0x1_04c1_1db7;
+            }
+            i += 1;
+        }
+        r
+    }
+
+    /// Multipliers for the high and low halves of a lane, to move it 128 and 512 bits along.
+    const FOLD_128: (u64, u64) = (x_pow_mod(128 + 64), x_pow_mod(128));
+    const FOLD_512: (u64, u64) = (x_pow_mod(512 + 64), x_pow_mod(512));
+
+    #[inline]
+    #[target_feature(enable = "pclmulqdq")]
+    unsafe fn clmul(a: u64, b: u64) -> u128 {
+        let product = _mm_clmulepi64_si128(_mm_set_epi64x(0, a as i64), _mm_set_epi64x(0, b as i64), 0x00);
+        std::mem::transmute::<__m128i, u128>(product)
+    }
+
+    /// Multiply a lane by x^distance modulo P, giving a polynomial of under 96 bits.
+    #[inline]
+    #[target_feature(enable = "pclmulqdq")]
+    unsafe fn fold(lane: u128, multipliers: (u64, u64)) -> u128 {
+        clmul((lane >> 64) as u64, multipliers.0) ^ clmul(lane as u64, multipliers.1)
+    }
+
+    fn load(bytes: &[u8]) -> u128 {
+        u128::from_be_bytes(bytes.try_into().unwrap())
+    }
+
+    /// Run the CRC register over data, which must be at least 64 bytes long.
+    #[target_feature(enable = "pclmulqdq")]
+    pub(super) unsafe fn crc_update(crc: u32, data: &[u8]) -> u32 {
+        let mut chunks = data.chunks_exact(64);
+        let first = chunks.next().unwrap();
+        let mut lanes = [
+            load(&first[0..16]) ^ (crc as u128) << 96,
+            load(&first[16..32]),
+            load(&first[32..48]),
+            load(&first[48..64]),
+        ];
+        for chunk in &mut chunks {
+            for (i, lane) in lanes.iter_mut().enumerate() {
+                *lane = fold(*lane, FOLD_512) ^ load(&chunk[i * 16..i * 16 + 16]);
+            }
+        }
+        let mut folded = lanes[0];
+        for lane in &lanes[1..] {
+            folded = fold(folded, FOLD_128) ^ lane;
+        }
+        let crc = super::slice16_update(0, &folded.to_be_bytes());
+        super::slice16_update(crc, chunks.remainder())
+    }
 }
 
 // CRC computation for bzip2
@@ -296,3 +493,64 @@ const BZ2_CRC32_TABLE: [u32; 256] = [
     0xb5365d03u32,
     0xb1f740b4u32,
 ];
+
+#[cfg(test)]
+mod test {
+    use super::{crc_byte, crc_combine, do_crc, do_crc_parallel, slice16_update};
+
+    fn bytewise_crc(data: &[u8]) -> u32 {
+        !data.iter().fold(!0, |crc, &b| crc_byte(crc, b))
+    }
+
+    fn lcg_data(len: usize) -> Vec<u8> {
+        let mut seed = 7_u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_value_test() {
+        // The standard check value for CRC-32/BZIP2
+        assert_eq!(do_crc(0, b"123456789"), 0xfc891918);
+        assert_eq!(do_crc(0, b""), 0);
+    }
+
+    #[test]
+    fn methods_agree_test() {
+        let data = lcg_data(5_000);
+        for start in 0..17 {
+            for len in (0..1_000).chain([4_000, 4_983]) {
+                let piece = &data[start..start + len];
+                let expected = bytewise_crc(piece);
+                assert_eq!(do_crc(0, piece), expected, "start {} len {}", start, len);
+                assert_eq!(!slice16_update(!0, piece), expected, "start {} len {}", start, len);
+                #[cfg(target_arch = "x86_64")]
+                if len >= 64 && std::arch::is_x86_feature_detected!("pclmulqdq") {
+                    assert_eq!(!unsafe { super::clmul::crc_update(!0, piece) }, expected);
+                }
+            }
+        }
+        // Continuing from an existing CRC
+        assert_eq!(do_crc(do_crc(0, &data[..1_234]), &data[1_234..]), bytewise_crc(&data));
+    }
+
+    #[test]
+    fn combine_test() {
+        let data = lcg_data(3_000);
+        for split in [0, 1, 3, 16, 100, 1_000, 2_999, 3_000] {
+            let (a, b) = data.split_at(split);
+            assert_eq!(crc_combine(do_crc(0, a), do_crc(0, b), b.len()), do_crc(0, &data), "split {}", split);
+        }
+    }
+
+    #[test]
+    fn parallel_test() {
+        let data = lcg_data(1_000_000);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        assert_eq!(pool.install(|| do_crc_parallel(&data)), bytewise_crc(&data));
+    }
+}
@@ -1,15 +1,18 @@
 //! Perform the first run-length-encoding (RLE1) transform for the BZIP2 file formate.
 //!
-//! The run-length-encoding compresses runs of 4-256 identical bytes in the initial data. If a run is longer than 256 bytes, it may be
-//! encoded as two or more runs. (258 identical bytes will be see as one run of 256 bytes and two identical bytes (which is not long enough
+//! The run-length-encoding compresses runs of 4-255 identical bytes in the initial data. If a run is longer than 255 bytes, it may be
+//! encoded as two or more runs. (257 identical bytes will be see as one run of 255 bytes and two identical bytes (which is not long enough
 //! to be encoded as another run.)
 //!
+//! Blocks are split exactly where the C version splits them: a run is never split across two blocks, so a block
+//! may hold up to 4 bytes more than the requested block size.
+//!
 //! The RLE1 phase happens **before** determining what data will be placed into each block. Because of this,
 //! the RLE1 phase is build as an iterator over the raw data. You must instantiate a RLE1Block struct before
 //! you can use it.
 //!
 //! Usage is:
-//! ```
+//! ```ignore
 //! let mut rle1 = RLE1Block::new(data, block_size);
 //! ```
 //! Where:
@@ -17,244 +20,175 @@
 //! - block_size: The actual size of each block that will be created.
 //!
 //! To get a block of data, you must iterate or call .next() on the struct. For example:
-//! ```
-//! let (crc, block, last_block) = rle1.next().unwrap();
+//! ```ignore
+//! let (crc, block, last_block) = rle1.next().unwrap()?;
 //! ```
 //! This returns the crc value for the block, the block of data, and a boolean indicating if the block is the last block.
+//! If reading the data fails, the error is returned in place of the block.
+//!
+//! ParallelRle1 produces the same blocks using several threads. It returns them a batch at a time:
+//! ```ignore
+//! let mut rle1 = ParallelRle1::new(data, block_size);
+//! let blocks = rle1.next_batch()?;
+//! ```
+//! A block boundary depends on all the RLE1 data before it, but a run always starts where the byte value changes.
+//! So the input is cut into chunks at such points and each chunk is measured on its own thread. The measurements
+//! give the exact block boundaries, and then the blocks are encoded in parallel.
+//!
+//! Both read their input through a ByteSource. new() reads from any Read source, and from_source() takes a
+//! MemorySource (such as a memory mapped file) that is split in place without copying.
 //!
 //! 
 
 use super::crc::do_crc;
+use rayon::prelude::*;
+use crate::bitstream::byte_source::{ByteSource, ReadSource};
+use std::io::{self, Read};
 
+/// Longest run encoded as one run (4 bytes and a count of 251).
+const MAX_RUN_LEN: usize = 255;
 const MAX_RUN: usize = 256 + 4;
 
-/// Iteratable struct that will return a block with a max size of block_size bytes
-/// encoded using BZIP2 RLE 1 style encoding
-pub struct RLE1Block<R>
-where
-    R: std::io::Read + std::marker::Sync + std::marker::Send,
-{
-    source: R,
+/// Iteratable struct that will return blocks of at least block_size bytes (or the rest of the data)
+/// encoded using BZIP2 RLE 1 style encoding. Blocks may be up to 4 bytes longer than block_size.
+pub struct RLE1Block<S> {
+    source: S,
     block_size: usize,
-    buffer: Vec<u8>,
-    buffer_cursor: usize,
-    data_gone: bool,
+    /// Position of the next byte to encode in source.data().
+    cursor: usize,
     pub block_crc: u32,
 }
 
-impl<R> RLE1Block<R>
-where
-    R: std::io::Read + std::marker::Sync + std::marker::Send,
-{
+impl<R: Read> RLE1Block<ReadSource<R>> {
     pub fn new(source: R, block_size: usize) -> Self {
+        Self::from_source(ReadSource::with_capacity(source, block_size), block_size)
+    }
+}
+
+impl<S: ByteSource> RLE1Block<S> {
+    /// Create an iterator over the blocks of a ByteSource.
+    pub fn from_source(source: S, block_size: usize) -> Self {
         RLE1Block {
             source,
             block_size,
-            buffer: Vec::with_capacity(block_size + 264),
-            buffer_cursor: 0,
-            data_gone: false,
+            cursor: 0,
             block_crc: 0,
         }
     }
 
-    /// Check (and refill) a low buffer - true if we have data, false if there is no more.
-    /// Refill when there is less than 256 bytes. We want to keep that many for comparision in case
-    /// the run happens over the end of our last read.
-    fn refill_buffer(&mut self) -> bool {
-        // If we have less than 256 bytes of data in our buffer, go try to get more
-        if self.data_gone || self.buffer.len() - self.buffer_cursor < MAX_RUN {
-            // First, removed data we have already processed
-            self.buffer.drain(..self.buffer_cursor);
-            self.buffer_cursor = 0;
-            // Then get more data
-            let mut temp_buffer = vec![0; self.block_size];
-            let received = self
-                .source
-                .read(&mut temp_buffer)
-                .expect("Unble to read source data");
-            // Append the new data to our buffer and adjust our counter for how much we have left.
-            temp_buffer.truncate(received);
-            self.buffer.append(&mut temp_buffer);
-            // If we read all the data, remember that it is gone.
-            if received < self.block_size {
-                self.data_gone = true;
-                return false;
-            }
+    /// Refill a low buffer. Refill when there is less than MAX_RUN bytes. We want to keep that many for comparision
+    /// in case the run happens over the end of our last read.
+    fn refill_buffer(&mut self) -> io::Result<()> {
+        if !self.source.is_done() && self.source.data().len() - self.cursor < MAX_RUN {
+            // First, drop data we have already processed, then get more data
+            self.source.consume(self.cursor);
+            self.cursor = 0;
+            self.source.fill(MAX_RUN)?;
         }
-        true
+        Ok(())
     }
 
     /// Encode runs of for our more identical bytes, pre-BWT. Returns a crc of the original data used,
     ///  the RLE1 data, and a bool set to true if this is the last block.
-    fn get_block(&mut self) -> (u32, Vec<u8>, bool) {
+    fn get_block(&mut self) -> io::Result<(u32, Vec<u8>, bool)> {
         /*
-        This is optimized for speed. It scans the input for runs of 4 identical bytes. A run can be anywhere
-        from 0-251 identical bytes after the run. This means our buffer should be at least 256 bytes long so
-        we can scan to the end of the longest run we can encode.
+        This follows the C version exactly so that block boundaries are identical. The input is split into runs
+        of identical bytes of at most 255 bytes. Each run is added to the block whole - 1-3 bytes are copied as they
+        are, 4-255 bytes become 4 bytes and a count. We keep adding runs until the block holds at least block_size
+        bytes, so a block can end up to 4 bytes longer than block_size.
 
-        We must build a crc of the input data (not the RLE1 data). Since we only add data to the output
-        whenever we encounter a run (or reach the end of the block, or have to refill the buffer), we will
-        only compute the current crc at those times.
+        We must build a crc of the input data (not the RLE1 data). We compute it over the input we have consumed
+        whenever the buffer needs to be refilled and when the block is done.
         */
 
-        // Since we only get here by the iterator, we can assume we have data in our buffer.
-        // Reserve space for the output, allowing an extra byte in case we end in a run and need to add a count
-        let mut out: Vec<u8> = Vec::with_capacity(self.block_size + 1);
-        // Pushing a byte at a time to the output is slower than extending by chunks, therefore initialize
-        // variable to remember where each chunk of non-runs starts so we can append data in chunks.
-        let mut start: usize = 0;
-        // Set a counter to the amount of unproccessed data in the buffer, checking for no data
-        let mut remaining = self.buffer.len();
-
-        // Now process the input until we reach our desired amount (including what is in a pending chunk)
-        while out.len() + (self.buffer_cursor - start) < self.block_size {
-            // Check how many bytes are remaining in our buffer
-            match remaining {
-                // In the case that we have 0 bytes left, all the data should be processed. We should only
-                // get here when the data ends with a run of duplicates. Clean up and return.
-                0 => {
-                    // Empty the buffer and reset the cursor
-                    self.buffer.clear();
-                    self.buffer_cursor = 0;
-                    return (
-                        self.block_crc,
-                        out,
-                        self.data_gone && self.buffer.is_empty(),
-                    );
-                }
-                // In the case that we have only 1, 2 or 3 bytes left, we don't need to look for runs.
-                1..=3 => {
-                    // Get to the end of the buffer
-                    self.buffer_cursor = self.buffer.len();
-                    out.extend_from_slice(&self.buffer[start..self.buffer_cursor]);
-                    self.block_crc =
-                        do_crc(self.block_crc, &self.buffer[start..self.buffer_cursor]);
-                    self.buffer.drain(..self.buffer_cursor);
-                    self.buffer_cursor = 0;
-                    return (
-                        self.block_crc,
-                        out,
-                        self.data_gone && self.buffer.is_empty(),
-                    );
-                }
-                // Otherwise we still need to look for runs.
-                _ => {
-                    // If the buffer is low, first copy out what we have processed and then go refill it.
-                    if remaining < MAX_RUN && !self.data_gone {
-                        // First shift the cursor back one. (If we get here after writing dups, we have
-                        //  advanced it one past where we have last checked.)
-                        self.buffer_cursor -= 1;
-                        self.block_crc =
-                            do_crc(self.block_crc, &self.buffer[start..self.buffer_cursor]);
-                        out.extend_from_slice(&self.buffer[start..self.buffer_cursor]);
-                        self.buffer.drain(..self.buffer_cursor);
-                        self.buffer_cursor = 0;
-                        self.refill_buffer();
-                        remaining = self.buffer.len();
-                        start = 0;
-                    }
-                    // Check to make sure we never begin at index 0 of the buffer.
-                    if self.buffer_cursor == 0 {
-                        self.buffer_cursor += 1;
-                        remaining -= 1;
-                    }
-
-                    // Then look for a run of 4 bytes, adjusting the remaining counter as appropriate
-                    // Look for a run of 4 identical bytes by first looking for two that are two bytes apart.
-                    if self.buffer[self.buffer_cursor] != self.buffer[self.buffer_cursor + 2] {
-                        self.buffer_cursor += 2;
-                        remaining -= 2;
-                        continue;
-                    } // Found a set, now check the byte between and fail if it's not the same.
-                    if self.buffer[self.buffer_cursor] != self.buffer[self.buffer_cursor + 1] {
-                        self.buffer_cursor += 2;
-                        remaining -= 2;
-                        continue;
-                    }
-                    // Middle byte is okay, so check the byte before and after, and fail if both tests show misses.
-                    if (self.buffer[self.buffer_cursor] != self.buffer[self.buffer_cursor - 1])
-                        && (self.buffer[self.buffer_cursor] != self.buffer[self.buffer_cursor + 3])
-                    {
-                        self.buffer_cursor += 2;
-                        remaining -= 2;
-                        continue;
-                    }
-                    // We are now at a run of 4. If the byte before was the good one, move the cursor back one byte.
-                    if self.buffer[self.buffer_cursor] == self.buffer[self.buffer_cursor - 1] {
-                        self.buffer_cursor -= 1;
-                    }
-                    // We are now at a run of 4
-                    {
-                        // Get the count of duplicates following (0-251)
-                        let dups = self.count_dups();
-                        // reset the buffer cursor to the end of the run of 4
-                        self.buffer_cursor += 4 + dups as usize;
-                        // If they are, calculate the CRC from the last start until the end of the duplicates
-                        self.block_crc = do_crc(
-                            self.block_crc,
-                            &self.buffer[start..(self.buffer_cursor as usize)],
-                        );
-                        out.extend_from_slice(
-                            &self.buffer[start..self.buffer_cursor - dups as usize],
-                        );
-                        // Write the duplicate count
-                        out.push(dups);
-                        // Reset start to this new chunk
-                        start = self.buffer_cursor;
-                        // Move the cursor one past the start position
-                        self.buffer_cursor += 1;
-                        // Update the remaining counter
-                        remaining = self.buffer.len() - start;
-                    }
-                }
+        // Reserve space for the output, allowing for a run that ends past block_size
+        let mut out: Vec<u8> = Vec::with_capacity(self.block_size + 4);
+        // Where the input for the crc starts in the source data
+        let mut start = self.cursor;
+
+        while out.len() < self.block_size {
+            let data_gone = self.source.is_done();
+            // If the buffer is low, update the crc with what we have processed and then go refill it.
+            if self.source.data().len() - self.cursor < MAX_RUN && !data_gone {
+                self.block_crc = do_crc(self.block_crc, &self.source.data()[start..self.cursor]);
+                self.refill_buffer()?;
+                start = 0;
+                continue;
+            }
+            if self.cursor == self.source.data().len() {
+                break;
             }
+            // Encode runs until the block is full or the buffer is low. Once the data is gone, use all of it.
+            let lookahead = if data_gone { 1 } else { MAX_RUN };
+            self.cursor += rle1_encode_runs(&self.source.data()[self.cursor..], &mut out, self.block_size, lookahead);
         }
-        // We filled the block (or can with the pending append), so return it
-        self.block_crc = do_crc(self.block_crc, &self.buffer[start..self.buffer_cursor]);
-        out.extend_from_slice(&self.buffer[start..self.buffer_cursor]);
-        self.buffer.drain(..self.buffer_cursor);
-        self.buffer_cursor = 0;
-        // Return the output
-        (
-            self.block_crc,
-            out,
-            self.data_gone && self.buffer.is_empty(),
-        )
+        self.block_crc = do_crc(self.block_crc, &self.source.data()[start..self.cursor]);
+
+        // If we used everything in the buffer, look ahead so we know whether this is the last block.
+        self.refill_buffer()?;
+        let last_block = self.source.is_done() && self.cursor == self.source.data().len();
+        Ok((self.block_crc, out, last_block))
     }
+}
 
-    /// Helper function for rel1_encode to count how many duplicate bytes occur (0-251).
-    fn count_dups(&self) -> u8 {
-        // Test if the byte after the run is the same as the bytes in the run. If not, return 0
-        if self.buffer[self.buffer_cursor] != self.buffer[self.buffer_cursor + 4] {
-            return 0;
+/// Encode whole runs from input onto out, until out holds at least block_size bytes. A run is only measured while at
+/// least lookahead (1 or more) bytes of input remain, so that more input can be added before a run is cut short.
+/// Returns the number of input bytes used.
+pub fn rle1_encode_runs(input: &[u8], out: &mut Vec<u8>, block_size: usize, lookahead: usize) -> usize {
+    let mut cursor = 0;
+    while out.len() < block_size && input.len() - cursor >= lookahead.max(1) {
+        let rest = &input[cursor..];
+        let run = run_length(rest);
+        if run < 4 {
+            out.extend_from_slice(&rest[..run]);
+        } else {
+            out.extend_from_slice(&rest[..4]);
+            out.push((run - 4) as u8);
         }
-        // self.buffer_cursor is the position of the first of four identical bytes. We need to count
-        // identical bytes *after* the fourth. If position returns None, then return 256 or the data
-        // remaining, if less.
-        let compare = self.buffer[self.buffer_cursor];
-        self.buffer
-            .iter()
-            .skip(self.buffer_cursor + 4)
-            .take(251)
-            .position(|&x| x != compare)
-            .unwrap_or_else(|| 251.min(self.buffer.len() - (self.buffer_cursor + 4))) as u8
+        cursor += run;
+    }
+    cursor
+}
+
+/// Same as rle1_encode_runs with a lookahead of 1, but only counts the output. Returns the number of input bytes
+/// used and the number of RLE1 bytes they encode to.
+fn rle1_measure_runs(input: &[u8], block_size: usize) -> (usize, usize) {
+    let (mut cursor, mut out_len) = (0, 0);
+    while out_len < block_size && cursor < input.len() {
+        let run = run_length(&input[cursor..]);
+        out_len += run.min(4) + usize::from(run >= 4);
+        cursor += run;
+    }
+    (cursor, out_len)
+}
+
+/// Length of the run at the start of rest (1-255 bytes). rest must not be empty.
+#[inline]
+fn run_length(rest: &[u8]) -> usize {
+    // Most of the time the next byte differs, so check that first.
+    let byte = rest[0];
+    if rest.len() > 1 && rest[1] != byte {
+        1
+    } else {
+        rest.iter()
+            .take(MAX_RUN_LEN)
+            .position(|&x| x != byte)
+            .unwrap_or_else(|| MAX_RUN_LEN.min(rest.len()))
     }
 }
 
-/// Iterator for RLE1 encoding.
-impl<R> Iterator for RLE1Block<R>
-where
-    R: std::io::Read + std::marker::Sync + std::marker::Send,
-{
-    type Item = (u32, Vec<u8>, bool);
-    fn next(&mut self) -> Option<(u32, Vec<u8>, bool)> {
-        // If there is no data to process, return None (Nothing to read and an empty buffer).
-        if self.data_gone && self.buffer.is_empty() {
+/// Iterator for RLE1 encoding. A failed read is returned as an error in place of the block.
+impl<S: ByteSource> Iterator for RLE1Block<S> {
+    type Item = io::Result<(u32, Vec<u8>, bool)>;
+    fn next(&mut self) -> Option<io::Result<(u32, Vec<u8>, bool)>> {
+        // Make sure the buffer is full. If there is still no data to process, return None (nothing to read and an
+        // empty buffer). An empty source has no blocks at all.
+        if let Err(e) = self.refill_buffer() {
+            return Some(Err(e));
+        }
+        if self.cursor == self.source.data().len() {
             return None;
         }
-
-        // Otherwise, make sure the buffer is full.
-        self.refill_buffer();
         // And clear the block crc value.
         self.block_crc = 0;
 
@@ -263,54 +197,321 @@ where
     }
 }
 
+/// Input is split into chunks of about this many bytes for the parallel measuring pass.
+const PARALLEL_CHUNK: usize = 256 * 1024;
+/// The measuring pass records a checkpoint each time it has counted this many more RLE1 bytes.
+const CHECKPOINT_STEP: usize = 16 * 1024;
+
+/// Splits its source into the same blocks as RLE1Block, using several threads. The source is read a batch of blocks
+/// at a time. Each batch is measured in chunks in parallel, the exact block cut points are found from those
+/// measurements, and then the blocks are encoded (and their CRCs computed) in parallel.
+pub struct ParallelRle1<S> {
+    /// Input not yet returned in a block. Its data always starts at the start of a run.
+    source: S,
+    block_size: usize,
+    /// Input bytes to split for a batch.
+    batch_size: usize,
+    /// Size of the chunks measured in parallel.
+    chunk_size: usize,
+    /// RLE1 data of the unfinished block at the start of the source data (when splitting sequentially).
+    partial: Vec<u8>,
+    /// Input bytes encoded into partial.
+    partial_used: usize,
+}
+
+impl<R: Read> ParallelRle1<ReadSource<R>> {
+    /// Create a splitter that reads source a batch at a time.
+    pub fn new(source: R, block_size: usize) -> Self {
+        Self::from_source(ReadSource::new(source), block_size)
+    }
+}
+
+impl<S: ByteSource> ParallelRle1<S> {
+    /// Create a splitter for blocks of block_size RLE1 bytes. A batch holds about one block per rayon thread.
+    pub fn from_source(source: S, block_size: usize) -> Self {
+        let batch_size = block_size * rayon::current_num_threads();
+        Self::with_sizes(source, block_size, batch_size, PARALLEL_CHUNK)
+    }
+
+    fn with_sizes(source: S, block_size: usize, batch_size: usize, chunk_size: usize) -> Self {
+        ParallelRle1 {
+            source,
+            block_size: block_size.max(1),
+            batch_size: batch_size.max(1),
+            chunk_size: chunk_size.max(1),
+            partial: vec![],
+            partial_used: 0,
+        }
+    }
+
+    /// Return the next batch of blocks in the form the RLE1Block iterator returns them: the crc of the input used,
+    /// the RLE1 data and whether it is the last block. An empty batch means all the data has been returned.
+    pub fn next_batch(&mut self) -> io::Result<Vec<(u32, Vec<u8>, bool)>> {
+        let mut target = self.batch_size;
+        loop {
+            self.source.fill(target)?;
+            // A memory source has all of the input available, so only a batch of it is split
+            let available = self.source.data().len();
+            let data = &self.source.data()[..available.min(target)];
+            let complete = self.source.is_done() && data.len() == available;
+            // With only one thread, measuring first would just be extra work
+            let (blocks, used) = if rayon::current_num_threads() > 1 {
+                self.partial.clear();
+                self.partial_used = 0;
+                parallel_blocks(data, self.block_size, self.chunk_size, complete)
+            } else {
+                sequential_blocks(data, self.block_size, complete, &mut self.partial, &mut self.partial_used)
+            };
+            if !blocks.is_empty() || complete {
+                self.source.consume(used);
+                return Ok(blocks);
+            }
+            // Not even one whole block yet. Runs compress up to 51 times, so this can take a lot more input.
+            target = data.len() * 2;
+        }
+    }
+}
+
+/// Measure data to find the block cut points, then encode the blocks in parallel. Returns the blocks and the number
+/// of input bytes they used.
+fn parallel_blocks(data: &[u8], block_size: usize, chunk_size: usize, complete: bool) -> (Vec<(u32, Vec<u8>, bool)>, usize) {
+    let cuts = block_cuts(data, block_size, chunk_size, complete);
+    let ranges = std::iter::once(0).chain(cuts.iter().copied()).zip(cuts.iter().copied());
+    let blocks = ranges
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(start, end)| {
+            let mut out = Vec::with_capacity(block_size + 4);
+            rle1_encode_runs(&data[start..end], &mut out, usize::MAX, 1);
+            (do_crc(0, &data[start..end]), out, complete && end == data.len())
+        })
+        .collect();
+    (blocks, cuts.last().copied().unwrap_or(0))
+}
+
+/// Encode the blocks in data one after the other, as RLE1Block does. An unfinished block is kept in partial (with
+/// the count of input bytes it used in partial_used) and continued on the next call. Returns the blocks and the
+/// number of input bytes they used.
+fn sequential_blocks(
+    data: &[u8],
+    block_size: usize,
+    complete: bool,
+    partial: &mut Vec<u8>,
+    partial_used: &mut usize,
+) -> (Vec<(u32, Vec<u8>, bool)>, usize) {
+    // Until the data is complete, a run is only measured when all of it is in data
+    let lookahead = if complete { 1 } else { MAX_RUN };
+    let mut blocks = vec![];
+    let mut start = 0;
+    let mut end = *partial_used;
+    let mut out = std::mem::take(partial);
+    while end < data.len() {
+        out.reserve(block_size + 4);
+        end += rle1_encode_runs(&data[end..], &mut out, block_size, lookahead);
+        if out.len() < block_size && !complete {
+            break;
+        }
+        blocks.push((do_crc(0, &data[start..end]), std::mem::take(&mut out), complete && end == data.len()));
+        start = end;
+    }
+    if end > start && !complete {
+        (*partial, *partial_used) = (out, end - start);
+    } else {
+        *partial_used = 0;
+    }
+    (blocks, start)
+}
+
+/// Find the input positions where the blocks in data end. Data must start at the start of a run. If complete is
+/// false more data may follow, so a block is only cut where every run before the cut was measured in full.
+fn block_cuts(data: &[u8], block_size: usize, chunk_size: usize, complete: bool) -> Vec<usize> {
+    // Chunks start where the byte value changes, which always starts a run. No run crosses a chunk boundary, so each
+    // chunk can be measured on its own.
+    let mut starts = vec![0];
+    for nominal in (chunk_size..data.len()).step_by(chunk_size) {
+        let last = *starts.last().unwrap();
+        if nominal <= last {
+            continue;
+        }
+        match (nominal..data.len()).find(|&p| data[p] != data[p - 1]) {
+            Some(start) => starts.push(start),
+            None => break,
+        }
+    }
+    starts.push(data.len());
+
+    // Measure the chunks, recording (input, output) checkpoints at run boundaries
+    let measured = starts
+        .par_windows(2)
+        .map(|chunk| {
+            let (start, end) = (chunk[0], chunk[1]);
+            let mut checkpoints = vec![];
+            let (mut used, mut out_len) = (0, 0);
+            while start + used < end {
+                let (more_used, more_out) = rle1_measure_runs(&data[start + used..end], CHECKPOINT_STEP);
+                used += more_used;
+                out_len += more_out;
+                checkpoints.push((start + used, out_len));
+            }
+            checkpoints
+        })
+        .collect::<Vec<_>>();
+    let mut checkpoints = vec![(0, 0)];
+    for chunk in measured {
+        let base = checkpoints.last().unwrap().1;
+        checkpoints.extend(chunk.into_iter().map(|(used, out_len)| (used, base + out_len)));
+    }
+    let total_out = checkpoints.last().unwrap().1;
+
+    // Cut the blocks in order. Each block is measured from the last checkpoint before it fills up.
+    let mut cuts = vec![];
+    let (mut in_start, mut out_start) = (0, 0);
+    loop {
+        let target = out_start + block_size;
+        if total_out < target {
+            // The rest is the last block, unless more data may follow
+            if complete && in_start < data.len() {
+                cuts.push(data.len());
+            }
+            break;
+        }
+        let checkpoint = checkpoints[checkpoints.partition_point(|&(_, out_len)| out_len < target) - 1];
+        let (from_in, from_out) = if checkpoint.0 > in_start { checkpoint } else { (in_start, out_start) };
+        let (used, out_len) = rle1_measure_runs(&data[from_in..], target - from_out);
+        let cut = from_in + used;
+        if !complete && cut + MAX_RUN > data.len() {
+            break;
+        }
+        cuts.push(cut);
+        (in_start, out_start) = (cut, from_out + out_len);
+    }
+    cuts
+}
+
 /// Unencodes runs of four or more characters from the RLE1 phase
 pub fn rle1_decode(rle1: &[u8]) -> Vec<u8> {
     /*
-    Logic: This is similar to the encoding. Start looking for a sequence of 4 identical bytes.
-    When you find them, get the next byte, which is a count of how many more such bytes are needed.
+    Logic: Count identical bytes as we copy them out. After a sequence of 4 identical bytes, the next byte is a
+    count of how many more such bytes are needed. The byte after the count starts a new sequence.
 
-    First output everthing from the start until the end of the sequence we found (not counting the
-    count byte) followed by a vec created with the repeating byte we want to insert.
+    (The decompressor uses the same logic in bwt_rle1_decode, fused with the BWT decoding.)
     */
 
-    // Initialize cursors for moving through a slice of the rle1 data
-    let mut start = 0;
-    let mut cursor = 1_usize;
-    // Initialize the output vec with 120% capacity of the input, which should cover most cases.
+    // Initialize the output vec with 125% capacity of the input, which should cover most cases.
     let mut out = Vec::with_capacity(rle1.len() * 5 / 4);
+    let mut prev = None;
+    let mut run = 0;
 
-    // Process the RLE1 data.
-    while cursor < rle1.len() - 4 {
-        // Look for a run of 4 identical bytes by first looking for two that are two bytes apart.
-        if rle1[cursor] != rle1[cursor + 2] {
-            cursor += 2;
-            continue;
-        } // Found a set, now check the byte between and fail if it's not the same.
-        if rle1[cursor] != rle1[cursor + 1] {
-            cursor += 2;
+    for &byte in rle1 {
+        if run == 4 {
+            // This is a count byte. Add that many more of the repeating byte.
+            out.resize(out.len() + byte as usize, prev.unwrap_or_default());
+            prev = None;
+            run = 0;
             continue;
         }
-        // That is okay, so check the byte before, then the byte after, and fail if one or the other is not the same.
-        if (rle1[cursor] != rle1[cursor - 1]) && (rle1[cursor] != rle1[cursor + 3]) {
-            cursor += 2;
-            continue;
+        if Some(byte) == prev {
+            run += 1;
+        } else {
+            prev = Some(byte);
+            run = 1;
         }
-        // If the byte before was the good one, move the cursor back one byte
-        if rle1[cursor] == rle1[cursor - 1] {
-            cursor -= 1;
+        out.push(byte);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rle1_decode, ParallelRle1, RLE1Block};
+    use crate::bitstream::byte_source::{ByteSource, MemorySource, ReadSource};
+    use std::io::{self, Read};
+
+    /// Returns at most 1000 bytes per read, as a pipe might.
+    struct ShortReads<'a>(&'a [u8]);
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
         }
-        // We are now at a run of 4
-        {
-            // Copy out the slice from the start cursor until the end of the run
-            out.extend_from_slice(&rle1[start..cursor + 4]);
-            // Create a vec of the repeating byte with a length taken from the byte following the run,
-            //  and add that data to the output
-            out.extend(vec![rle1[cursor]; usize::from(rle1[cursor + 4])]);
-            cursor += 5;
-            start = cursor;
+    }
+
+    /// Runs of many lengths (including 255-260 and several thousand), with a few plain bytes between them.
+    fn run_data() -> Vec<u8> {
+        let mut seed = 7_u32;
+        let mut data = vec![];
+        for i in 0..3_000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let len = match i % 7 {
+                0 => 255 + (seed >> 16) as usize % 6,
+                1 => (seed >> 16) as usize % 5_000,
+                _ => 1 + (seed >> 16) as usize % 5,
+            };
+            data.extend(std::iter::repeat_n((seed >> 24) as u8 % 3, len));
         }
-        cursor += 1;
+        data
+    }
+
+    /// Split source with ParallelRle1 on a pool of the given number of threads, returning all the blocks.
+    fn split_parallel<S: ByteSource + Send>(
+        source: S,
+        sizes: (usize, usize, usize),
+        threads: usize,
+    ) -> Vec<(u32, Vec<u8>, bool)> {
+        let (block_size, batch_size, chunk_size) = sizes;
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let mut splitter = ParallelRle1::with_sizes(source, block_size, batch_size, chunk_size);
+        let mut blocks = vec![];
+        pool.install(|| loop {
+            let batch = splitter.next_batch().unwrap();
+            if batch.is_empty() {
+                break;
+            }
+            blocks.extend(batch);
+        });
+        blocks
+    }
+
+    #[test]
+    fn parallel_matches_sequential_test() {
+        let inputs = [run_data(), vec![0; 300_000], b"abcd".repeat(50_000), vec![], b"x".to_vec()];
+        for data in &inputs {
+            for sizes in [(100_000, 250_000, 20_000), (5_000, 7_000, 999), (40, 1, 1)] {
+                let expected = RLE1Block::new(&data[..], sizes.0).collect::<io::Result<Vec<_>>>().unwrap();
+                let in_place =
+                    RLE1Block::from_source(MemorySource::new(data), sizes.0).collect::<io::Result<Vec<_>>>().unwrap();
+                assert!(in_place == expected, "Blocks read in place differ for {:?}", sizes);
+                for threads in [1, 4] {
+                    let blocks = split_parallel(ReadSource::new(ShortReads(data)), sizes, threads);
+                    assert!(blocks == expected, "Blocks differ for {:?} on {} threads", sizes, threads);
+                    let blocks = split_parallel(MemorySource::new(data), sizes, threads);
+                    assert!(blocks == expected, "Blocks read in place differ for {:?} on {} threads", sizes, threads);
+                }
+                let decoded = expected.iter().flat_map(|(_, block, _)| rle1_decode(block)).collect::<Vec<u8>>();
+                assert!(decoded == *data);
+            }
+        }
+    }
+
+    /// A reader that returns its data, then fails.
+    struct Broken<'a>(&'a [u8]);
+    impl Read for Broken<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::other("device error"));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn read_error_test() {
+        // A read error part way through a block is returned instead of the block, not as a panic
+        let data = run_data();
+        let mut blocks = RLE1Block::new(Broken(&data[..data.len() / 2]), 5_000);
+        let error = blocks.by_ref().find_map(Result::err).unwrap();
+        assert_eq!(error.to_string(), "device error");
     }
-    out.extend_from_slice(&rle1[start..rle1.len()]);
-    out
 }
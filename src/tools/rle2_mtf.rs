@@ -11,16 +11,21 @@
 //! This module also returns a frequency table and symbol map used during the huffman stage. This is done at this stage because it 
 //! is more efficient to build these items while processing the data rather than re-processing the data to build them.
 //! 
-use super::freq_count::freqs;
-use log::error;
 
 const RUNA: u16 = 0;
 const RUNB: u16 = 1;
-const ZERO_BOMB: usize = 2 * 1024 * 1024;
 
 /// Does Move-To-Front transforma and Run-Length-Encoding 2 prior to the huffman stage.
 /// Receives a block of BWT data. Returns the rle2 data, an array containing a frequency map, and a symbol map.
-pub fn rle2_mtf_encode(block: &[u8]) -> (Vec<u16>, [u32; 256], Vec<u16>) {
+pub fn rle2_mtf_encode(block: &[u8]) -> (Vec<u16>, [u32; 258], Vec<u16>) {
+    let mut rle2 = vec![];
+    let (freqs, sym_map) = rle2_mtf_encode_into(block, &mut rle2);
+    (rle2, freqs, sym_map)
+}
+
+/// Same as rle2_mtf_encode, but the rle2 data is written to rle2 so the vec can be reused from block to block.
+/// Returns the frequency map and the symbol map.
+pub fn rle2_mtf_encode_into(block: &[u8], rle2: &mut Vec<u16>) -> ([u32; 258], Vec<u16>) {
     // Create a custom index of the input, using an array for speed
     // Start by finding every u8 in the input.
     let mut bool_array = vec![false; 256];
@@ -53,9 +58,10 @@ pub fn rle2_mtf_encode(block: &[u8]) -> (Vec<u16>, [u32; 256], Vec<u16>) {
     // Initialize an index into the output vec (block.rle2)
     let mut out_idx = 0_usize;
     // Size the rle2
-    let mut rle2 = vec![0_u16; block.len() + 1];
-    // Initialize a frequency table
-    let mut freqs = [0_u32; 256];
+    rle2.clear();
+    rle2.resize(block.len() + 1, 0);
+    // Initialize a frequency table, indexed by output symbol (RUNA, RUNB, 2..=EOB)
+    let mut freqs = [0_u32; 258];
 
     // ...then do the transform (VecDeque saves a tiny bit of time over a vec)
     for byte in block {
@@ -101,36 +107,36 @@ pub fn rle2_mtf_encode(block: &[u8]) -> (Vec<u16>, [u32; 256], Vec<u16>) {
             }
         }
         // Update the frequency count
-        freqs[idx] += 1;
+        freqs[idx + 1] += 1;
         // Then output the data
         rle2[out_idx] = idx as u16 + 1;
         out_idx += 1;
 
 
         // Shift each index in front of the current byte index. Do this first in blocks for speed.
-        let temp_sym = mtf_index[idx as usize];
+        let temp_sym = mtf_index[idx];
 
         while idx > 7 {
-            mtf_index[idx as usize] = mtf_index[idx as usize - 1];
-            mtf_index[idx as usize - 1] = mtf_index[idx as usize - 2];
-            mtf_index[idx as usize - 2] = mtf_index[idx as usize - 3];
-            mtf_index[idx as usize - 3] = mtf_index[idx as usize - 4];
-            mtf_index[idx as usize - 4] = mtf_index[idx as usize - 5];
-            mtf_index[idx as usize - 5] = mtf_index[idx as usize - 6];
-            mtf_index[idx as usize - 6] = mtf_index[idx as usize - 7];
-            mtf_index[idx as usize - 7] = mtf_index[idx as usize - 8];
+            mtf_index[idx] = mtf_index[idx - 1];
+            mtf_index[idx - 1] = mtf_index[idx - 2];
+            mtf_index[idx - 2] = mtf_index[idx - 3];
+            mtf_index[idx - 3] = mtf_index[idx - 4];
+            mtf_index[idx - 4] = mtf_index[idx - 5];
+            mtf_index[idx - 5] = mtf_index[idx - 6];
+            mtf_index[idx - 6] = mtf_index[idx - 7];
+            mtf_index[idx - 7] = mtf_index[idx - 8];
             idx -= 8;
         }
         while idx > 3 {
-            mtf_index[idx as usize] = mtf_index[idx as usize - 1];
-            mtf_index[idx as usize - 1] = mtf_index[idx as usize - 2];
-            mtf_index[idx as usize - 2] = mtf_index[idx as usize - 3];
-            mtf_index[idx as usize - 3] = mtf_index[idx as usize - 4];
+            mtf_index[idx] = mtf_index[idx - 1];
+            mtf_index[idx - 1] = mtf_index[idx - 2];
+            mtf_index[idx - 2] = mtf_index[idx - 3];
+            mtf_index[idx - 3] = mtf_index[idx - 4];
             idx -= 4;
         }
         // ...then clean up any odd ones
         while idx > 0 {
-            mtf_index[idx as usize] = mtf_index[idx as usize - 1];
+            mtf_index[idx] = mtf_index[idx - 1];
             idx -= 1;
         }
         // ...and finally put the "new" symbol index at the front of the index.
@@ -169,121 +175,115 @@ pub fn rle2_mtf_encode(block: &[u8]) -> (Vec<u16>, [u32; 256], Vec<u16>) {
     }
     // Add the EOB symbol to the end
     rle2[out_idx] = eob;
+    freqs[eob as usize] += 1;
     out_idx += 1;
 
     // Truncate the vec to the actual data.
     rle2.truncate(out_idx);
-    (rle2, freqs, sym_map)
+    (freqs, sym_map)
 }
 
-/// Watch for malicious input
-fn zero_bomb(zeros: usize) {
-    // Blow up if the run is too big - this should be more elegant in the future
-    if zeros > ZERO_BOMB {
-        error!("Run of zeros exceeded a million - probably input bomb.");
-        std::process::exit(100)
-    }
+/// Undoes RLE2 and MTF one symbol at a time, so the decompressor can feed it symbols as they come off the bitstream
+/// without storing them first. The frequency of each byte is counted as it is produced.
+pub struct Rle2MtfDecoder<'a> {
+    /// The MTF index, starting with the symbol set of the block.
+    mtf_index: [u8; 256],
+    /// The decoded data.
+    out: &'a mut Vec<u8>,
+    /// Frequency count of the decoded data.
+    freq: [u32; 256],
+    /// Length of the RUNA/RUNB run being collected, and the value of the next RUNA.
+    zeros: usize,
+    bit_multiplier: usize,
+    /// The most bytes the block may decode to.
+    limit: usize,
 }
 
-/// Does run-length-decoding and MTF decoding.
-/// Takes huffman decoder data, symbol set, and max block size.
-/// Returns ascii data for bwt transform, plus frequency count of the data.
-pub fn rle2_mtf_decode_fast(
-    data_in: &[u16],
-    mtf_index: &mut Vec<u8>,
-    size: usize,
-) -> (Vec<u8>, [u32;256]) {
-    // Initialize output buffer
-    let mut out = vec![0_u8; size];
-
-    // Initialize counters
-    let mut zeros = 0_usize;
-    let mut bit_multiplier = 1;
-    let mut index = 0_usize;
+impl<'a> Rle2MtfDecoder<'a> {
+    /// Start decoding a block with the symbol set from its symbol map. The data is written to out, which is cleared
+    /// first. Limit is the most bytes the block may hold.
+    pub fn new(symbol_set: &[u8], out: &'a mut Vec<u8>, limit: usize) -> Self {
+        let mut mtf_index = [0_u8; 256];
+        mtf_index[..symbol_set.len()].copy_from_slice(symbol_set);
+        out.clear();
+        out.reserve(limit);
+        Self {
+            mtf_index,
+            out,
+            freq: [0; 256],
+            zeros: 0,
+            bit_multiplier: 1,
+            limit,
+        }
+    }
 
-    // iterate through the input (less EOB), doing the conversion as we find RUNA/RUNB sequences
-    for &rle2_code in data_in.iter().take(data_in.len() - 1) {
+    /// Decode one RLE2 symbol (not the EOB). Returns false if the block would hold more than limit bytes.
+    #[inline]
+    pub fn push(&mut self, rle2_code: u16) -> bool {
         match rle2_code {
-            // If we found RUNA, do magic to calculate how many zeros we need
+            // If we found RUNA or RUNB, do magic to calculate how many zeros we need
             RUNA => {
-                zeros += bit_multiplier;
-                bit_multiplier <<= 1;
+                self.zeros += self.bit_multiplier;
+                self.bit_multiplier <<= 1;
+                self.zeros <= self.limit
             }
-            // If we found RUNB, do magic to calculate how many zeros we need
             RUNB => {
-                zeros += bit_multiplier << 1;
-                bit_multiplier <<= 1;
+                self.zeros += self.bit_multiplier << 1;
+                self.bit_multiplier <<= 1;
+                self.zeros <= self.limit
             }
-
             // Found a "normal" rle2_code
             n => {
                 // Output zeros from RUNA/RUNB sequences, if any
-                if zeros > 0 {
-                    zero_bomb(zeros);
-                    for repeat in out.iter_mut().skip(index).take(zeros) {
-                        *repeat = mtf_index[0];
-                    }
-
-                    // Adjust the RUNA/RUNB sequence counters
-                    index += zeros;
-                    bit_multiplier = 1;
-                    zeros = 0;
+                if !self.write_run() || self.out.len() == self.limit {
+                    return false;
                 }
 
-                // Convert the RLE2_code into an MTF_code
-                let mut mtf_code = n as usize - 1;
-                // And output an byte from the MTF index
-                out[index] = mtf_index[mtf_code];
-
-                // Increment the index
-                index += 1;
+                // Convert the RLE2_code into an MTF_code, and output the byte from the MTF index
+                let mtf_code = n as usize - 1;
+                let byte = self.mtf_index[mtf_code];
+                self.out.push(byte);
+                self.freq[byte as usize] += 1;
 
-                // If this index is less than 16 elements into the vec,
+                // Move the byte to the front of the index
                 if mtf_code < 16 {
-                    // Shift each index at the front of mtfa "forward" one. Do this first in blocks of 4 for speed.
-                    let temp_sym = mtf_index[mtf_code];
-
-                    while mtf_code > 3 {
-                        mtf_index[mtf_code] = mtf_index[mtf_code - 1];
-                        mtf_index[mtf_code - 1] = mtf_index[mtf_code - 2];
-                        mtf_index[mtf_code - 2] = mtf_index[mtf_code - 3];
-                        mtf_index[mtf_code - 3] = mtf_index[mtf_code - 4];
-                        mtf_code -= 4;
+                    // Most codes are small. Shift them one at a time, which is faster than copy_within.
+                    let mut i = mtf_code;
+                    while i > 0 {
+                        self.mtf_index[i] = self.mtf_index[i - 1];
+                        i -= 1;
                     }
-                    // ...then clean up any odd ones
-                    while mtf_code > 0 {
-                        mtf_index[mtf_code] = mtf_index[mtf_code - 1];
-                        mtf_code -= 1;
-                    }
-                    // ...and finally move this index to the front.
-                    mtf_index[0] = temp_sym;
                 } else {
-                    /* general case */
-                    let sym = mtf_index.remove(mtf_code);
-                    mtf_index.insert(0, sym as u8)
+                    self.mtf_index.copy_within(..mtf_code, 1);
                 }
+                self.mtf_index[0] = byte;
+                true
             }
         }
     }
-    // Output trailing zeros from RUNA/RUNB sequences, if any
-    if zeros > 0 {
-        zero_bomb(zeros);
-        for repeat in out.iter_mut().skip(index).take(zeros) {
-            *repeat = mtf_index[0];
-        }
 
-        // Adjust the index as needed
-        index += zeros;
+    /// Output the pending run of zeros (copies of the front of the MTF index). Returns false if that would exceed
+    /// the limit.
+    #[inline]
+    fn write_run(&mut self) -> bool {
+        if self.zeros > 0 {
+            if self.out.len() + self.zeros > self.limit {
+                return false;
+            }
+            let byte = self.mtf_index[0];
+            self.out.resize(self.out.len() + self.zeros, byte);
+            self.freq[byte as usize] += self.zeros as u32;
+            self.zeros = 0;
+            self.bit_multiplier = 1;
+        }
+        true
     }
 
-    // Truncate the vec to the actual data.
-    // (Index is incremented after writing the symbol, so must be decremented by one here.)
-    out.truncate(index);
-
-    //Create the freq vec
-    let freq = freqs(&out);
-
-    (out, freq)
+    /// Finish the block at its EOB symbol. Returns the frequency count of the decoded data, or None if the final
+    /// run would exceed the limit.
+    pub fn finish(mut self) -> Option<[u32; 256]> {
+        self.write_run().then_some(self.freq)
+    }
 }
 
 const BIT_MASK: u16 = 0x8000;
@@ -321,3 +321,30 @@ fn encode_sym_map_from_bool_map(symbols: &[bool]) -> Vec<u16> {
     sym_maps
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::{rle2_mtf_encode, Rle2MtfDecoder};
+    use crate::tools::{freq_count::freqs, symbol_map::decode_sym_map};
+
+    #[test]
+    fn decoder_round_trip_test() {
+        // Long zero runs, and all 256 byte values so MTF codes of 16 and above are used
+        let mut data = vec![b'a'; 5_000];
+        data.extend((0..=255_u8).cycle().take(3_000));
+        data.extend(b"mississippi".repeat(100));
+        let (rle2, _, sym_map) = rle2_mtf_encode(&data);
+
+        let mut out = vec![];
+        let mut decoder = Rle2MtfDecoder::new(&decode_sym_map(&sym_map).unwrap(), &mut out, data.len());
+        assert!(rle2[..rle2.len() - 1].iter().all(|&sym| decoder.push(sym)));
+        let freq = decoder.finish().unwrap();
+        assert!(out == data);
+        assert_eq!(freq, freqs(&data));
+
+        // One byte less than the data needs is too small
+        let mut decoder = Rle2MtfDecoder::new(&decode_sym_map(&sym_map).unwrap(), &mut out, data.len() - 1);
+        let fits = rle2[..rle2.len() - 1].iter().all(|&sym| decoder.push(sym));
+        assert!(!fits || decoder.finish().is_none());
+    }
+}
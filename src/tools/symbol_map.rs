@@ -13,11 +13,20 @@
 //! in the input. That means the next u16 would be a bit map for this block of u8s with 1s and 0s
 //! indicating the presence / absense of those u8s. Etc.
 //!
+//! A map read from a corrupt stream may not hold one u16 for each bit set in maps\[0\], or may list no u8s at all.
+//! Both are returned as DecodeError::BadSymbolMap.
+//!
+use crate::compression::decode_error::DecodeError;
+
 const BIT_MASK: u16 = 0x8000;
 
 
 /// Takes the unique bzip2 symbol map as a slice of u16s. Returns a vec of u8 values found in the map.
-pub fn decode_sym_map(symbol_map: &[u16]) -> Vec<u8> {
+pub fn decode_sym_map(symbol_map: &[u16]) -> Result<Vec<u8>, DecodeError> {
+    // There must be one u16 for each block marked in the index, and at least one u8 must be present
+    if symbol_map.is_empty() || symbol_map.len() != 1 + symbol_map[0].count_ones() as usize {
+        return Err(DecodeError::BadSymbolMap);
+    }
     // Initialize a vec of symbols so we can mark which u8s are present
     let mut symbols: Vec<u8> = Vec::with_capacity(256);
     // Set a counter for the number of maps
@@ -38,7 +47,10 @@ pub fn decode_sym_map(symbol_map: &[u16]) -> Vec<u8> {
             }
         }
     }
-    symbols
+    if symbols.is_empty() {
+        return Err(DecodeError::BadSymbolMap);
+    }
+    Ok(symbols)
 }
 
 
@@ -48,12 +60,20 @@ fn decode_symbol_map_test() {
     let mut compare = "Making a silly test.".as_bytes().to_vec();
     compare.sort_unstable();
     compare.dedup();
-    assert_eq!(compare, decode_sym_map(&maps));
+    assert_eq!(Ok(compare), decode_sym_map(&maps));
 }
 
 #[test]
 fn decode_symbol_map_full_test() {
     let maps = vec![0xffff; 17];
     let compare = (0..=255).collect::<Vec<u8>>();
-    assert_eq!(compare, decode_sym_map(&maps));
+    assert_eq!(Ok(compare), decode_sym_map(&maps));
+}
+
+#[test]
+fn decode_symbol_map_error_test() {
+    // Missing and extra block maps, and a map with no u8s
+    for maps in [vec![], vec![0xc000, 1], vec![0x8000, 1, 1], vec![0], vec![0x8000, 0]] {
+        assert_eq!(decode_sym_map(&maps), Err(DecodeError::BadSymbolMap));
+    }
 }
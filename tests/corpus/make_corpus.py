@@ -0,0 +1,52 @@
+#!/usr/bin/env python3
+"""Regenerate the reference corpus with the C library (Python's bz2 module wraps libbzip2).
+
+The inputs are built exactly as corpus_input() in src/compression/compress.rs builds them.
+Run from this directory: python3 make_corpus.py
+"""
+import bz2
+
+
+class Lcg:
+    def __init__(self, seed):
+        self.state = seed
+
+    def next(self):
+        self.state = (self.state * 1103515245 + 12345) & 0xFFFFFFFF
+        return self.state >> 16
+
+
+WORDS = [b"the", b"quick", b"brown", b"fox", b"jumps", b"over", b"lazy", b"dog", b"\n", b"aaaaaa", b"zzzzzzzzzzzz"]
+
+
+def corpus_input(name):
+    lcg = Lcg(2023)
+    if name == "empty":
+        return b""
+    if name == "hello":
+        return b"hello world\n"
+    if name == "text":
+        return open("text.txt", "rb").read()
+    if name == "words":
+        return b" ".join(WORDS[lcg.next() % len(WORDS)] for _ in range(60_000))
+    if name == "zeros":
+        return bytes(300_000)
+    if name == "abc":
+        return b"abc" * 40_000
+    if name == "runs":
+        out = bytearray()
+        for _ in range(1_500):
+            b = lcg.next() % 4
+            out += bytes([b]) * (1 + lcg.next() % 600)
+        return bytes(out)
+    if name == "binary":
+        return bytes(lcg.next() & 0xFF for _ in range(20_000))
+    raise ValueError(name)
+
+
+# (name, block size)
+CORPUS = [("empty", 9), ("hello", 9), ("text", 9), ("words", 1), ("zeros", 9), ("abc", 9), ("runs", 1), ("binary", 9)]
+
+for name, block_size in CORPUS:
+    with open(f"{name}.bz2", "wb") as f:
+        f.write(bz2.compress(corpus_input(name), block_size))
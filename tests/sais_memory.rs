@@ -0,0 +1,58 @@
+//! Peak memory of the SA-IS sort.
+//!
+//! This needs a counting global allocator, so it runs in its own test binary instead of replacing the allocator for
+//! all of the unit tests.
+//!
+use bzip2::bwt_algorithms::julian_sort::julian_sort;
+use bzip2::bwt_algorithms::sais_fallback::sais_entry;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Allocator that tracks the peak memory allocated by the current thread.
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.get() + layout.size();
+        CURRENT.set(current);
+        PEAK.set(PEAK.get().max(current));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.set(CURRENT.get().saturating_sub(layout.size()));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Reset the peak to the memory currently allocated by this thread, and return that amount.
+fn reset_peak() -> usize {
+    let current = CURRENT.get();
+    PEAK.set(current);
+    current
+}
+
+#[test]
+fn memory_test() {
+    // Repetitive data, so that several recursion levels are needed
+    let mut seed = 7_u32;
+    let data = (0..300_000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            b"abcab"[(seed >> 16) as usize % 5]
+        })
+        .collect::<Vec<u8>>();
+    let before = reset_peak();
+    let result = sais_entry(&data);
+    let peak = PEAK.get() - before;
+    // 4n for the suffix array, n for the output, n/4 for the type bits and a little more for deeper levels
+    assert!(peak <= data.len() * 11 / 2, "Peak was {} bytes for {} bytes", peak, data.len());
+    assert_eq!(result, julian_sort(&data, 30));
+}